@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS sessions (
+    id uuid DEFAULT uuid_generate_v4() NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    ip_address character varying,
+    user_agent character varying,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_seen_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    revoked_at timestamp(6) without time zone
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions (user_id);
+
+ALTER TABLE refresh_tokens ADD COLUMN IF NOT EXISTS session_id uuid REFERENCES sessions(id) ON DELETE CASCADE;
+
+-- the dump left permissions_id_seq at 1, new rows would collide with the seeded ids
+SELECT setval('permissions_id_seq', (SELECT COALESCE(MAX(id), 1) FROM permissions));
+
+INSERT INTO permissions (permissions_name)
+SELECT 'terminateSession'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'terminateSession');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'terminateSession'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
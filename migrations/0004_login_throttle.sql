@@ -0,0 +1,17 @@
+-- failed login counters, keyed by 'user:<username>' or 'ip:<address>'
+CREATE TABLE IF NOT EXISTS login_throttle (
+    throttle_key character varying NOT NULL PRIMARY KEY,
+    failures integer DEFAULT 0 NOT NULL,
+    lockouts integer DEFAULT 0 NOT NULL,
+    locked_until timestamp(6) without time zone,
+    last_failure_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+INSERT INTO permissions (permissions_name)
+SELECT 'unlockUser'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'unlockUser');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'unlockUser'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
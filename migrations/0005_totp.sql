@@ -0,0 +1,35 @@
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id uuid NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- base32, as shown to the authenticator app
+    secret character varying NOT NULL,
+    enabled boolean DEFAULT false NOT NULL,
+    -- last accepted 30 second step, a code cannot be replayed
+    last_used_step bigint,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    confirmed_at timestamp(6) without time zone
+);
+
+CREATE TABLE IF NOT EXISTS totp_recovery_codes (
+    id uuid DEFAULT uuid_generate_v4() NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash character varying NOT NULL,
+    used_at timestamp(6) without time zone,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS totp_recovery_codes_user_id_idx ON totp_recovery_codes (user_id);
+
+-- seeded roles were inserted with explicit ids
+SELECT setval('roles_id_seq', (SELECT COALESCE(MAX(id), 1) FROM roles));
+
+-- roles whose users must enroll a second factor
+ALTER TABLE roles ADD COLUMN IF NOT EXISTS require_mfa boolean DEFAULT false NOT NULL;
+
+INSERT INTO permissions (permissions_name)
+SELECT 'mfaPolicy'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'mfaPolicy');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'mfaPolicy'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
@@ -0,0 +1,12 @@
+-- existing passwords count as set when this migration runs
+ALTER TABLE users ADD COLUMN IF NOT EXISTS password_changed_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL;
+
+-- earlier hashes of each user, for PASSWORD_HISTORY
+CREATE TABLE IF NOT EXISTS password_history (
+    id uuid DEFAULT uuid_generate_v4() NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    password_hash character varying NOT NULL,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS password_history_user_id_idx ON password_history (user_id, created_at DESC);
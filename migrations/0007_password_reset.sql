@@ -0,0 +1,34 @@
+-- one-time codes an admin hands to a user who forgot the password
+CREATE TABLE IF NOT EXISTS password_reset_codes (
+    id uuid DEFAULT uuid_generate_v4() NOT NULL PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash character varying NOT NULL UNIQUE,
+    issued_by uuid REFERENCES users(id) ON DELETE SET NULL,
+    expires_at timestamp(6) without time zone NOT NULL,
+    used_at timestamp(6) without time zone,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS password_reset_codes_user_id_idx ON password_reset_codes (user_id);
+
+-- security relevant actions; actor and target are kept as names so rows survive user deletion
+CREATE TABLE IF NOT EXISTS audit_log (
+    id bigserial NOT NULL PRIMARY KEY,
+    action character varying NOT NULL,
+    actor character varying,
+    target character varying,
+    ip_address character varying,
+    detail text,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_created_at_idx ON audit_log (created_at);
+
+INSERT INTO permissions (permissions_name)
+SELECT 'issueResetCode'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'issueResetCode');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'issueResetCode'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
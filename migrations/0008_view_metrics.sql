@@ -0,0 +1,8 @@
+INSERT INTO permissions (permissions_name)
+SELECT 'viewMetrics'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'viewMetrics');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'viewMetrics'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
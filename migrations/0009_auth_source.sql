@@ -0,0 +1,6 @@
+-- where the password of a user is checked: 'local' (users.password) or 'ldap'
+ALTER TABLE users ADD COLUMN IF NOT EXISTS auth_source character varying DEFAULT 'local' NOT NULL;
+-- stable id of the account in its directory, set for users created on first login
+ALTER TABLE users ADD COLUMN IF NOT EXISTS external_id character varying;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_external_id_idx ON users (auth_source, external_id) WHERE external_id IS NOT NULL;
@@ -0,0 +1,20 @@
+-- a login started at the IdP, removed when the browser comes back
+CREATE TABLE IF NOT EXISTS oidc_login_states (
+    state character varying PRIMARY KEY,
+    pkce_verifier character varying NOT NULL,
+    nonce character varying NOT NULL,
+    expires_at timestamp(6) without time zone NOT NULL
+);
+
+-- IdP accounts linked to users rows
+CREATE TABLE IF NOT EXISTS user_identities (
+    id uuid DEFAULT public.uuid_generate_v4() PRIMARY KEY,
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issuer character varying NOT NULL,
+    subject character varying NOT NULL,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    last_login_at timestamp(6) without time zone,
+    UNIQUE (issuer, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON user_identities (user_id);
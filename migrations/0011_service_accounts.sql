@@ -0,0 +1,37 @@
+-- non-human callers (capture stations, scripts), they authenticate with API keys only
+CREATE TABLE IF NOT EXISTS service_accounts (
+    id uuid DEFAULT public.uuid_generate_v4() PRIMARY KEY,
+    name character varying NOT NULL UNIQUE,
+    description character varying,
+    created_by uuid REFERENCES users(id) ON DELETE SET NULL,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+
+-- the key is shown once; lookup_id is the public part after the prefix, key_hash the sha256 of the whole key
+CREATE TABLE IF NOT EXISTS api_keys (
+    id uuid DEFAULT public.uuid_generate_v4() PRIMARY KEY,
+    service_account_id uuid NOT NULL REFERENCES service_accounts(id) ON DELETE CASCADE,
+    lookup_id character varying NOT NULL UNIQUE,
+    key_hash character varying NOT NULL,
+    created_by uuid REFERENCES users(id) ON DELETE SET NULL,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    expires_at timestamp(6) without time zone,
+    last_used_at timestamp(6) without time zone,
+    revoked_at timestamp(6) without time zone
+);
+
+-- what a key may do, a subset of what its creator could do
+CREATE TABLE IF NOT EXISTS api_key_permissions (
+    api_key_id uuid NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    permissions_id integer NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    PRIMARY KEY (api_key_id, permissions_id)
+);
+
+INSERT INTO permissions (permissions_name)
+SELECT 'manageServiceAccounts'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'manageServiceAccounts');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'manageServiceAccounts'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
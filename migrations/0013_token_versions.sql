@@ -0,0 +1,5 @@
+-- bumped whenever the permissions of a role change; the permission cache compares its entries with
+-- the version in this table and reloads an outdated role. the user version is copied into every
+-- access token, tokens whose version is older than the user's are refused
+ALTER TABLE roles ADD COLUMN IF NOT EXISTS permissions_version integer DEFAULT 1 NOT NULL;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS token_version integer DEFAULT 1 NOT NULL;
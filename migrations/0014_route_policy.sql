@@ -0,0 +1,22 @@
+-- reading users and the permission matrix was open to anyone, it now needs these;
+-- granted to the roles that manage users / permissions so nobody loses a page
+INSERT INTO permissions (permissions_name)
+SELECT name FROM (VALUES ('viewUsers'), ('viewPermissions')) AS new(name)
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = new.name);
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT DISTINCT rp.role_id, v.id FROM role_permissions rp
+JOIN permissions p ON p.id = rp.permissions_id
+JOIN permissions v ON v.permissions_name = 'viewUsers'
+WHERE p.permissions_name IN ('newUser', 'deletedUser', 'editPassword')
+  AND NOT EXISTS (SELECT 1 FROM role_permissions x WHERE x.role_id = rp.role_id AND x.permissions_id = v.id);
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT DISTINCT rp.role_id, v.id FROM role_permissions rp
+JOIN permissions p ON p.id = rp.permissions_id
+JOIN permissions v ON v.permissions_name = 'viewPermissions'
+WHERE p.permissions_name IN ('addPermission', 'deletedPermission')
+  AND NOT EXISTS (SELECT 1 FROM role_permissions x WHERE x.role_id = rp.role_id AND x.permissions_id = v.id);
+
+-- cached permission sets of these roles are stale now
+UPDATE roles SET permissions_version = permissions_version + 1;
@@ -0,0 +1,13 @@
+-- role names are picked from lists and mapped from directory groups, two that only differ in case are a mistake
+CREATE UNIQUE INDEX IF NOT EXISTS roles_role_name_key ON roles (lower(role_name));
+
+INSERT INTO permissions (permissions_name)
+SELECT name FROM (VALUES ('createRole'), ('renameRole'), ('deleteRole')) AS new(name)
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = new.name);
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name IN ('createRole', 'renameRole', 'deleteRole')
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
+
+UPDATE roles SET permissions_version = permissions_version + 1 WHERE role_name = 'admin';
@@ -0,0 +1,5 @@
+-- filled from the permission list in src/tools/perm.rs at every startup
+ALTER TABLE permissions ADD COLUMN IF NOT EXISTS description varchar DEFAULT '' NOT NULL;
+ALTER TABLE permissions ADD COLUMN IF NOT EXISTS category varchar DEFAULT '' NOT NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS permissions_permissions_name_key ON permissions (permissions_name);
@@ -0,0 +1,20 @@
+-- a role holds the permissions of its parents and of their parents in turn
+CREATE TABLE IF NOT EXISTS role_parents (
+    role_id integer NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    parent_id integer NOT NULL REFERENCES roles(id) ON DELETE CASCADE,
+    PRIMARY KEY (role_id, parent_id),
+    CHECK (role_id <> parent_id)
+);
+
+CREATE INDEX IF NOT EXISTS role_parents_parent_id_idx ON role_parents (parent_id);
+
+INSERT INTO permissions (permissions_name)
+SELECT 'setRoleParents'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'setRoleParents');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'setRoleParents'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
+
+UPDATE roles SET permissions_version = permissions_version + 1 WHERE role_name = 'admin';
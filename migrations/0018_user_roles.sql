@@ -0,0 +1,29 @@
+-- a user can hold several roles and gets the permissions of all of them
+CREATE TABLE IF NOT EXISTS user_roles (
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role_id integer NOT NULL REFERENCES roles(id) ON DELETE RESTRICT,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, role_id)
+);
+
+CREATE INDEX IF NOT EXISTS user_roles_role_id_idx ON user_roles (role_id);
+
+INSERT INTO user_roles (user_id, role_id)
+SELECT id, role_id FROM users
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE users DROP COLUMN role_id;
+
+INSERT INTO permissions (permissions_name)
+SELECT 'assignRoles'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'assignRoles');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'assignRoles'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
+
+UPDATE roles SET permissions_version = permissions_version + 1 WHERE role_name = 'admin';
+
+-- access tokens name the roles they were issued for, older ones name one role
+UPDATE users SET token_version = token_version + 1;
@@ -0,0 +1,22 @@
+-- permissions granted to or denied for one user on top of their roles; a deny beats every grant
+CREATE TABLE IF NOT EXISTS user_permissions (
+    user_id uuid NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    permissions_id integer NOT NULL REFERENCES permissions(id) ON DELETE CASCADE,
+    granted boolean NOT NULL,
+    -- ignored from then on, the row stays for the listing
+    expires_at timestamp(6) without time zone,
+    created_by varchar NOT NULL,
+    created_at timestamp(6) without time zone DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    PRIMARY KEY (user_id, permissions_id)
+);
+
+INSERT INTO permissions (permissions_name)
+SELECT 'manageUserPermissions'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'manageUserPermissions');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'manageUserPermissions'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
+
+UPDATE roles SET permissions_version = permissions_version + 1 WHERE role_name = 'admin';
@@ -0,0 +1,11 @@
+-- single sign-on accounts are only linked to local and directory users by an admin
+INSERT INTO permissions (permissions_name)
+SELECT 'linkIdentity'
+WHERE NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = 'linkIdentity');
+
+INSERT INTO role_permissions (role_id, permissions_id)
+SELECT r.id, p.id FROM roles r, permissions p
+WHERE r.role_name = 'admin' AND p.permissions_name = 'linkIdentity'
+  AND NOT EXISTS (SELECT 1 FROM role_permissions rp WHERE rp.role_id = r.id AND rp.permissions_id = p.id);
+
+UPDATE roles SET permissions_version = permissions_version + 1 WHERE role_name = 'admin';
@@ -0,0 +1,4 @@
+-- one account per name regardless of case; local, directory and single sign-on users share the
+-- namespace, lookups by name must never have two rows to choose from.
+-- fails on an existing duplicate, which has to be renamed or removed by hand first
+CREATE UNIQUE INDEX IF NOT EXISTS users_username_lower_idx ON users (lower(username));
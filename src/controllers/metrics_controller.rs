@@ -0,0 +1,27 @@
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+
+use crate::responses::response::HashMetricsResponse;
+use crate::tools::password_hash::PasswordHasher;
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+
+#[utoipa::path(
+    get,
+    path = "/api/metrics/passwordHashing",
+    tag = "Metrics",
+    responses(
+        (status = 200, description = "Password hashing queue: slots, waiting jobs, wait times and rejections", body = HashMetricsResponse)
+    )
+)]
+#[get("/metrics/passwordHashing")]
+pub async fn password_hashing_metrics(
+    hasher: &State<PasswordHasher>,
+    _permitted: Require<perm::ViewMetrics>
+) -> Result<Json<HashMetricsResponse>, Status> {
+    Ok(Json(HashMetricsResponse {
+        status: "success".to_string(),
+        metrics: hasher.metrics(),
+    }))
+}
@@ -1,3 +1,9 @@
 pub mod user_controller;
 pub mod permission_controller;
-pub mod worklist_controller;
\ No newline at end of file
+pub mod worklist_controller;
+pub mod session_controller;
+pub mod totp_controller;
+pub mod password_reset_controller;
+pub mod metrics_controller;
+pub mod oidc_controller;
+pub mod service_account_controller;
\ No newline at end of file
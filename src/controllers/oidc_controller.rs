@@ -0,0 +1,180 @@
+use log::{error, info, warn};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::time::Duration;
+use rocket::response::Redirect;
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::controllers::user_controller::continue_login;
+use crate::models::user::LinkIdentityRequest;
+use crate::responses::response::{ApiError, GenericResponse, LoginResponse};
+use crate::tools::client::ClientInfo;
+use crate::tools::jwt::{JwtKeyring, TokenConfig};
+use crate::tools::oidc::{self, OidcError, OidcProvider};
+use crate::tools::perm;
+use crate::tools::refresh_token::hash_token;
+use crate::tools::permission_control::Require;
+
+// hash of the state handed to the browser that started the login, so a callback with
+// someone else's code and state is refused (login CSRF)
+const STATE_COOKIE: &str = "oidc_state";
+
+#[utoipa::path(
+    get,
+    path = "/api/user/oidc/login",
+    tag = "User",
+    responses(
+        (status = 303, description = "Redirect to the identity provider to sign in")
+    )
+)]
+#[get("/user/oidc/login")]
+pub async fn oidc_login(
+    pool: &State<PgPool>,
+    provider: &State<OidcProvider>,
+    cookies: &CookieJar<'_>
+) -> Result<Redirect, Status> {
+    match provider.begin_login(pool.inner()).await {
+        Ok((url, state)) => {
+            let ttl_minutes = provider.config().map_or(10, |config| config.login_ttl_minutes);
+            // Lax: the IdP sends the browser back with a top-level GET
+            cookies.add(
+                Cookie::build((STATE_COOKIE, hash_token(&state)))
+                    .path("/")
+                    .secure(true)
+                    .http_only(true)
+                    .same_site(SameSite::Lax)
+                    .max_age(Duration::minutes(ttl_minutes))
+                    .build()
+            );
+            Ok(Redirect::to(url))
+        }
+        Err(OidcError::Disabled) => Err(Status::NotFound),
+        Err(e) => {
+            error!("OIDC login API error: {}", e);
+            Err(e.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/oidc/callback",
+    tag = "User",
+    params(
+        ("code" = Option<String>, Query, description = "Authorization code from the identity provider"),
+        ("state" = String, Query, description = "State handed out by /api/user/oidc/login"),
+        ("error" = Option<String>, Query, description = "Set by the identity provider when the login failed")
+    ),
+    responses(
+        (status = 200, description = "Exchange the identity provider's answer for our tokens", body = LoginResponse)
+    )
+)]
+#[get("/user/oidc/callback?<code>&<state>&<error>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn oidc_callback(
+    code: Option<String>,
+    state: String,
+    error: Option<String>,
+    pool: &State<PgPool>,
+    provider: &State<OidcProvider>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>
+) -> Result<Json<LoginResponse>, Status> {
+    let config = provider.config().ok_or(Status::NotFound)?;
+
+    // left in place on a mismatch, the browser's own login can still finish
+    let started_here = cookies.get(STATE_COOKIE).is_some_and(|cookie| cookie.value() == hash_token(&state));
+    if !started_here {
+        warn!("OIDC callback from a browser that did not start the login");
+        return Err(Status::Unauthorized);
+    }
+    cookies.remove(Cookie::build(STATE_COOKIE).path("/").build());
+
+    let code = match (code, error) {
+        (Some(code), None) => code,
+        (_, error) => {
+            warn!("Identity provider refused the login: {:?}", error);
+            return Err(Status::Unauthorized);
+        }
+    };
+
+    let identity = match provider.finish_login(pool.inner(), &code, &state).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            warn!("OIDC login failed: {}", e);
+            return Err(e.into());
+        }
+    };
+
+    let user_id = match oidc::find_or_link_user(pool.inner(), config, &identity).await {
+        Ok(user_id) => user_id,
+        Err(e) => {
+            warn!("OIDC login of subject {} failed: {}", identity.subject, e);
+            return Err(e.into());
+        }
+    };
+
+    let username = match sqlx::query_scalar!("SELECT username FROM users WHERE id = $1", user_id)
+        .fetch_one(pool.inner())
+        .await
+    {
+        Ok(username) => username,
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    info!("User {} signed in through {}", username, identity.issuer);
+    continue_login(pool.inner(), keyring.inner(), token_config.inner(), cookies, user_id, &username, &client)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/oidc/link",
+    tag = "User",
+    request_body = LinkIdentityRequest,
+    responses(
+        (status = 200, description = "Let an identity provider account sign in as an existing user, who keeps their roles", body = GenericResponse),
+        (status = 409, description = "The account is linked already", body = GenericResponse)
+    )
+)]
+#[post("/user/oidc/link", format = "json", data = "<link_data>")]
+pub async fn link_oidc_identity(
+    link_data: Json<LinkIdentityRequest>,
+    pool: &State<PgPool>,
+    provider: &State<OidcProvider>,
+    permitted: Require<perm::LinkIdentity>
+) -> Result<Json<GenericResponse>, ApiError> {
+    let config = provider.config().ok_or(Status::NotFound)?;
+    let uuid = Uuid::parse_str(&link_data.uid).map_err(|_| ApiError::from(Status::BadRequest))?;
+    let subject = link_data.subject.trim();
+    if subject.is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "subject is required"));
+    }
+
+    let db_error = |e: sqlx::Error| {
+        error!("Link identity API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let exists = sqlx::query_scalar!("SELECT id FROM users WHERE id = $1", uuid)
+        .fetch_optional(pool.inner())
+        .await
+        .map_err(db_error)?;
+    if exists.is_none() {
+        return Err(ApiError::new(Status::NotFound, "user not found"));
+    }
+
+    if !oidc::link_identity(pool.inner(), uuid, &config.issuer_url, subject).await.map_err(db_error)? {
+        return Err(ApiError::new(Status::Conflict, "the account is linked already"));
+    }
+
+    info!("{} linked {} at {} to user {}", permitted.user.user_id, subject, config.issuer_url, uuid);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "account linked".to_string() }))
+}
@@ -0,0 +1,197 @@
+use log::{error, info, warn};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::controllers::user_controller::{enforce_password_policy, ensure_local_password};
+use crate::models::user::{ResetCodeRequest, RedeemResetCodeRequest};
+use crate::responses::response::{ApiError, GenericResponse, ResetCodeResponse};
+use crate::tools::audit;
+use crate::tools::auth::AuthenticatedUser;
+use crate::tools::client::ClientInfo;
+use crate::tools::login_throttle::{self, is_locked, user_key, ip_key, LoginThrottleConfig};
+use crate::tools::password_hash::PasswordHasher;
+use crate::tools::password_policy::{self, PasswordPolicy};
+use crate::tools::password_reset::{self, ResetCodeConfig};
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+use crate::tools::session::revoke_user_sessions;
+
+// wrong codes count like wrong passwords, the endpoint is open to everyone
+async fn reject_code(
+    pool: &PgPool,
+    throttle_config: &LoginThrottleConfig,
+    username: &str,
+    client: &ClientInfo
+) -> ApiError {
+    let mut result = login_throttle::record_failure(pool, throttle_config, &user_key(username), throttle_config.max_failures).await;
+    if let Some(ip) = &client.ip_address {
+        result = result.and(login_throttle::record_failure(pool, throttle_config, &ip_key(ip), throttle_config.max_ip_failures).await);
+    }
+    if let Err(e) = result {
+        error!("Failed to record reset code failure: {:?}", e);
+    }
+
+    if let Err(e) = audit::record(pool, audit::RESET_CODE_REJECTED, None, Some(username), client, None).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    ApiError::new(Status::BadRequest, "reset code is invalid or expired")
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/resetCode",
+    tag = "User",
+    request_body = ResetCodeRequest,
+    responses(
+        (status = 200, description = "Issue a one-time password reset code for a user", body = ResetCodeResponse)
+    )
+)]
+#[post("/user/resetCode", format = "json", data = "<reset_data>")]
+pub async fn issue_reset_code(
+    reset_data: Json<ResetCodeRequest>,
+    auth_user: AuthenticatedUser,
+    _permitted: Require<perm::IssueResetCode>,
+    pool: &State<PgPool>,
+    config: &State<ResetCodeConfig>,
+    client: ClientInfo
+) -> Result<Json<ResetCodeResponse>, Status> {
+    let user_id = match Uuid::parse_str(&reset_data.uid) {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    let username = match sqlx::query!("SELECT username FROM users WHERE id = $1", user_id)
+        .fetch_optional(pool.inner())
+        .await
+    {
+        Ok(Some(user)) => user.username,
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if let Err(e) = ensure_local_password(pool.inner(), user_id).await {
+        return Err(e.status);
+    }
+
+    let code = match password_reset::issue_code(pool.inner(), config.inner(), user_id, auth_user.user_id).await {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Reset code API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    // no audit entry, no code
+    let detail = format!("valid for {} minutes", config.ttl_minutes);
+    if let Err(e) = audit::record(pool.inner(), audit::RESET_CODE_ISSUED, Some(&auth_user.username), Some(&username), &client, Some(&detail)).await {
+        error!("Failed to write audit log: {:?}", e);
+        return Err(Status::InternalServerError);
+    }
+
+    Ok(Json(ResetCodeResponse {
+        status: "success".to_string(),
+        code,
+        expires_in: config.ttl_minutes * 60,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/resetPassword",
+    tag = "User",
+    request_body = RedeemResetCodeRequest,
+    responses(
+        (status = 200, description = "Set a new password with a reset code, no login needed", body = GenericResponse)
+    )
+)]
+#[post("/user/resetPassword", format = "json", data = "<redeem_data>")]
+pub async fn redeem_reset_code(
+    redeem_data: Json<RedeemResetCodeRequest>,
+    pool: &State<PgPool>,
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    throttle_config: &State<LoginThrottleConfig>,
+    client: ClientInfo
+) -> Result<Json<GenericResponse>, ApiError> {
+    let redeem = redeem_data.into_inner();
+
+    let mut throttle_keys = vec![user_key(&redeem.username)];
+    if let Some(ip) = &client.ip_address {
+        throttle_keys.push(ip_key(ip));
+    }
+    match is_locked(pool.inner(), &throttle_keys).await {
+        Ok(false) => {},
+        Ok(true) => {
+            warn!("Rejected reset code for {} from {:?}: locked out", redeem.username, client.ip_address);
+            return Err(Status::TooManyRequests.into());
+        }
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    let user_id = match sqlx::query!("SELECT id FROM users WHERE username = $1 AND deleted IS NOT TRUE", redeem.username)
+        .fetch_optional(pool.inner())
+        .await
+    {
+        Ok(Some(user)) => user.id,
+        Ok(None) => return Err(reject_code(pool.inner(), throttle_config.inner(), &redeem.username, &client).await),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    let code_id = match password_reset::find_valid_code(pool.inner(), user_id, &redeem.code).await {
+        Ok(Some(code_id)) => code_id,
+        Ok(None) => return Err(reject_code(pool.inner(), throttle_config.inner(), &redeem.username, &client).await),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    // checked before the code is used up, so a rejected password does not cost the code
+    enforce_password_policy(pool.inner(), policy.inner(), hasher.inner(), &redeem.username, Some(user_id), &redeem.new_password).await?;
+
+    match password_reset::consume_code(pool.inner(), code_id).await {
+        Ok(true) => {},
+        Ok(false) => return Err(reject_code(pool.inner(), throttle_config.inner(), &redeem.username, &client).await),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    let hashed_password = match hasher.hash(&redeem.new_password).await {
+        Ok(h) => h,
+        Err(e) => return Err(Status::from(e).into())
+    };
+
+    if let Err(e) = password_policy::record_password_change(pool.inner(), policy.inner(), user_id, &hashed_password).await {
+        error!("Error updateing password: {:?}", e);
+        return Err(Status::InternalServerError.into());
+    }
+
+    // the password may have been known to someone else, end every session
+    if let Err(e) = revoke_user_sessions(pool.inner(), user_id, None).await {
+        error!("Failed to revoke sessions after password reset: {:?}", e);
+    }
+    if let Err(e) = login_throttle::reset(pool.inner(), &user_key(&redeem.username)).await {
+        error!("Failed to reset login failures: {:?}", e);
+    }
+    if let Err(e) = audit::record(pool.inner(), audit::RESET_CODE_REDEEMED, Some(&redeem.username), Some(&redeem.username), &client, None).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    info!("User {} set a new password with a reset code", redeem.username);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "Password reset".to_string() }))
+}
@@ -3,9 +3,20 @@ use rocket::http::Status;
 use rocket::serde::json::Json;
 use rocket::State;
 use sqlx::PgPool;
+use uuid::Uuid;
 
 use crate::models::permission::{RolePermissionRequest, Permission, PermissionListResponse, RolePermission, RolePermissionResponse, RoleWithPermissions, Role, RoleResponse};
-use crate::responses::response::GenericResponse;
+use crate::models::permission::{CreateRoleRequest, RenameRoleRequest, DeleteRoleRequest, RoleCreatedResponse, RoleMember, RoleInUseResponse};
+use crate::models::permission::{InheritedPermission, RoleParentsRequest};
+use crate::models::permission::{PermissionEffect, UserPermission, UserPermissionRequest, DeleteUserPermissionRequest, UserPermissionListResponse};
+use crate::models::totp::MfaPolicyRequest;
+use crate::responses::response::{ApiError, DeleteRoleError, GenericResponse};
+use crate::tools::audit;
+use crate::tools::client::ClientInfo;
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+use crate::tools::permission_cache::{bump_role_version, bump_role_versions, PermissionCache};
+use crate::tools::user_permissions;
 
 #[utoipa::path(
     get,
@@ -19,7 +30,7 @@ use crate::responses::response::GenericResponse;
 pub async fn get_role(
     pool: &State<PgPool>
 ) -> Result<Json<RoleResponse>, Status> {
-    match sqlx::query_as!(Role, "SELECT id, role_name FROM roles")
+    match sqlx::query_as!(Role, "SELECT id, role_name, require_mfa FROM roles")
         .fetch_all(pool.inner())
         .await
     {
@@ -31,12 +42,210 @@ pub async fn get_role(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/role",
+    tag = "Role",
+    request_body = CreateRoleRequest,
+    responses(
+        (status = 200, description = "Create a role without permissions", body = RoleCreatedResponse),
+        (status = 409, description = "A role with this name exists", body = GenericResponse)
+    )
+)]
+#[post("/role", format = "json", data = "<role_data>")]
+pub async fn create_role(
+    role_data: Json<CreateRoleRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::CreateRole>
+) -> Result<Json<RoleCreatedResponse>, ApiError> {
+    let role_name = role_data.role_name.trim();
+    if role_name.is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "roleName is required"));
+    }
+
+    // names are unique regardless of case
+    match sqlx::query_scalar!(
+        "INSERT INTO roles (role_name) VALUES ($1) ON CONFLICT DO NOTHING RETURNING id",
+        role_name
+    )
+    .fetch_optional(pool.inner())
+    .await {
+        Ok(Some(id)) => {
+            info!("{} created role {} ({})", permitted.user.user_id, role_name, id);
+            Ok(Json(RoleCreatedResponse { status: "success".to_string(), id }))
+        }
+        Ok(None) => Err(ApiError::new(Status::Conflict, "a role with this name exists")),
+        Err(e) => {
+            error!("Create role API error: {:?}", e);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/role/rename",
+    tag = "Role",
+    request_body = RenameRoleRequest,
+    responses(
+        (status = 200, description = "Rename a role", body = GenericResponse),
+        (status = 409, description = "A role with this name exists", body = GenericResponse)
+    )
+)]
+#[post("/role/rename", format = "json", data = "<role_data>")]
+pub async fn rename_role(
+    role_data: Json<RenameRoleRequest>,
+    pool: &State<PgPool>,
+    permission_cache: &State<PermissionCache>,
+    permitted: Require<perm::RenameRole>
+) -> Result<Json<GenericResponse>, ApiError> {
+    let role_name = role_data.role_name.trim();
+    if role_name.is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "roleName is required"));
+    }
+
+    // the name is cached with the permissions
+    match sqlx::query!(
+        "UPDATE roles SET role_name = $1, permissions_version = permissions_version + 1 WHERE id = $2",
+        role_name, role_data.role_id
+    )
+    .execute(pool.inner())
+    .await {
+        Ok(result) if result.rows_affected() == 0 => Err(Status::NotFound.into()),
+        Ok(_) => {
+            permission_cache.invalidate(role_data.role_id);
+            info!("{} renamed role {} to {}", permitted.user.user_id, role_data.role_id, role_name);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "role renamed".to_string() }))
+        }
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Err(ApiError::new(Status::Conflict, "a role with this name exists"))
+        }
+        Err(e) => {
+            error!("Rename role API error: {:?}", e);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/role/delete",
+    tag = "Role",
+    request_body = DeleteRoleRequest,
+    responses(
+        (status = 200, description = "Delete a role, its users move to reassignTo", body = GenericResponse),
+        (status = 409, description = "Users still hold the role and no reassignTo was given", body = RoleInUseResponse)
+    )
+)]
+#[post("/role/delete", format = "json", data = "<role_data>")]
+pub async fn delete_role(
+    role_data: Json<DeleteRoleRequest>,
+    pool: &State<PgPool>,
+    permission_cache: &State<PermissionCache>,
+    permitted: Require<perm::DeleteRole>
+) -> Result<Json<GenericResponse>, DeleteRoleError> {
+    let req = role_data.into_inner();
+    if req.reassign_to == Some(req.role_id) {
+        return Err(DeleteRoleError::Failed(ApiError::new(Status::BadRequest, "reassignTo is the role being deleted")));
+    }
+
+    let db_error = |e: sqlx::Error| {
+        error!("Delete role API error: {:?}", e);
+        DeleteRoleError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    // the lock keeps users from being given the role until the delete is done
+    let role = sqlx::query_scalar!("SELECT role_name FROM roles WHERE id = $1 FOR UPDATE", req.role_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(DeleteRoleError::from(Status::NotFound))?;
+
+    let moved = match req.reassign_to {
+        Some(target) => {
+            let exists = sqlx::query_scalar!("SELECT id FROM roles WHERE id = $1", target)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(db_error)?;
+            if exists.is_none() {
+                return Err(DeleteRoleError::Failed(ApiError::new(Status::BadRequest, "reassignTo is not a role")));
+            }
+
+            // a role change retires the user's access tokens
+            sqlx::query!(
+                "INSERT INTO user_roles (user_id, role_id) SELECT user_id, $1 FROM user_roles WHERE role_id = $2 ON CONFLICT DO NOTHING",
+                target, req.role_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+            sqlx::query!(
+                r#"
+                    UPDATE users SET token_version = token_version + 1, updated_at = NOW()
+                    WHERE id IN (SELECT user_id FROM user_roles WHERE role_id = $1)
+                "#,
+                req.role_id
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(db_error)?;
+            sqlx::query!("DELETE FROM user_roles WHERE role_id = $1", req.role_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(db_error)?
+                .rows_affected()
+        }
+        None => {
+            let users = sqlx::query_as!(
+                RoleMember,
+                r#"
+                    SELECT u.id, u.username, u.deleted IS TRUE AS "deleted!" FROM user_roles ur
+                    JOIN users u ON u.id = ur.user_id
+                    WHERE ur.role_id = $1
+                    ORDER BY u.username
+                "#,
+                req.role_id
+            )
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(db_error)?;
+            if !users.is_empty() {
+                return Err(DeleteRoleError::InUse(Json(RoleInUseResponse {
+                    status: "error".to_string(),
+                    message: format!("{} users still hold the role, pass reassignTo to move them", users.len()),
+                    users,
+                })));
+            }
+            0
+        }
+    };
+
+    // roles inheriting from it lose what it granted
+    let changed = bump_role_versions(&mut *tx, req.role_id).await.map_err(db_error)?;
+    sqlx::query!("DELETE FROM role_permissions WHERE role_id = $1", req.role_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query!("DELETE FROM roles WHERE id = $1", req.role_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    for id in changed {
+        permission_cache.invalidate(id);
+    }
+    info!("{} deleted role {} ({}), {} users moved to {:?}", permitted.user.user_id, role, req.role_id, moved, req.reassign_to);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "role deleted".to_string() }))
+}
+
 #[utoipa::path(
     get,
     path = "/api/permissions",
     tag = "Permission",
     responses(
-        (status = 200, description = "Get permission list", body = PermissionListResponse)
+        (status = 200, description = "Get permission list with descriptions, grouped by category", body = PermissionListResponse)
     )
 )]
 #[get("/permissions")]
@@ -45,11 +254,13 @@ pub async fn permission_list(
 ) -> Result<Json<PermissionListResponse>, Status> {
     match sqlx::query_as!(
         Permission,
-        "SELECT id, permissions_name FROM permissions"
+        "SELECT id, permissions_name, description, category FROM permissions"
     )
     .fetch_all(pool.inner())
     .await {
-        Ok(permissions) => {
+        Ok(mut permissions) => {
+            // in the order of src/tools/perm.rs, which keeps a category together
+            permissions.sort_by_key(|p| perm::Permission::from_name(&p.permissions_name).map_or(usize::MAX, |p| p as usize));
             info!("Fetch permission success");
             Ok(Json(PermissionListResponse {
                 status: "success".to_string(),
@@ -68,7 +279,7 @@ pub async fn permission_list(
     path = "/api/permission/userRolePermission",
     tag = "Permission",
     responses(
-        (status = 200, description = "Get permission of role of user", body = PermissionListResponse)
+        (status = 200, description = "Permissions of every role, granted directly and inherited from its ancestors", body = PermissionListResponse)
     )
 )]
 #[get("/permission/userRolePermission")]
@@ -77,58 +288,171 @@ pub async fn get_role_permission(
 )-> Result<Json<RolePermissionResponse>, Status> {
     let all_roles = sqlx::query!(
         r#"
-            SELECT r.id as role_id, r.role_name, p.id as permissions_id, p.permissions_name
+            SELECT r.id as role_id, r.role_name, p.id as "permissions_id?", p.permissions_name as "permissions_name?"
             FROM roles r
             LEFT JOIN role_permissions rp ON r.id = rp.role_id
             LEFT JOIN permissions p ON rp.permissions_id = p.id
+            ORDER BY r.id, p.id
         "#
     )
     .fetch_all(pool.inner())
     .await;
 
-    match all_roles {
-        Ok(records) => {
-            let mut role_map: std::collections::HashMap<i32, RoleWithPermissions> = std::collections::HashMap::new();
-
-            for record in records {
-                let role_entry = role_map.entry(record.role_id).or_insert_with(|| RoleWithPermissions {
-                    id: record.role_id,
-                    role_name: record.role_name.clone(),
-                    permissions: Vec::new()
-                });
-
-                if let Some(permission_id) = record.permissions_id {
-                    role_entry.permissions.push(RolePermission {
-                        id: permission_id,
-                        name: record.permissions_name.clone().unwrap_or_default()
-                    });
-                }
-            }
+    let parents = sqlx::query!("SELECT role_id, parent_id FROM role_parents ORDER BY role_id, parent_id")
+        .fetch_all(pool.inner())
+        .await;
 
-            let data: Vec<RoleWithPermissions> = role_map.into_iter().map(|(_, role)| {
-                let mut unique_permissions = std::collections::HashMap::new();
-                for permissions in role.permissions {
-                    unique_permissions.entry(permissions.id).or_insert(permissions);
-                }
-
-                RoleWithPermissions {
-                    id: role.id,
-                    role_name: role.role_name,
-                    permissions: unique_permissions.into_iter().map(|(_, p)| p).collect(),
-                }
-            }).collect();
-
-            info!("Fetch role permission success");
-            Ok(Json(RolePermissionResponse {
-                status: "success".to_string(),
-                data
-            }))
-        },
-        Err(e) => {
+    // every (role, ancestor) pair with what the ancestor was granted
+    let inherited = sqlx::query!(
+        r#"
+            WITH RECURSIVE ancestors(role_id, ancestor_id) AS (
+                SELECT role_id, parent_id FROM role_parents
+                UNION
+                SELECT a.role_id, rpa.parent_id FROM ancestors a JOIN role_parents rpa ON rpa.role_id = a.ancestor_id
+            )
+            SELECT DISTINCT a.role_id as "role_id!", a.ancestor_id as "ancestor_id!", r.role_name, p.id as permissions_id, p.permissions_name
+            FROM ancestors a
+            JOIN roles r ON r.id = a.ancestor_id
+            JOIN role_permissions rp ON rp.role_id = a.ancestor_id
+            JOIN permissions p ON rp.permissions_id = p.id
+            ORDER BY a.role_id, p.id, a.ancestor_id
+        "#
+    )
+    .fetch_all(pool.inner())
+    .await;
+
+    let (records, parents, inherited) = match (all_roles, parents, inherited) {
+        (Ok(records), Ok(parents), Ok(inherited)) => (records, parents, inherited),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
             error!("Role permission API error: {:?}", e);
-            Err(Status::InternalServerError)
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let mut data: Vec<RoleWithPermissions> = Vec::new();
+    for record in records {
+        if data.last().map(|role| role.id) != Some(record.role_id) {
+            data.push(RoleWithPermissions {
+                id: record.role_id,
+                role_name: record.role_name,
+                parents: Vec::new(),
+                permissions: Vec::new(),
+                inherited: Vec::new(),
+            });
+        }
+        let role_entry = data.last_mut().unwrap();
+
+        // role_permissions can hold the same pair twice, rows come sorted by permission
+        if let (Some(id), Some(name)) = (record.permissions_id, record.permissions_name) {
+            if role_entry.permissions.last().map(|p| p.id) != Some(id) {
+                role_entry.permissions.push(RolePermission { id, name });
+            }
         }
     }
+
+    let position: std::collections::HashMap<i32, usize> = data.iter().enumerate().map(|(i, role)| (role.id, i)).collect();
+    for parent in parents {
+        data[position[&parent.role_id]].parents.push(parent.parent_id);
+    }
+    for record in inherited {
+        data[position[&record.role_id]].inherited.push(InheritedPermission {
+            id: record.permissions_id,
+            name: record.permissions_name,
+            from_role_id: record.ancestor_id,
+            from_role_name: record.role_name,
+        });
+    }
+
+    info!("Fetch role permission success");
+    Ok(Json(RolePermissionResponse {
+        status: "success".to_string(),
+        data
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/role/parents",
+    tag = "Role",
+    request_body = RoleParentsRequest,
+    responses(
+        (status = 200, description = "Replace the roles a role inherits permissions from", body = GenericResponse),
+        (status = 409, description = "The role would inherit from itself", body = GenericResponse)
+    )
+)]
+#[post("/role/parents", format = "json", data = "<parents_data>")]
+pub async fn set_role_parents(
+    parents_data: Json<RoleParentsRequest>,
+    pool: &State<PgPool>,
+    permission_cache: &State<PermissionCache>,
+    permitted: Require<perm::SetRoleParents>
+) -> Result<Json<GenericResponse>, ApiError> {
+    let req = parents_data.into_inner();
+    let mut parent_ids = req.parent_ids.clone();
+    parent_ids.sort_unstable();
+    parent_ids.dedup();
+
+    let db_error = |e: sqlx::Error| {
+        error!("Role parents API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    // one change at a time, two concurrent ones could close a cycle neither sees
+    sqlx::query!("LOCK TABLE role_parents IN SHARE ROW EXCLUSIVE MODE")
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    let known = sqlx::query_scalar!("SELECT id FROM roles WHERE id = $1 OR id = ANY($2)", req.role_id, &parent_ids)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if !known.contains(&req.role_id) {
+        return Err(Status::NotFound.into());
+    }
+    if let Some(unknown) = parent_ids.iter().find(|id| !known.contains(id)) {
+        return Err(ApiError::new(Status::BadRequest, format!("unknown role {}", unknown)));
+    }
+
+    // a cycle: the role is among the new parents or their ancestors
+    let cycle = sqlx::query_scalar!(
+        r#"
+            WITH RECURSIVE lineage(id) AS (
+                SELECT unnest($2::int[])
+                UNION
+                SELECT rpa.parent_id FROM role_parents rpa JOIN lineage l ON rpa.role_id = l.id
+            )
+            SELECT EXISTS (SELECT 1 FROM lineage WHERE id = $1) AS "cycle!"
+        "#,
+        req.role_id, &parent_ids
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    if cycle {
+        return Err(ApiError::new(Status::Conflict, "the role would inherit from itself"));
+    }
+
+    sqlx::query!("DELETE FROM role_parents WHERE role_id = $1", req.role_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    sqlx::query!(
+        "INSERT INTO role_parents (role_id, parent_id) SELECT $1, unnest($2::int[])",
+        req.role_id, &parent_ids
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+    let changed = bump_role_versions(&mut *tx, req.role_id).await.map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    for id in changed {
+        permission_cache.invalidate(id);
+    }
+    info!("{} set the parents of role {} to {:?}", permitted.user.user_id, req.role_id, parent_ids);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "role parents updated".to_string() }))
 }
 
 #[utoipa::path(
@@ -143,7 +467,8 @@ pub async fn get_role_permission(
 #[post("/permission/addRolePermission", format = "json", data = "<request>")]
 pub async fn add_role_permissiom(
     request: Json<RolePermissionRequest>,
-    pool: &State<PgPool>
+    pool: &State<PgPool>,
+    permission_cache: &State<PermissionCache>
 ) -> Result<Json<GenericResponse>, Status> {
     let req = request.into_inner();
 
@@ -176,8 +501,8 @@ pub async fn add_role_permissiom(
     .fetch_optional(pool.inner())
     .await;
 
-    let role = match role {
-        Ok(Some(role)) => role,
+    match role {
+        Ok(Some(_)) => {}
         Ok(None) => {
             error!("Role not found:{}", req.role_id);
             return Ok(Json(GenericResponse {
@@ -213,10 +538,16 @@ pub async fn add_role_permissiom(
     .execute(pool.inner())
     .await;
 
-    match result {
+    if let Err(e) = result {
+        error!("Database error: {:?}", e);
+        return Err(Status::InsufficientStorage);
+    }
+
+    // users of the role get the new set on their next request
+    match bump_role_version(pool.inner(), permission_cache.inner(), req.role_id).await {
         Ok(_) => {
             info!("Added permission to role: {} -> {}", req.role_id, req.permissions_name);
-            return Ok(Json(GenericResponse {
+            Ok(Json(GenericResponse {
                 status: "success".to_string(),
                 message: "add permission api success".to_string()
             }))
@@ -240,7 +571,8 @@ pub async fn add_role_permissiom(
 #[post("/permission/deleteRolePermission", format = "json", data = "<request>")]
 pub async fn delete_role_permission(
     request: Json<RolePermissionRequest>,
-    pool: &State<PgPool>
+    pool: &State<PgPool>,
+    permission_cache: &State<PermissionCache>
 ) -> Result<Json<GenericResponse>, Status> {
     let req = request.into_inner();
 
@@ -273,8 +605,8 @@ pub async fn delete_role_permission(
     .fetch_optional(pool.inner())
     .await;
 
-    let role = match role {
-        Ok(Some(role)) => role,
+    match role {
+        Ok(Some(_)) => {}
         Ok(None) => {
             error!("Role not found:{}", req.role_id);
             return Ok(Json(GenericResponse {
@@ -317,7 +649,12 @@ pub async fn delete_role_permission(
    .execute(pool.inner())
    .await;
 
-   match result {
+   if let Err(e) = result {
+        error!("Database error: {:?}", e);
+        return Err(Status::InternalServerError);
+   }
+
+   match bump_role_version(pool.inner(), permission_cache.inner(), req.role_id).await {
         Ok(_) => {
             info!("Deleted permission from role: {} -> {}", req.role_id, req.permissions_name);
             Ok(Json(GenericResponse {
@@ -332,5 +669,197 @@ pub async fn delete_role_permission(
    }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/role/mfaPolicy",
+    tag = "Role",
+    request_body = MfaPolicyRequest,
+    responses(
+        (status = 200, description = "Make two-factor authentication mandatory for a role", body = GenericResponse)
+    )
+)]
+#[post("/role/mfaPolicy", format = "json", data = "<policy_data>")]
+pub async fn set_mfa_policy(
+    policy_data: Json<MfaPolicyRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::MfaPolicy>
+) -> Result<Json<GenericResponse>, Status> {
+    let req = policy_data.into_inner();
 
+    // members without an authenticator are asked to enroll at their next login
+    match sqlx::query!(
+        "UPDATE roles SET require_mfa = $1 WHERE id = $2",
+        req.require_mfa, req.role_id
+    )
+    .execute(pool.inner())
+    .await {
+        Ok(result) if result.rows_affected() == 0 => Err(Status::NotFound),
+        Ok(_) => {
+            info!("{} set require_mfa = {} for role {}", permitted.user.user_id, req.require_mfa, req.role_id);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "MFA policy updated".to_string() }))
+        }
+        Err(e) => {
+            error!("MFA policy API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
 
+#[utoipa::path(
+    get,
+    path = "/api/permission/userPermissions",
+    tag = "Permission",
+    responses(
+        (status = 200, description = "Every permission granted to or denied for a single user, expired ones included", body = UserPermissionListResponse)
+    )
+)]
+#[get("/permission/userPermissions")]
+pub async fn user_permission_list(
+    pool: &State<PgPool>
+) -> Result<Json<UserPermissionListResponse>, Status> {
+    match sqlx::query!(
+        r#"
+            SELECT up.user_id, u.username, p.permissions_name, up.granted, up.expires_at,
+                   (up.expires_at IS NULL OR up.expires_at > CURRENT_TIMESTAMP) AS "active!",
+                   up.created_by, up.created_at
+            FROM user_permissions up
+            JOIN users u ON up.user_id = u.id
+            JOIN permissions p ON up.permissions_id = p.id
+            ORDER BY u.username, p.permissions_name
+        "#
+    )
+    .fetch_all(pool.inner())
+    .await {
+        Ok(rows) => {
+            let data = rows
+                .into_iter()
+                .map(|row| UserPermission {
+                    user_id: row.user_id,
+                    username: row.username,
+                    permissions_name: row.permissions_name,
+                    effect: if row.granted { PermissionEffect::Grant } else { PermissionEffect::Deny },
+                    expires_at: row.expires_at,
+                    active: row.active,
+                    created_by: row.created_by,
+                    created_at: row.created_at,
+                })
+                .collect();
+            Ok(Json(UserPermissionListResponse { status: "success".to_string(), data }))
+        }
+        Err(e) => {
+            error!("User permission API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/permission/setUserPermission",
+    tag = "Permission",
+    request_body = UserPermissionRequest,
+    responses(
+        (status = 200, description = "Grant or deny a permission to one user on top of their roles; their access tokens are retired", body = GenericResponse),
+        (status = 403, description = "Granting a permission the caller does not have", body = GenericResponse)
+    )
+)]
+#[post("/permission/setUserPermission", format = "json", data = "<request>")]
+pub async fn set_user_permission(
+    request: Json<UserPermissionRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::ManageUserPermissions>,
+    client: ClientInfo
+) -> Result<Json<GenericResponse>, ApiError> {
+    let uuid = Uuid::parse_str(&request.uid).map_err(|_| ApiError::from(Status::BadRequest))?;
+    if request.expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(ApiError::new(Status::BadRequest, "expiresInDays has to be positive"));
+    }
+    // nobody hands out more than they have themselves, denying is always allowed
+    if request.effect == PermissionEffect::Grant && !permitted.user.permissions.contains(&request.permissions_name) {
+        return Err(ApiError::new(Status::Forbidden, format!("you do not have the permission {}", request.permissions_name)));
+    }
+
+    let db_error = |e: sqlx::Error| {
+        error!("Set user permission API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    let (username, permission_id) = lock_permission_holder(&mut tx, uuid, &request.permissions_name).await?;
+
+    let granted = request.effect == PermissionEffect::Grant;
+    user_permissions::set_override(&mut tx, uuid, permission_id, granted, request.expires_in_days, &permitted.user.user_id)
+        .await
+        .map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    let effect = if granted { "grant" } else { "deny" };
+    let detail = match request.expires_in_days {
+        Some(days) => format!("{} {} for {} days", effect, request.permissions_name, days),
+        None => format!("{} {}", effect, request.permissions_name),
+    };
+    if let Err(e) = audit::record(pool.inner(), audit::USER_PERMISSION_SET, Some(&permitted.user.user_id), Some(&username), &client, Some(&detail)).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    Ok(Json(GenericResponse { status: "success".to_string(), message: format!("permission {} set to {}", request.permissions_name, effect) }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/permission/deleteUserPermission",
+    tag = "Permission",
+    request_body = DeleteUserPermissionRequest,
+    responses(
+        (status = 200, description = "Remove a grant or deny of one user, their roles decide again; their access tokens are retired", body = GenericResponse)
+    )
+)]
+#[post("/permission/deleteUserPermission", format = "json", data = "<request>")]
+pub async fn delete_user_permission(
+    request: Json<DeleteUserPermissionRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::ManageUserPermissions>,
+    client: ClientInfo
+) -> Result<Json<GenericResponse>, ApiError> {
+    let uuid = Uuid::parse_str(&request.uid).map_err(|_| ApiError::from(Status::BadRequest))?;
+
+    let db_error = |e: sqlx::Error| {
+        error!("Delete user permission API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    let (username, permission_id) = lock_permission_holder(&mut tx, uuid, &request.permissions_name).await?;
+
+    if !user_permissions::remove_override(&mut tx, uuid, permission_id).await.map_err(db_error)? {
+        return Err(ApiError::new(Status::NotFound, "the permission is neither granted to nor denied for the user"));
+    }
+    tx.commit().await.map_err(db_error)?;
+
+    if let Err(e) = audit::record(pool.inner(), audit::USER_PERMISSION_REMOVED, Some(&permitted.user.user_id), Some(&username), &client, Some(&request.permissions_name)).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    Ok(Json(GenericResponse { status: "success".to_string(), message: format!("permission {} removed", request.permissions_name) }))
+}
+
+// looks up the user and the permission id and locks the user row, so concurrent
+// changes of the same user's permissions run one after the other
+async fn lock_permission_holder(conn: &mut sqlx::PgConnection, user_id: Uuid, permissions_name: &str) -> Result<(String, i32), ApiError> {
+    let db_error = |e: sqlx::Error| {
+        error!("Database error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+
+    let username = sqlx::query_scalar!("SELECT username FROM users WHERE id = $1 FOR UPDATE", user_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::new(Status::NotFound, "user not found"))?;
+
+    let permission_id = sqlx::query_scalar!("SELECT id FROM permissions WHERE permissions_name = $1", permissions_name)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::new(Status::BadRequest, format!("unknown permission {}", permissions_name)))?;
+
+    Ok((username, permission_id))
+}
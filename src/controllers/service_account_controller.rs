@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+use log::{error, info};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::models::service_account::{
+    ApiKeyInfo, ApiKeyResponse, CreateApiKeyRequest, CreateServiceAccountRequest, RevokeApiKeyRequest,
+    ServiceAccountInfo, ServiceAccountListResponse, ServiceAccountResponse,
+};
+use crate::responses::response::{ApiError, GenericResponse};
+use crate::tools::api_key;
+use crate::tools::audit;
+use crate::tools::auth::AuthenticatedUser;
+use crate::tools::client::ClientInfo;
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+
+#[utoipa::path(
+    post,
+    path = "/api/serviceAccount",
+    tag = "ServiceAccount",
+    request_body = CreateServiceAccountRequest,
+    responses(
+        (status = 200, description = "Create a service account, keys are issued separately", body = ServiceAccountResponse)
+    )
+)]
+#[post("/serviceAccount", format = "json", data = "<account_data>")]
+pub async fn create_service_account(
+    account_data: Json<CreateServiceAccountRequest>,
+    auth_user: AuthenticatedUser,
+    _permitted: Require<perm::ManageServiceAccounts>,
+    pool: &State<PgPool>
+) -> Result<Json<ServiceAccountResponse>, ApiError> {
+    let name = account_data.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "name is required"));
+    }
+
+    let created = sqlx::query_scalar!(
+        r#"
+            INSERT INTO service_accounts (name, description, created_by)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (name) DO NOTHING
+            RETURNING id
+        "#,
+        name, account_data.description, auth_user.user_id
+    )
+    .fetch_optional(pool.inner())
+    .await;
+
+    match created {
+        Ok(Some(id)) => {
+            info!("User {} created service account {}", auth_user.username, name);
+            Ok(Json(ServiceAccountResponse { status: "success".to_string(), id }))
+        }
+        Ok(None) => Err(ApiError::new(Status::Conflict, "a service account with this name exists")),
+        Err(e) => {
+            error!("Create service account API error: {:?}", e);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/serviceAccount",
+    tag = "ServiceAccount",
+    responses(
+        (status = 200, description = "Service accounts with their keys, revoked and expired ones included", body = ServiceAccountListResponse)
+    )
+)]
+#[get("/serviceAccount")]
+pub async fn list_service_accounts(
+    _permitted: Require<perm::ManageServiceAccounts>,
+    pool: &State<PgPool>
+) -> Result<Json<ServiceAccountListResponse>, Status> {
+    let accounts = sqlx::query!(
+        "SELECT id, name, description, created_at FROM service_accounts ORDER BY name"
+    )
+    .fetch_all(pool.inner())
+    .await;
+
+    let keys = sqlx::query!(
+        r#"
+            SELECT k.id, k.service_account_id, k.lookup_id, k.created_at, k.expires_at, k.last_used_at, k.revoked_at,
+                   COALESCE(ARRAY_AGG(p.permissions_name ORDER BY p.permissions_name)
+                            FILTER (WHERE p.id IS NOT NULL), '{}') AS "permissions!"
+            FROM api_keys k
+            LEFT JOIN api_key_permissions kp ON kp.api_key_id = k.id
+            LEFT JOIN permissions p ON p.id = kp.permissions_id
+            GROUP BY k.id
+            ORDER BY k.created_at
+        "#
+    )
+    .fetch_all(pool.inner())
+    .await;
+
+    let (accounts, keys) = match (accounts, keys) {
+        (Ok(accounts), Ok(keys)) => (accounts, keys),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Service account list API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let mut keys_by_account: HashMap<Uuid, Vec<ApiKeyInfo>> = HashMap::new();
+    for key in keys {
+        keys_by_account.entry(key.service_account_id).or_default().push(ApiKeyInfo {
+            id: key.id,
+            lookup_id: key.lookup_id,
+            permissions: key.permissions,
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+            revoked_at: key.revoked_at,
+        });
+    }
+
+    let data = accounts
+        .into_iter()
+        .map(|account| ServiceAccountInfo {
+            keys: keys_by_account.remove(&account.id).unwrap_or_default(),
+            id: account.id,
+            name: account.name,
+            description: account.description,
+            created_at: account.created_at,
+        })
+        .collect();
+
+    Ok(Json(ServiceAccountListResponse { status: "success".to_string(), data }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/serviceAccount/key",
+    tag = "ServiceAccount",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 200, description = "Issue an API key limited to the given permissions; the key is only returned here", body = ApiKeyResponse)
+    )
+)]
+#[post("/serviceAccount/key", format = "json", data = "<key_data>")]
+pub async fn create_api_key(
+    key_data: Json<CreateApiKeyRequest>,
+    auth_user: AuthenticatedUser,
+    permitted: Require<perm::ManageServiceAccounts>,
+    pool: &State<PgPool>,
+    client: ClientInfo
+) -> Result<Json<ApiKeyResponse>, ApiError> {
+    let account_id = Uuid::parse_str(&key_data.service_account_id).map_err(|_| ApiError::from(Status::BadRequest))?;
+    if key_data.permissions.is_empty() {
+        return Err(ApiError::new(Status::BadRequest, "a key needs at least one permission"));
+    }
+    if key_data.expires_in_days.is_some_and(|days| days <= 0) {
+        return Err(ApiError::new(Status::BadRequest, "expiresInDays has to be positive"));
+    }
+
+    // nobody hands out more than they have themselves
+    if let Some(missing) = key_data.permissions.iter().find(|name| !permitted.user.permissions.contains(*name)) {
+        return Err(ApiError::new(Status::Forbidden, format!("you do not have the permission {}", missing)));
+    }
+
+    let account = sqlx::query!("SELECT name FROM service_accounts WHERE id = $1", account_id)
+        .fetch_optional(pool.inner())
+        .await;
+    let account_name = match account {
+        Ok(Some(account)) => account.name,
+        Ok(None) => return Err(Status::NotFound.into()),
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    let permission_ids = match sqlx::query!(
+        "SELECT id, permissions_name FROM permissions WHERE permissions_name = ANY($1)",
+        &key_data.permissions
+    )
+    .fetch_all(pool.inner())
+    .await
+    {
+        Ok(rows) => rows,
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    if let Some(unknown) = key_data.permissions.iter().find(|name| !permission_ids.iter().any(|row| &row.permissions_name == *name)) {
+        return Err(ApiError::new(Status::BadRequest, format!("unknown permission {}", unknown)));
+    }
+    let permission_ids: Vec<i32> = permission_ids.into_iter().map(|row| row.id).collect();
+
+    let (key_id, key) = match api_key::create_key(pool.inner(), account_id, &permission_ids, key_data.expires_in_days, auth_user.user_id).await {
+        Ok(created) => created,
+        Err(e) => {
+            error!("Create API key error: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    let detail = format!("key {} with {}", key_id, key_data.permissions.join(","));
+    if let Err(e) = audit::record(pool.inner(), audit::API_KEY_CREATED, Some(&auth_user.username), Some(&account_name), &client, Some(&detail)).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    Ok(Json(ApiKeyResponse { status: "success".to_string(), id: key_id, key }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/serviceAccount/key/revoke",
+    tag = "ServiceAccount",
+    request_body = RevokeApiKeyRequest,
+    responses(
+        (status = 200, description = "Revoke an API key, it stops working immediately", body = GenericResponse)
+    )
+)]
+#[post("/serviceAccount/key/revoke", format = "json", data = "<revoke_data>")]
+pub async fn revoke_api_key(
+    revoke_data: Json<RevokeApiKeyRequest>,
+    auth_user: AuthenticatedUser,
+    _permitted: Require<perm::ManageServiceAccounts>,
+    pool: &State<PgPool>,
+    client: ClientInfo
+) -> Result<Json<GenericResponse>, Status> {
+    let key_id = match Uuid::parse_str(&revoke_data.key_id) {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    match api_key::revoke_key(pool.inner(), key_id).await {
+        Ok(true) => {},
+        Ok(false) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("Revoke API key error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    let detail = format!("key {}", key_id);
+    if let Err(e) = audit::record(pool.inner(), audit::API_KEY_REVOKED, Some(&auth_user.username), None, &client, Some(&detail)).await {
+        error!("Failed to write audit log: {:?}", e);
+    }
+
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "API key revoked".to_string() }))
+}
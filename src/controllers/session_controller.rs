@@ -0,0 +1,198 @@
+use log::{error, info};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::models::session::{SessionInfo, SessionListResponse, RevokeSessionRequest, TerminateSessionsRequest};
+use crate::responses::response::GenericResponse;
+use crate::tools::auth::AuthenticatedUser;
+use crate::tools::jwt::TokenConfig;
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+use crate::tools::session::{revoke_session, revoke_user_sessions};
+
+// a session without a usable refresh token is over, even if nobody revoked it
+async fn active_sessions(
+    pool: &PgPool,
+    user_id: Uuid,
+    current: Option<Uuid>,
+    token_config: &TokenConfig
+) -> Result<Vec<SessionInfo>, sqlx::Error> {
+    sqlx::query_as!(
+        SessionInfo,
+        r#"
+            SELECT id, ip_address, user_agent, created_at, last_seen_at,
+                   (id = $2) AS "current!"
+            FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL
+              AND last_seen_at > CURRENT_TIMESTAMP - make_interval(secs => $3)
+            ORDER BY last_seen_at DESC
+        "#,
+        user_id, current.unwrap_or(Uuid::nil()), token_config.refresh_ttl.num_seconds() as f64
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/sessions",
+    tag = "Session",
+    responses(
+        (status = 200, description = "Active sessions of the current user", body = SessionListResponse)
+    )
+)]
+#[get("/user/sessions")]
+pub async fn list_sessions(
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>,
+    token_config: &State<TokenConfig>
+) -> Result<Json<SessionListResponse>, Status> {
+    match active_sessions(pool.inner(), auth_user.user_id, Some(auth_user.claims.sid), token_config.inner()).await {
+        Ok(sessions) => Ok(Json(SessionListResponse { status: "success".to_string(), data: sessions })),
+        Err(e) => {
+            error!("Session list API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/sessions/revoke",
+    tag = "Session",
+    request_body = RevokeSessionRequest,
+    responses(
+        (status = 200, description = "Sign out one of the current user's sessions", body = GenericResponse)
+    )
+)]
+#[post("/user/sessions/revoke", format = "json", data = "<revoke_data>")]
+pub async fn revoke_own_session(
+    revoke_data: Json<RevokeSessionRequest>,
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>
+) -> Result<Json<GenericResponse>, Status> {
+    let session_id = match Uuid::parse_str(&revoke_data.session_id) {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    // only the owner may revoke it here, admins go through /user/sessions/terminate
+    let owned = sqlx::query!(
+        "SELECT id FROM sessions WHERE id = $1 AND user_id = $2",
+        session_id, auth_user.user_id
+    )
+    .fetch_optional(pool.inner())
+    .await;
+
+    match owned {
+        Ok(Some(_)) => {},
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    match revoke_session(pool.inner(), session_id).await {
+        Ok(_) => {
+            info!("User {} revoked session {}", auth_user.username, session_id);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "Session revoked".to_string() }))
+        }
+        Err(e) => {
+            error!("Revoke session API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/sessions/revokeAll",
+    tag = "Session",
+    responses(
+        (status = 200, description = "Sign out everywhere, including this session", body = GenericResponse)
+    )
+)]
+#[post("/user/sessions/revokeAll")]
+pub async fn revoke_all_sessions(
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>
+) -> Result<Json<GenericResponse>, Status> {
+    match revoke_user_sessions(pool.inner(), auth_user.user_id, None).await {
+        Ok(count) => {
+            info!("User {} signed out of {} sessions", auth_user.username, count);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "Signed out everywhere".to_string() }))
+        }
+        Err(e) => {
+            error!("Revoke all sessions API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/sessions/{uid}",
+    tag = "Session",
+    responses(
+        (status = 200, description = "Active sessions of another user", body = SessionListResponse)
+    ),
+    params(
+        ("uid", description = "User id")
+    )
+)]
+#[get("/user/sessions/<uid>")]
+pub async fn list_user_sessions(
+    uid: &str,
+    pool: &State<PgPool>,
+    token_config: &State<TokenConfig>,
+    _permitted: Require<perm::TerminateSession>
+) -> Result<Json<SessionListResponse>, Status> {
+    let user_id = match Uuid::parse_str(uid) {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    match active_sessions(pool.inner(), user_id, None, token_config.inner()).await {
+        Ok(sessions) => Ok(Json(SessionListResponse { status: "success".to_string(), data: sessions })),
+        Err(e) => {
+            error!("Session list API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/sessions/terminate",
+    tag = "Session",
+    request_body = TerminateSessionsRequest,
+    responses(
+        (status = 200, description = "Terminate every session of another user", body = GenericResponse)
+    )
+)]
+#[post("/user/sessions/terminate", format = "json", data = "<terminate_data>")]
+pub async fn terminate_user_sessions(
+    terminate_data: Json<TerminateSessionsRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::TerminateSession>
+) -> Result<Json<GenericResponse>, Status> {
+    let user_id = match Uuid::parse_str(&terminate_data.uid) {
+        Ok(id) => id,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    match revoke_user_sessions(pool.inner(), user_id, None).await {
+        Ok(count) => {
+            info!("{} terminated {} sessions of user {}", permitted.user.user_id, count, user_id);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "Sessions terminated".to_string() }))
+        }
+        Err(e) => {
+            error!("Terminate sessions API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
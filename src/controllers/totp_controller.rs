@@ -0,0 +1,245 @@
+use log::{error, info, warn};
+use rocket::http::{CookieJar, Status};
+use rocket::serde::json::Json;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::controllers::user_controller::start_session;
+use crate::models::totp::{TotpSetupResponse, TotpConfirmResponse, TotpCodeRequest};
+use crate::responses::response::GenericResponse;
+use crate::tools::auth::{AuthenticatedUser, TotpEnrollee};
+use crate::tools::client::ClientInfo;
+use crate::tools::jwt::{JwtKeyring, TokenConfig};
+use crate::tools::login_throttle::{self, is_locked, user_key, LoginThrottleConfig};
+use crate::tools::token_revocation::revoke_token;
+use crate::tools::totp::{self, TotpConfig};
+
+async fn find_user_id(pool: &PgPool, username: &str) -> Result<Uuid, Status> {
+    match sqlx::query!("SELECT id FROM users WHERE username = $1 AND deleted IS NOT TRUE", username)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(Some(user)) => Ok(user.id),
+        Ok(None) => Err(Status::NotFound),
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/totp/setup",
+    tag = "TOTP",
+    responses(
+        (status = 200, description = "Start authenticator enrollment, signed in or with an enrollment challenge", body = TotpSetupResponse)
+    )
+)]
+#[post("/user/totp/setup")]
+pub async fn totp_setup(
+    enrollee: TotpEnrollee,
+    pool: &State<PgPool>,
+    totp_config: &State<TotpConfig>
+) -> Result<Json<TotpSetupResponse>, Status> {
+    let user_id = find_user_id(pool.inner(), &enrollee.username).await?;
+
+    // an active authenticator has to be disabled first, with a code from it
+    match totp::is_enabled(pool.inner(), user_id).await {
+        Ok(false) => {},
+        Ok(true) => return Err(Status::Conflict),
+        Err(e) => {
+            error!("TOTP setup API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    let secret = totp::new_secret();
+    let authenticator = match totp::build_totp(totp_config.inner(), &secret, &enrollee.username) {
+        Ok(authenticator) => authenticator,
+        Err(e) => {
+            warn!("Cannot build TOTP for {}: {}", enrollee.username, e);
+            return Err(Status::BadRequest);
+        }
+    };
+
+    let qr_code = match authenticator.get_qr_base64() {
+        Ok(qr_code) => qr_code,
+        Err(e) => {
+            error!("Failed to render TOTP QR code: {}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    if let Err(e) = totp::store_pending_secret(pool.inner(), user_id, &secret).await {
+        error!("TOTP setup API error: {:?}", e);
+        return Err(Status::InternalServerError);
+    }
+
+    info!("User {} started TOTP enrollment", enrollee.username);
+    Ok(Json(TotpSetupResponse {
+        status: "success".to_string(),
+        otpauth_url: authenticator.get_url(),
+        secret,
+        qr_code,
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/totp/confirm",
+    tag = "TOTP",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 200, description = "Enable the authenticator with a first code and get recovery codes", body = TotpConfirmResponse)
+    )
+)]
+#[post("/user/totp/confirm", format = "json", data = "<confirm_data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn totp_confirm(
+    confirm_data: Json<TotpCodeRequest>,
+    enrollee: TotpEnrollee,
+    pool: &State<PgPool>,
+    totp_config: &State<TotpConfig>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>
+) -> Result<Json<TotpConfirmResponse>, Status> {
+    let user_id = find_user_id(pool.inner(), &enrollee.username).await?;
+
+    match totp::find_secret(pool.inner(), user_id).await {
+        Ok(Some(secret)) if !secret.enabled => {},
+        Ok(Some(_)) => return Err(Status::Conflict),
+        Ok(None) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("TOTP confirm API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    match totp::verify_code(pool.inner(), totp_config.inner(), user_id, &enrollee.username, &confirm_data.code).await {
+        Ok(true) => {},
+        Ok(false) => return Err(Status::BadRequest),
+        Err(e) => {
+            error!("TOTP confirm API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    let recovery_codes = match totp::enable(pool.inner(), totp_config.inner(), user_id).await {
+        Ok(codes) => codes,
+        Err(e) => {
+            error!("TOTP confirm API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+    info!("User {} enabled TOTP", enrollee.username);
+
+    let mut response = TotpConfirmResponse {
+        status: "success".to_string(),
+        message: "two-factor authentication enabled".to_string(),
+        recovery_codes,
+        token: None,
+        refresh_token: None,
+        expires_in: None,
+    };
+
+    // enrolled during login: the code just checked is the second factor, finish the login
+    if let Some(challenge) = enrollee.challenge {
+        if let Err(e) = revoke_token(pool.inner(), challenge.jti, challenge.exp).await {
+            error!("Failed to revoke challenge token: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+
+        let login = start_session(pool.inner(), keyring.inner(), token_config.inner(), cookies, user_id, &enrollee.username, &client).await?;
+        response.token = login.token;
+        response.refresh_token = login.refresh_token;
+        response.expires_in = login.expires_in;
+    }
+
+    Ok(Json(response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/totp/disable",
+    tag = "TOTP",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 200, description = "Remove the authenticator, needs a current code", body = GenericResponse)
+    )
+)]
+#[post("/user/totp/disable", format = "json", data = "<disable_data>")]
+pub async fn totp_disable(
+    disable_data: Json<TotpCodeRequest>,
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>,
+    totp_config: &State<TotpConfig>,
+    throttle_config: &State<LoginThrottleConfig>
+) -> Result<Json<GenericResponse>, Status> {
+    let user_id = auth_user.user_id;
+
+    match totp::required_by_role(pool.inner(), user_id).await {
+        Ok(false) => {},
+        Ok(true) => return Err(Status::Forbidden),
+        Err(e) => {
+            error!("TOTP disable API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    match totp::is_enabled(pool.inner(), user_id).await {
+        Ok(true) => {},
+        Ok(false) => return Err(Status::NotFound),
+        Err(e) => {
+            error!("TOTP disable API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    // a stolen session must not be able to guess the code, wrong ones count against the login lockout
+    let throttle_key = user_key(&auth_user.username);
+    match is_locked(pool.inner(), std::slice::from_ref(&throttle_key)).await {
+        Ok(false) => {},
+        Ok(true) => {
+            warn!("Rejected TOTP disable for {}: locked out", auth_user.username);
+            return Err(Status::TooManyRequests);
+        }
+        Err(e) => {
+            error!("TOTP disable API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    match totp::verify_code(pool.inner(), totp_config.inner(), user_id, &auth_user.username, &disable_data.code).await {
+        Ok(true) => {},
+        Ok(false) => {
+            warn!("Wrong code from user {} disabling TOTP", auth_user.username);
+            if let Err(e) = login_throttle::record_failure(pool.inner(), throttle_config.inner(), &throttle_key, throttle_config.max_failures).await {
+                error!("Failed to record login failure: {:?}", e);
+            }
+            return Err(Status::BadRequest);
+        }
+        Err(e) => {
+            error!("TOTP disable API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    if let Err(e) = login_throttle::reset(pool.inner(), &throttle_key).await {
+        error!("Failed to reset login failures: {:?}", e);
+    }
+
+    match totp::disable(pool.inner(), user_id).await {
+        Ok(()) => {
+            info!("User {} disabled TOTP", auth_user.username);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "two-factor authentication disabled".to_string() }))
+        }
+        Err(e) => {
+            error!("TOTP disable API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
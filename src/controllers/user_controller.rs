@@ -1,83 +1,270 @@
-use std::collections::HashSet;
 use std::time::SystemTime;
 
-use regex::Regex;
-use rocket::futures::lock::Mutex;
-use rocket::http::{Cookie, CookieJar, HeaderMap, SameSite, Status};
-use rocket::request::{self, FromRequest, Request};
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
 use rocket::serde::json::Json;
-use rocket::outcome::Outcome; 
 use rocket::State;
 use sqlx::PgPool;
 use log::{info, warn, error};
+use uuid::Uuid;
 
 
-use crate::models::user::{DeleteUserRequest, LoginRequest, Permission, RegisterRequest, Role, UserWithRole, UserInfo, EditRequest };
-use crate::responses::response::{UserListResponse, CaptchaResponse, UserInfoResponse, GenericResponse, LoginResponse};
-use crate::models::captcha::{CaptchaStore, generate_captcha};
-use crate::tools::jwt::{generate_jwt, validate_jwt};
-use crate::tools::permission_control::UserWithPermissions;
+use crate::models::totp::TotpLoginRequest;
+use crate::models::user::{ChangePasswordRequest, DeleteUserRequest, ExpiredPasswordRequest, LoginRequest, RegisterRequest, UserWithRole, EditRequest, RefreshRequest, UnlockRequest, UserRoleRequest };
+use crate::responses::response::{UserListResponse, CaptchaResponse, UserInfoResponse, GenericResponse, LoginResponse, ApiError};
+use crate::models::captcha::{CaptchaStore, CaptchaConfig, generate_captcha};
+use crate::tools::jwt::{generate_jwt, generate_challenge, JwtKeyring, TokenConfig, MFA_CHALLENGE, MFA_ENROLL_CHALLENGE, PASSWORD_CHANGE_CHALLENGE};
+use crate::tools::refresh_token::{issue_refresh_token, rotate_refresh_token, RefreshOutcome};
+use crate::tools::perm;
+use crate::tools::permission_control::Require;
+use crate::tools::permission_cache::{token_grant, PermissionCache};
+use crate::tools::auth::{AuthenticatedUser, check_challenge, new_csrf_token, TOKEN_COOKIE, CSRF_COOKIE};
+use crate::tools::token_revocation::revoke_token;
+use crate::tools::session::{create_session, revoke_session, revoke_user_sessions};
+use crate::tools::client::ClientInfo;
+use crate::tools::login_throttle::{self, is_locked, user_key, ip_key, LoginThrottleConfig};
+use crate::tools::totp::{self, TotpConfig};
+use crate::tools::password_policy::{self, PasswordPolicy, PolicyViolation};
+use crate::tools::password_hash::PasswordHasher;
+use crate::tools::auth_backend::{self, AuthBackends, BackendContext, LOCAL_SOURCE, LDAP_SOURCE, OIDC_SOURCE};
+use crate::tools::user_roles;
 
 
-// init token black
-pub struct TokenBlack {
-    black: Mutex<HashSet<String>>
+// tool function
+pub async fn validate_captcha(captcha_id: &str, captcha_value: &str, store: &CaptchaStore) -> Result<(), Status> {
+    let mut store = store.lock().expect("Faild to lock store");
+    if let Some(captcha_info) = store.get(captcha_id) {
+        if captcha_info.expires < SystemTime::now() || captcha_info.captcha != captcha_value {
+            store.remove(captcha_id);
+            Err(Status::BadRequest)
+        } else {
+            store.remove(captcha_id);
+            Ok(())
+        }
+    } else {
+        Err(Status::BadRequest)
+    }
 }
 
-impl TokenBlack {
-    pub fn new() -> Self {
-        TokenBlack {
-            black: Mutex::new(HashSet::new())
-        }
+async fn record_failed_login<T>(
+    pool: &PgPool,
+    config: &LoginThrottleConfig,
+    username: &str,
+    client: &ClientInfo
+) -> Result<T, Status> {
+    let mut result = login_throttle::record_failure(pool, config, &user_key(username), config.max_failures).await;
+    if let Some(ip) = &client.ip_address {
+        result = result.and(login_throttle::record_failure(pool, config, &ip_key(ip), config.max_ip_failures).await);
     }
 
-    async fn add(&self, token: String) {
-        let mut blacklist = self.black.lock().await;
-        blacklist.insert(token);
+    match result {
+        Ok(()) => Err(Status::Unauthorized),
+        Err(e) => {
+            error!("Failed to record login failure: {:?}", e);
+            Err(Status::InternalServerError)
+        }
     }
 }
 
-struct RequestHeaders<'h>(&'h HeaderMap<'h>);
+fn access_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build((TOKEN_COOKIE, token.to_string()))
+        .path("/")
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build()
+}
 
-#[derive(Debug)]
-enum ApiTokenError {
-    Missing,
-    Invalid,
+fn set_token_cookies(cookies: &CookieJar<'_>, token: &str, refresh_token: &str) {
+    cookies.add(access_cookie(token));
+    // only sent to /api/user/refresh and /api/user/logout
+    cookies.add(
+        Cookie::build(("refresh_token", refresh_token.to_string()))
+            .path("/api/user")
+            .secure(true)
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .build()
+    );
+    // not http-only: the front end reads it and sends it back as X-CSRF-Token
+    cookies.add(
+        Cookie::build((CSRF_COOKIE, new_csrf_token()))
+            .path("/")
+            .secure(true)
+            .same_site(SameSite::Strict)
+            .build()
+    );
 }
 
-#[rocket::async_trait]
-impl<'r> FromRequest<'r> for RequestHeaders<'r> {
-    type Error = ApiTokenError;
+// which extra step, if any, stands between a correct password and a session
+async fn pending_challenge(pool: &PgPool, user_id: Uuid) -> Result<Option<&'static str>, sqlx::Error> {
+    if totp::is_enabled(pool, user_id).await? {
+        return Ok(Some(MFA_CHALLENGE));
+    }
+    if totp::required_by_role(pool, user_id).await? {
+        return Ok(Some(MFA_ENROLL_CHALLENGE));
+    }
+    Ok(None)
+}
+
+// the client has to come back with the challenge token to the endpoint for `purpose`;
+// the failure counter stays until that step succeeds
+fn challenge_response(
+    keyring: &JwtKeyring,
+    token_config: &TokenConfig,
+    username: &str,
+    purpose: &str
+) -> Result<Json<LoginResponse>, Status> {
+    let (status, message) = match purpose {
+        MFA_CHALLENGE => ("mfa_required", "enter the code from your authenticator app"),
+        MFA_ENROLL_CHALLENGE => ("mfa_enrollment_required", "two-factor authentication must be set up"),
+        _ => ("password_expired", "password has expired and must be changed"),
+    };
 
-    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
-        let request_headers = request.headers();
-        Outcome::Success(RequestHeaders(request_headers))
+    match generate_challenge(keyring, username, purpose, token_config.challenge_ttl) {
+        Ok(challenge) => {
+            info!("User {} passed password check, waiting for {}", username, purpose);
+            Ok(Json(LoginResponse {
+                status: status.to_string(),
+                message: message.to_string(),
+                token: None,
+                refresh_token: None,
+                expires_in: None,
+                challenge_token: Some(challenge)
+            }))
+        }
+        Err(e) => {
+            error!("Failed to generate challenge token: {:?}", e);
+            Err(Status::InternalServerError)
+        }
     }
 }
 
+// password accepted (and replaced if it had expired): second factor if needed, else a session
+pub(crate) async fn continue_login(
+    pool: &PgPool,
+    keyring: &JwtKeyring,
+    token_config: &TokenConfig,
+    cookies: &CookieJar<'_>,
+    user_id: Uuid,
+    username: &str,
+    client: &ClientInfo
+) -> Result<LoginResponse, Status> {
+    match pending_challenge(pool, user_id).await {
+        Ok(None) => start_session(pool, keyring, token_config, cookies, user_id, username, client).await,
+        Ok(Some(purpose)) => challenge_response(keyring, token_config, username, purpose).map(|json| json.into_inner()),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
 
-// tool function
-pub async fn validate_captcha(captcha_id: &str, captcha_value: &str, store: &CaptchaStore) -> Result<(), Status> {
-    let mut store = store.lock().expect("Faild to lock store");
-    if let Some(captcha_info) = store.get(captcha_id) {
-        if captcha_info.expires < SystemTime::now() || captcha_info.captcha != captcha_value {
-            store.remove(captcha_id);
-            Err(Status::BadRequest)
-        } else {
-            store.remove(captcha_id);
-            Ok(())
+// last step of every login flow: clears the failure counter, opens a session and issues tokens
+pub(crate) async fn start_session(
+    pool: &PgPool,
+    keyring: &JwtKeyring,
+    token_config: &TokenConfig,
+    cookies: &CookieJar<'_>,
+    user_id: Uuid,
+    username: &str,
+    client: &ClientInfo
+) -> Result<LoginResponse, Status> {
+    if let Err(e) = login_throttle::reset(pool, &user_key(username)).await {
+        error!("Failed to reset login failures: {:?}", e);
+    }
+
+    let session_id = match create_session(pool, user_id, client).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            error!("Failed to create session: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let refresh_token = match issue_refresh_token(pool, user_id, session_id, None, token_config.refresh_ttl).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to issue refresh token: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let grant = match token_grant(pool, user_id).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            error!("Failed to load role for token: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    match generate_jwt(keyring, username, session_id, grant, token_config.access_ttl).await {
+        Ok(token) => {
+            set_token_cookies(cookies, &token, &refresh_token);
+            info!("User {} logged in successfully", username);
+            Ok(LoginResponse {
+                status: "success".to_string(),
+                message: "login success".to_string(),
+                token: Some(token),
+                refresh_token: Some(refresh_token),
+                expires_in: Some(token_config.access_ttl.num_seconds()),
+                challenge_token: None
+            })
+        }
+        Err(e) => {
+            error!("Failed to generate JWT: {:?}", e);
+            Err(Status::InternalServerError)
         }
-    } else {
-        Err(Status::BadRequest)
     }
 }
 
-fn valided_password(password: &str) -> bool {
-    let re = Regex::new(r"^[A-Za-z\d]{8,}$").unwrap();
-    re.is_match(password) && password.chars().any(|c| c.is_alphabetic()) && password.chars().any(|c| c.is_numeric())
+// static rules first, then the history of an existing user
+pub(crate) async fn enforce_password_policy(
+    pool: &PgPool,
+    policy: &PasswordPolicy,
+    hasher: &PasswordHasher,
+    username: &str,
+    user_id: Option<Uuid>,
+    password: &str
+) -> Result<(), ApiError> {
+    if let Err(violation) = policy.check(username, password) {
+        return Err(ApiError::new(Status::BadRequest, violation.to_string()));
+    }
+
+    if let Some(user_id) = user_id {
+        let hashes = match password_policy::recent_password_hashes(pool, policy, user_id).await {
+            Ok(hashes) => hashes,
+            Err(e) => {
+                error!("Password history lookup failed: {:?}", e);
+                return Err(Status::InternalServerError.into());
+            }
+        };
+
+        match hasher.verify_any(password, hashes).await {
+            Ok(false) => {},
+            Ok(true) => return Err(ApiError::new(Status::BadRequest, PolicyViolation::Reused(policy.history).to_string())),
+            Err(e) => {
+                error!("Password history check failed: {}", e);
+                return Err(Status::from(e).into());
+            }
+        }
+    }
+
+    Ok(())
 }
 
 
+// directory users change their password in the directory
+pub(crate) async fn ensure_local_password(pool: &PgPool, user_id: Uuid) -> Result<(), ApiError> {
+    match auth_backend::has_local_password(pool, user_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::new(Status::Conflict, "password is managed by the directory")),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            Err(Status::InternalServerError.into())
+        }
+    }
+}
+
 #[utoipa::path(
     get,
     path = "/api/user",
@@ -91,17 +278,21 @@ pub async fn get_users(pool: &State<PgPool>) -> Result<Json<UserListResponse>, S
     let user: Vec<UserWithRole> = match sqlx::query_as!(
         UserWithRole,
         r#"
-            SELECT users.id, users.username, 
-            users.voice_attachment, users.role_id, users.deleted, roles.role_name
+            SELECT users.id, users.username,
+            users.voice_attachment, users.deleted,
+            COALESCE(ARRAY_AGG(roles.id ORDER BY roles.id) FILTER (WHERE roles.id IS NOT NULL), '{}') AS "role_ids!",
+            COALESCE(ARRAY_AGG(roles.role_name ORDER BY roles.id) FILTER (WHERE roles.id IS NOT NULL), '{}') AS "role_names!"
             FROM users
-            JOIN roles ON users.role_id = roles.id
+            LEFT JOIN user_roles ON user_roles.user_id = users.id
+            LEFT JOIN roles ON user_roles.role_id = roles.id
+            GROUP BY users.id
         "#
     )
     .fetch_all(pool.inner())
     .await {
         Ok(users) => users,
         Err(e) => {
-            error!("get user list error");
+            error!("get user list error: {:?}", e);
             return Err(Status::InternalServerError);
         }
     };
@@ -125,36 +316,60 @@ pub async fn get_users(pool: &State<PgPool>) -> Result<Json<UserListResponse>, S
 pub async fn register(
     register_data: Json<RegisterRequest>,
     pool: &State<PgPool>,
-    user_with_permissions: UserWithPermissions
-) -> Result<Json<GenericResponse>, Status> {
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    _permitted: Require<perm::NewUser>
+) -> Result<Json<GenericResponse>, ApiError> {
     let reg_data = register_data.into_inner();
 
-    if !user_with_permissions.permissions.contains("newUser") {
-        return  Err(Status::Unauthorized);
-    }
+    enforce_password_policy(pool.inner(), policy.inner(), hasher.inner(), &reg_data.username, None, &reg_data.password).await?;
 
-    if !valided_password(&reg_data.password) {
-        return Err(Status::BadRequest);
-    }
-
-    let hashed_password = match bcrypt::hash(&reg_data.password, bcrypt::DEFAULT_COST) {
+    let hashed_password = match hasher.hash(&reg_data.password).await {
         Ok(h) => h,
-        Err(_) => return Err(Status::InternalServerError)
+        Err(e) => return Err(Status::from(e).into())
     };
 
     let role_id = match reg_data.role_id.parse::<i32>() {
         Ok(id) => id,
-        Err(_) => return Err(Status::BadRequest),  // 转换失败时返回 400 Bad Request
+        Err(_) => return Err(Status::BadRequest.into()),  // 转换失败时返回 400 Bad Request
     };
 
-    match sqlx::query!(
-        "INSERT INTO users (username, password, voice_attachment, role_id) VALUES($1, $2, $3, $4) RETURNING id",
-        reg_data.username, hashed_password, reg_data.voice_attachment, role_id
+    let mut tx = match pool.begin().await {
+        Ok(tx) => tx,
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    let created = sqlx::query!(
+        "INSERT INTO users (username, password, voice_attachment) VALUES($1, $2, $3) RETURNING id",
+        reg_data.username, hashed_password, reg_data.voice_attachment
     )
-    .fetch_one(pool.inner())
-    .await {
-        Ok(user) => Ok(Json(GenericResponse { status: "success".to_string(), message: "User resister success".to_string() })),
-        Err(_) => Err(Status::InternalServerError)
+    .fetch_one(&mut *tx)
+    .await;
+    let created = match created {
+        Ok(user) => sqlx::query!("INSERT INTO user_roles (user_id, role_id) VALUES ($1, $2)", user.id, role_id)
+            .execute(&mut *tx)
+            .await
+            .map(|_| user),
+        Err(e) => Err(e)
+    };
+    let created = match created {
+        Ok(user) => tx.commit().await.map(|_| user),
+        Err(e) => Err(e)
+    };
+
+    match created {
+        Ok(user) => {
+            // first entry of the history, so the initial password cannot come back later either
+            if let Err(e) = password_policy::record_password_change(pool.inner(), policy.inner(), user.id, &hashed_password).await {
+                error!("Failed to record password history: {:?}", e);
+            }
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "User resister success".to_string() }))
+        },
+        // users_username_lower_idx
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err(ApiError::new(Status::Conflict, "username is taken")),
+        Err(_) => Err(Status::InternalServerError.into())
     }
 }
 
@@ -167,13 +382,16 @@ pub async fn register(
     ),
 )]
 #[get("/user/captcha")]
-pub async fn generate_captcha_handler(store: &State<CaptchaStore>) -> Result<Json<CaptchaResponse>, Status> {
-    let (captcha_id, captcha_image) = generate_captcha(store.inner()).await;
+pub async fn generate_captcha_handler(
+    store: &State<CaptchaStore>,
+    config: &State<CaptchaConfig>
+) -> Result<Json<CaptchaResponse>, Status> {
+    let (captcha_id, captcha_image) = generate_captcha(store.inner(), config.inner()).await;
 
     let captcha_response = CaptchaResponse {
         captcha_image,
         captcha_id,
-        expires_in: 60
+        expires_in: config.ttl_seconds as i64
     };
 
     Ok(Json(captcha_response))
@@ -189,63 +407,317 @@ pub async fn generate_captcha_handler(store: &State<CaptchaStore>) -> Result<Jso
     ),
 )]
 #[post("/user/login", format = "json", data = "<login_data>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn login(
     login_data: Json<LoginRequest>,
     pool: &State<PgPool>,
     captcha_store: &State<CaptchaStore>,
+    captcha_config: &State<CaptchaConfig>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    throttle_config: &State<LoginThrottleConfig>,
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    backends: &State<AuthBackends>,
+    client: ClientInfo,
     cookies: &CookieJar<'_>
 ) -> Result<Json<LoginResponse>, Status> {
     let login = login_data.into_inner();
     info!("Attempting to login user: {}", login.username);
-    // vaild captcha
-    validate_captcha(&login.captchaId, &login.captcha, captcha_store.inner()).await;
 
-    // user and password validation
-    let result = sqlx::query!(
-        "SELECT * FROM users WHERE username = $1",
-        login.username,
-    )
-    .fetch_optional(pool.inner())
-    .await;
+    // locked the same way whether or not the username exists
+    let mut throttle_keys = vec![user_key(&login.username)];
+    if let Some(ip) = &client.ip_address {
+        throttle_keys.push(ip_key(ip));
+    }
+    match is_locked(pool.inner(), &throttle_keys).await {
+        Ok(false) => {},
+        Ok(true) => {
+            warn!("Rejected login for {} from {:?}: locked out", login.username, client.ip_address);
+            return Err(Status::TooManyRequests);
+        }
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
 
+    // vaild captcha
+    let captcha_required = if captcha_config.required_after_failures <= 0 {
+        true
+    } else {
+        // due once the username or the client address reached the limit
+        match login_throttle::most_recent_failures(pool.inner(), throttle_config.inner(), &throttle_keys).await {
+            Ok(failures) => failures >= captcha_config.required_after_failures,
+            Err(e) => {
+                error!("Database error occurred: {:?}", e);
+                return Err(Status::InternalServerError);
+            }
+        }
+    };
 
-    match result {
-        Ok(Some(user)) => {
-            if bcrypt::verify(&login.password, &user.password).unwrap_or(false) {
-                match generate_jwt(&login.username).await{
-                    Ok(token) => {
-                        println!("Token : {}", token);
-                        cookies.add(
-                            Cookie::build(("user_token", token.clone()))
-                                .path("/")
-                                .secure(true)
-                                .http_only(true)
-                                .same_site(SameSite::Lax)
-                                .build()
-                        );
-                        info!("User {} logged in successfully", login.username);
-                        Ok(Json(LoginResponse { status: "success".to_string(), message: "login success".to_string(), token: Some(token) }))
-                    }
-                    Err(e) => {
-                        error!("Failed to generate JWT: {:?}", e);
-                        Err(Status::InternalServerError)
-                    }
+    if captcha_required {
+        match (&login.captcha_id, &login.captcha) {
+            (Some(captcha_id), Some(captcha)) => {
+                if let Err(status) = validate_captcha(captcha_id, captcha, captcha_store.inner()).await {
+                    warn!("Invalid captcha for user {}", login.username);
+                    return Err(status);
                 }
-            } else {
-                warn!("Invalid password attempt for user {}", login.username);
-                Err(Status::Unauthorized)
             }
-        },
+            _ => {
+                warn!("Missing captcha for user {}", login.username);
+                return Err(Status::PreconditionRequired);
+            }
+        }
+    }
+
+    // user and password validation
+    let ctx = BackendContext { pool: pool.inner(), hasher: hasher.inner() };
+    let account = match backends.authenticate(&ctx, &login.username, &login.password).await {
+        Ok(Some(account)) => account,
         Ok(None) => {
-            warn!("No user found with username: {}", login.username);
-            Err(Status::Unauthorized)
-        },
+            warn!("Invalid credentials for user {}", login.username);
+            return record_failed_login(pool.inner(), throttle_config.inner(), &login.username, &client).await;
+        }
+        Err(e) => {
+            error!("Authentication backend error: {}", e);
+            return Err(e.into());
+        }
+    };
+
+    // an expired password is replaced before anything else; directory passwords expire in the directory
+    if account.source == LOCAL_SOURCE {
+        match password_policy::is_expired(pool.inner(), policy.inner(), account.user_id).await {
+            Ok(false) => {},
+            Ok(true) => return challenge_response(keyring.inner(), token_config.inner(), &login.username, PASSWORD_CHANGE_CHALLENGE),
+            Err(e) => {
+                error!("Database error occurred: {:?}", e);
+                return Err(Status::InternalServerError);
+            }
+        }
+    }
+
+    continue_login(pool.inner(), keyring.inner(), token_config.inner(), cookies, account.user_id, &login.username, &client)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/login/totp",
+    tag = "User",
+    request_body = TotpLoginRequest,
+    responses(
+        (status = 200, description = "Second login step: authenticator or recovery code", body = LoginResponse)
+    ),
+)]
+#[post("/user/login/totp", format = "json", data = "<totp_data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn login_totp(
+    totp_data: Json<TotpLoginRequest>,
+    pool: &State<PgPool>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    throttle_config: &State<LoginThrottleConfig>,
+    totp_config: &State<TotpConfig>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>
+) -> Result<Json<LoginResponse>, Status> {
+    let totp_req = totp_data.into_inner();
+
+    let challenge = match check_challenge(pool.inner(), keyring.inner(), &totp_req.challenge_token, MFA_CHALLENGE).await {
+        Ok(claims) => claims,
+        Err((status, _)) => return Err(status)
+    };
+    let username = challenge.sub.clone();
+
+    // wrong codes count against the same lockout as wrong passwords
+    match is_locked(pool.inner(), &[user_key(&username)]).await {
+        Ok(false) => {},
+        Ok(true) => {
+            warn!("Rejected second factor for {}: locked out", username);
+            return Err(Status::TooManyRequests);
+        }
         Err(e) => {
             error!("Database error occurred: {:?}", e);
-            Err(Status::InternalServerError)
+            return Err(Status::InternalServerError);
         }
     }
 
+    let user_id = match sqlx::query!("SELECT id FROM users WHERE username = $1 AND deleted IS NOT TRUE", username)
+        .fetch_optional(pool.inner())
+        .await
+    {
+        Ok(Some(user)) => user.id,
+        Ok(None) => return Err(Status::Unauthorized),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    let verified = match (&totp_req.code, &totp_req.recovery_code) {
+        (Some(code), _) => totp::verify_code(pool.inner(), totp_config.inner(), user_id, &username, code).await,
+        (None, Some(recovery_code)) => totp::use_recovery_code(pool.inner(), user_id, recovery_code).await,
+        (None, None) => return Err(Status::BadRequest)
+    };
+
+    match verified {
+        Ok(true) => {},
+        Ok(false) => {
+            warn!("Invalid second factor for user {}", username);
+            return record_failed_login(pool.inner(), throttle_config.inner(), &username, &client).await;
+        }
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    if totp_req.code.is_none() {
+        info!("User {} signed in with a recovery code", username);
+    }
+
+    if let Err(e) = revoke_token(pool.inner(), challenge.jti, challenge.exp).await {
+        error!("Failed to revoke challenge token: {:?}", e);
+        return Err(Status::InternalServerError);
+    }
+
+    start_session(pool.inner(), keyring.inner(), token_config.inner(), cookies, user_id, &username, &client)
+        .await
+        .map(Json)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/login/password",
+    tag = "User",
+    request_body = ExpiredPasswordRequest,
+    responses(
+        (status = 200, description = "Replace an expired password and continue the login", body = LoginResponse)
+    ),
+)]
+#[post("/user/login/password", format = "json", data = "<password_data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn login_password(
+    password_data: Json<ExpiredPasswordRequest>,
+    pool: &State<PgPool>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    client: ClientInfo,
+    cookies: &CookieJar<'_>
+) -> Result<Json<LoginResponse>, ApiError> {
+    let password_req = password_data.into_inner();
+
+    let challenge = match check_challenge(pool.inner(), keyring.inner(), &password_req.challenge_token, PASSWORD_CHANGE_CHALLENGE).await {
+        Ok(claims) => claims,
+        Err((status, _)) => return Err(status.into())
+    };
+    let username = challenge.sub.clone();
+
+    let user_id = match sqlx::query!("SELECT id FROM users WHERE username = $1 AND deleted IS NOT TRUE", username)
+        .fetch_optional(pool.inner())
+        .await
+    {
+        Ok(Some(user)) => user.id,
+        Ok(None) => return Err(Status::Unauthorized.into()),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    enforce_password_policy(pool.inner(), policy.inner(), hasher.inner(), &username, Some(user_id), &password_req.new_password).await?;
+
+    let hashed_password = match hasher.hash(&password_req.new_password).await {
+        Ok(h) => h,
+        Err(e) => return Err(Status::from(e).into())
+    };
+
+    if let Err(e) = password_policy::record_password_change(pool.inner(), policy.inner(), user_id, &hashed_password).await {
+        error!("Error updateing password: {:?}", e);
+        return Err(Status::InternalServerError.into());
+    }
+
+    if let Err(e) = revoke_token(pool.inner(), challenge.jti, challenge.exp).await {
+        error!("Failed to revoke challenge token: {:?}", e);
+        return Err(Status::InternalServerError.into());
+    }
+    info!("User {} replaced an expired password", username);
+
+    continue_login(pool.inner(), keyring.inner(), token_config.inner(), cookies, user_id, &username, &client)
+        .await
+        .map(Json)
+        .map_err(ApiError::from)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/refresh",
+    tag = "User",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Rotate refresh token and issue a new access token", body = LoginResponse)
+    ),
+)]
+#[post("/user/refresh", format = "json", data = "<refresh_data>")]
+pub async fn refresh(
+    refresh_data: Json<RefreshRequest>,
+    pool: &State<PgPool>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    cookies: &CookieJar<'_>
+) -> Result<Json<LoginResponse>, Status> {
+    // body first, browser clients send the http-only cookie instead
+    let presented = match refresh_data.into_inner().refresh_token {
+        Some(token) => token,
+        None => match cookies.get("refresh_token") {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Err(Status::Unauthorized)
+        }
+    };
+
+    let (user_id, username, session_id, refresh_token) = match rotate_refresh_token(pool.inner(), &presented, token_config.refresh_ttl).await {
+        Ok(RefreshOutcome::Rotated { user_id, username, session_id, token }) => (user_id, username, session_id, token),
+        Ok(RefreshOutcome::Reused) | Ok(RefreshOutcome::Invalid) => {
+            cookies.remove(Cookie::build("refresh_token").path("/api/user").build());
+            return Err(Status::Unauthorized);
+        }
+        Err(e) => {
+            error!("Refresh token API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    // role and versions as they are now, a refresh picks up role and password changes
+    let grant = match token_grant(pool.inner(), user_id).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            error!("Failed to load role for token: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
+    };
+
+    match generate_jwt(keyring.inner(), &username, session_id, grant, token_config.access_ttl).await {
+        Ok(token) => {
+            set_token_cookies(cookies, &token, &refresh_token);
+            info!("Refreshed token for user {}", username);
+            Ok(Json(LoginResponse {
+                status: "success".to_string(),
+                message: "refresh success".to_string(),
+                token: Some(token),
+                refresh_token: Some(refresh_token),
+                expires_in: Some(token_config.access_ttl.num_seconds()),
+                challenge_token: None
+            }))
+        }
+        Err(e) => {
+            error!("Failed to generate JWT: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
 }
 
 #[utoipa::path(
@@ -259,33 +731,26 @@ pub async fn login(
 // Implement the logout handler
 #[post("/user/logout")]
 pub async fn logout(
-    token_black: &State<TokenBlack>,
-    headers: RequestHeaders<'_>
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>,
+    cookies: &CookieJar<'_>
 ) -> Result<Json<GenericResponse>, Status> {
-    let RequestHeaders(header_map) = headers;
-    let auth_header = header_map.get_one("Authorization");
-
-    match auth_header {
-        Some(auth_header) => {
-            // 提取標頭中的token部分（假設格式為"Bearer <token>"）
-            let token = auth_header.split_whitespace().nth(1);
-            match token {
-                Some(t) => {
-                    token_black.add(t.to_string()).await;
-                    info!("Logout successful");
-                    Ok(Json(GenericResponse { status: "success".to_string(), message: "Logged out successfully".to_string() }))
-                },
-                None => {
-                    error!("Logout API error: No token provided");
-                    Err(Status::BadRequest)
-                }
-            }
-        },
-        None => {
-            error!("Logout API error: No Authorization header provided");
-            Err(Status::BadRequest)
-        }
+    if let Err(e) = revoke_token(pool.inner(), auth_user.claims.jti, auth_user.claims.exp).await {
+        error!("Logout API error: failed to revoke token: {:?}", e);
+        return Err(Status::InternalServerError);
+    }
+
+    if let Err(e) = revoke_session(pool.inner(), auth_user.claims.sid).await {
+        error!("Logout API error: failed to revoke session: {:?}", e);
+        return Err(Status::InternalServerError);
     }
+
+    cookies.remove(Cookie::build(TOKEN_COOKIE).path("/").build());
+    cookies.remove(Cookie::build(CSRF_COOKIE).path("/").build());
+    cookies.remove(Cookie::build("refresh_token").path("/api/user").build());
+
+    info!("Logout successful: {}", auth_user.username);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "Logged out successfully".to_string() }))
 }
 
 
@@ -299,86 +764,28 @@ pub async fn logout(
 )]
 #[get("/user/userinfo")]
 pub async fn get_userinfo(
-    headers: RequestHeaders<'_>,
+    auth_user: AuthenticatedUser,
     pool: &State<PgPool>,
-    token_black: &State<TokenBlack>
+    permission_cache: &State<PermissionCache>
 ) -> Result<Json<UserInfoResponse>, Status> {
-    let RequestHeaders(header_map) = headers;
-    let auth_header = header_map.get_one("Authorization");
-
-    let token = match auth_header {
-        Some(auth_header) => auth_header.split_whitespace().nth(1),
-        None => return Err(Status::Unauthorized)
-    };
-
-    let token = match token {
-        Some(token) => token,
-        None => return Err(Status::Unauthorized)
-    };
-
-    if token_black.black.lock().await.contains(token) {
-        return Err(Status::Unauthorized);
-    }
-
-    let claims = match validate_jwt(token) {
-        Ok(claims) => claims,
-        Err(_) => return  Err(Status::Unauthorized)
-    };
-
-    let username = claims.claims.sub;
-
-    let user: Option<UserInfo> = sqlx::query_as!(
-        UserInfo,
-        "SELECT id, username, role_id FROM users WHERE username = $1",
-        username
-    )
-    .fetch_optional(pool.inner())
-    .await
-    .unwrap();
-
-    let user = match user {
-        Some(user) => user,
-        None => return  Err(Status::NotFound)
+    let (roles, permissions) = match permission_cache.user_permissions(pool.inner(), &auth_user).await {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            error!("User info API error: {:?}", e);
+            return Err(Status::InternalServerError);
+        }
     };
 
-    let role: Option<Role> = sqlx::query_as!(
-        Role,
-        "SELECT id, role_name FROM roles WHERE id = $1",
-        user.role_id
-    )
-    .fetch_optional(pool.inner())
-    .await
-    .unwrap();
-
-    let role = match role {
-        Some(role) => role,
-        None => return Err(Status::NotFound) 
-    };
-
-    let permission: Vec<Permission> = sqlx::query_as!(
-        Permission,
-        "
-            SELECT permissions.id, permissions_name
-            FROM permissions
-            JOIN role_permissions ON permissions.id = role_permissions.permissions_id
-            WHERE role_permissions.role_id = $1
-        ",
-        user.role_id
-    )
-    .fetch_all(pool.inner())
-    .await
-    .unwrap();
-
-    let username_clone= user.username.clone();
-    let permission_list: Vec<String> = permission.into_iter().map(|p| p.permissions_name).collect();
+    let mut permission_list: Vec<String> = permissions.into_iter().collect();
+    permission_list.sort();
 
+    info!("Fetch user info for username: {}", auth_user.username);
     let user_info = UserInfoResponse {
-        username: user.username,
-        role: role.role_name,
+        username: auth_user.username,
+        roles: roles.iter().map(|role| role.role_name.clone()).collect(),
         permissions: permission_list
     };
 
-    info!("Fetch user info for username: {}", username_clone);
     Ok(Json(user_info))
 
 }
@@ -398,27 +805,33 @@ pub async fn get_userinfo(
 pub async fn soft_delete_user(
     delete_data: Json<DeleteUserRequest>,
     pool: &State<PgPool>,
-    user_with_permissions: UserWithPermissions
+    _permitted: Require<perm::DeletedUser>
 ) -> Result<Json<GenericResponse>, Status> {
     let delete_request = delete_data.into_inner();
 
-    if !user_with_permissions.permissions.contains("deletedUser") {
-        return Err(Status::Forbidden);
-    }
-
     let uuid = match uuid::Uuid::parse_str(&delete_request.Uid) {
         Ok(u) => u,
         Err(_) => return Err(Status::BadRequest)
     };
 
     match sqlx::query!(
-        "UPDATE users SET deleted = TRUE WHERE id = $1",
+        "UPDATE users SET deleted = TRUE, updated_at = CURRENT_TIMESTAMP WHERE id = $1",
         uuid
     )
     .execute(pool.inner())
     .await {
-        Ok(_) => {
-            info!("Soft deleted user id :{}", delete_request.Uid);
+        Ok(result) if result.rows_affected() == 0 => return Err(Status::NotFound),
+        Ok(_) => {},
+        Err(e) => {
+            error!("soft delete api error: {}", e);
+            return Err(Status::InternalServerError);
+        }
+    }
+
+    // the guard refuses the user from now on, this also ends refresh and the session list
+    match revoke_user_sessions(pool.inner(), uuid, None).await {
+        Ok(count) => {
+            info!("Soft deleted user id :{}, {} sessions revoked", delete_request.Uid, count);
             Ok(Json(GenericResponse { status: "success".to_string(), message: "User soft deleted success".to_string() }))
         },
         Err(e) => {
@@ -428,6 +841,159 @@ pub async fn soft_delete_user(
     }
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/user/reactivate",
+    tag = "User",
+    responses(
+        (status = 200, description = "Enable a soft deleted user again; old sessions stay signed out", body = GenericResponse)
+    ),
+    params(
+        ("id", description="User id")
+    )
+)]
+#[post("/user/reactivate", format = "json", data = "<reactivate_data>")]
+pub async fn reactivate_user(
+    reactivate_data: Json<DeleteUserRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::ReactivateUser>
+) -> Result<Json<GenericResponse>, Status> {
+    let reactivate_request = reactivate_data.into_inner();
+
+    let uuid = match uuid::Uuid::parse_str(&reactivate_request.Uid) {
+        Ok(u) => u,
+        Err(_) => return Err(Status::BadRequest)
+    };
+
+    match sqlx::query!(
+        "UPDATE users SET deleted = FALSE, updated_at = CURRENT_TIMESTAMP WHERE id = $1 AND deleted IS TRUE",
+        uuid
+    )
+    .execute(pool.inner())
+    .await {
+        Ok(result) if result.rows_affected() == 0 => Err(Status::NotFound),
+        Ok(_) => {
+            info!("{} reactivated user id :{}", permitted.user.user_id, reactivate_request.Uid);
+            Ok(Json(GenericResponse { status: "success".to_string(), message: "User reactivated".to_string() }))
+        },
+        Err(e) => {
+            error!("Reactivate user API error: {:?}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/role/assign",
+    tag = "User",
+    request_body = UserRoleRequest,
+    responses(
+        (status = 200, description = "Give a user another role; their access tokens are retired", body = GenericResponse),
+        (status = 409, description = "The roles of directory and single sign-on users follow their source", body = GenericResponse)
+    )
+)]
+#[post("/user/role/assign", format = "json", data = "<role_data>")]
+pub async fn assign_user_role(
+    role_data: Json<UserRoleRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::AssignRoles>
+) -> Result<Json<GenericResponse>, ApiError> {
+    let uuid = Uuid::parse_str(&role_data.uid).map_err(|_| ApiError::from(Status::BadRequest))?;
+
+    let db_error = |e: sqlx::Error| {
+        error!("Assign role API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    lock_role_holder(&mut tx, uuid, role_data.role_id).await?;
+
+    let added = user_roles::assign_role(&mut tx, uuid, role_data.role_id).await.map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    let message = if added {
+        info!("{} gave role {} to user {}", permitted.user.user_id, role_data.role_id, uuid);
+        "role assigned"
+    } else {
+        "the user already holds the role"
+    };
+    Ok(Json(GenericResponse { status: "success".to_string(), message: message.to_string() }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/role/remove",
+    tag = "User",
+    request_body = UserRoleRequest,
+    responses(
+        (status = 200, description = "Take a role away from a user; their access tokens are retired", body = GenericResponse),
+        (status = 409, description = "The user's last role, or a directory or single sign-on user", body = GenericResponse)
+    )
+)]
+#[post("/user/role/remove", format = "json", data = "<role_data>")]
+pub async fn remove_user_role(
+    role_data: Json<UserRoleRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::AssignRoles>
+) -> Result<Json<GenericResponse>, ApiError> {
+    let uuid = Uuid::parse_str(&role_data.uid).map_err(|_| ApiError::from(Status::BadRequest))?;
+
+    let db_error = |e: sqlx::Error| {
+        error!("Remove role API error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+    let mut tx = pool.begin().await.map_err(db_error)?;
+    lock_role_holder(&mut tx, uuid, role_data.role_id).await?;
+
+    let held = sqlx::query_scalar!("SELECT role_id FROM user_roles WHERE user_id = $1", uuid)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(db_error)?;
+    if !held.contains(&role_data.role_id) {
+        return Err(ApiError::new(Status::NotFound, "the user does not hold the role"));
+    }
+    if held.len() == 1 {
+        return Err(ApiError::new(Status::Conflict, "a user needs at least one role"));
+    }
+
+    user_roles::remove_role(&mut tx, uuid, role_data.role_id).await.map_err(db_error)?;
+    tx.commit().await.map_err(db_error)?;
+
+    info!("{} took role {} from user {}", permitted.user.user_id, role_data.role_id, uuid);
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "role removed".to_string() }))
+}
+
+// checks user and role exist and locks the user row, so concurrent changes of the
+// same user's roles run one after the other
+async fn lock_role_holder(conn: &mut sqlx::PgConnection, user_id: Uuid, role_id: i32) -> Result<(), ApiError> {
+    let db_error = |e: sqlx::Error| {
+        error!("Database error: {:?}", e);
+        ApiError::from(Status::InternalServerError)
+    };
+
+    let source = sqlx::query_scalar!("SELECT auth_source FROM users WHERE id = $1 FOR UPDATE", user_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?
+        .ok_or_else(|| ApiError::new(Status::NotFound, "user not found"))?;
+    // replaced from the mapped groups, or the provider's role claim, on every login
+    if source == LDAP_SOURCE {
+        return Err(ApiError::new(Status::Conflict, "the roles of directory users follow their groups"));
+    }
+    if source == OIDC_SOURCE {
+        return Err(ApiError::new(Status::Conflict, "the roles of single sign-on users follow their identity provider"));
+    }
+
+    let role = sqlx::query_scalar!("SELECT id FROM roles WHERE id = $1", role_id)
+        .fetch_optional(&mut *conn)
+        .await
+        .map_err(db_error)?;
+    if role.is_none() {
+        return Err(ApiError::new(Status::NotFound, "role not found"));
+    }
+    Ok(())
+}
+
 #[utoipa::path(
     post,
     path = "/api/user/editpassword",
@@ -441,42 +1007,200 @@ pub async fn soft_delete_user(
 pub async fn edit_password(
     edit_data: Json<EditRequest>,
     pool: &State<PgPool>,
-    user_with_permissions: UserWithPermissions
-) -> Result<Json<GenericResponse>, Status> {
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    _permitted: Require<perm::EditPassword>
+) -> Result<Json<GenericResponse>, ApiError> {
     let edit_req = edit_data.into_inner();
 
-    if !user_with_permissions.permissions.contains("editPassword") {
-        return Err(Status::Forbidden);
-    }
-
     let uuid = match uuid::Uuid::parse_str(&edit_req.Uid) {
         Ok(u) => u,
-        Err(_) => return Err(Status::BadRequest)
+        Err(_) => return Err(Status::BadRequest.into())
     };
 
-    if !valided_password(&edit_req.newPassword) {
-        return Err(Status::BadRequest);
-    }
+    let username = match sqlx::query!("SELECT username FROM users WHERE id = $1", uuid)
+        .fetch_optional(pool.inner())
+        .await
+    {
+        Ok(Some(user)) => user.username,
+        Ok(None) => return Err(Status::NotFound.into()),
+        Err(e) => {
+            error!("Database error: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
 
-    let hashed_password = match bcrypt::hash(&edit_req.newPassword, bcrypt::DEFAULT_COST) {
+    ensure_local_password(pool.inner(), uuid).await?;
+    enforce_password_policy(pool.inner(), policy.inner(), hasher.inner(), &username, Some(uuid), &edit_req.newPassword).await?;
+
+    let hashed_password = match hasher.hash(&edit_req.newPassword).await {
         Ok(h) => h,
-        Err(_) => return Err(Status::InternalServerError)
+        Err(e) => return Err(Status::from(e).into())
     };
 
-    match sqlx::query!(
-        "UPDATE users SET password = $1 WHERE id = $2",
-        hashed_password,
-        uuid
-    )
-    .execute(pool.inner())
-    .await {
+    match password_policy::record_password_change(pool.inner(), policy.inner(), uuid, &hashed_password).await {
         Ok(_) => {
             info!("Updated password for user");
             Ok(Json(GenericResponse { status: "success".to_string(), message: "password update succedd".to_string() }))
         },
         Err(e) => {
             error!("Error updateing password: {}", e);
-            Err(Status::InsufficientStorage)
+            Err(Status::InsufficientStorage.into())
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/me/password",
+    tag = "User",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, description = "Change the current user's password, signs out the other sessions and returns a new access token for this one", body = LoginResponse)
+    ),
+)]
+#[post("/user/me/password", format = "json", data = "<change_data>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn change_own_password(
+    change_data: Json<ChangePasswordRequest>,
+    auth_user: AuthenticatedUser,
+    pool: &State<PgPool>,
+    policy: &State<PasswordPolicy>,
+    hasher: &State<PasswordHasher>,
+    throttle_config: &State<LoginThrottleConfig>,
+    keyring: &State<JwtKeyring>,
+    token_config: &State<TokenConfig>,
+    cookies: &CookieJar<'_>
+) -> Result<Json<LoginResponse>, ApiError> {
+    let change_req = change_data.into_inner();
+
+    // a stolen session must not be able to guess the password either
+    let throttle_key = user_key(&auth_user.username);
+    match is_locked(pool.inner(), std::slice::from_ref(&throttle_key)).await {
+        Ok(false) => {},
+        Ok(true) => return Err(Status::TooManyRequests.into()),
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
         }
     }
-}
\ No newline at end of file
+
+    ensure_local_password(pool.inner(), auth_user.user_id).await?;
+
+    let current_hash = match sqlx::query!("SELECT password FROM users WHERE id = $1", auth_user.user_id)
+        .fetch_one(pool.inner())
+        .await
+    {
+        Ok(user) => user.password,
+        Err(e) => {
+            error!("Database error occurred: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+
+    let verified = match hasher.verify(&change_req.old_password, &current_hash).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            error!("Password check failed: {}", e);
+            return Err(Status::from(e).into());
+        }
+    };
+
+    if !verified {
+        warn!("Wrong current password from user {} changing password", auth_user.username);
+        if let Err(e) = login_throttle::record_failure(pool.inner(), throttle_config.inner(), &throttle_key, throttle_config.max_failures).await {
+            error!("Failed to record login failure: {:?}", e);
+        }
+        return Err(ApiError::new(Status::BadRequest, "current password is wrong"));
+    }
+
+    enforce_password_policy(pool.inner(), policy.inner(), hasher.inner(), &auth_user.username, Some(auth_user.user_id), &change_req.new_password).await?;
+
+    let hashed_password = match hasher.hash(&change_req.new_password).await {
+        Ok(h) => h,
+        Err(e) => return Err(Status::from(e).into())
+    };
+
+    if let Err(e) = password_policy::record_password_change(pool.inner(), policy.inner(), auth_user.user_id, &hashed_password).await {
+        error!("Error updateing password: {:?}", e);
+        return Err(Status::InternalServerError.into());
+    }
+
+    // whoever knew the old password is signed out, this session stays
+    match revoke_user_sessions(pool.inner(), auth_user.user_id, Some(auth_user.claims.sid)).await {
+        Ok(count) => info!("User {} changed password, {} other sessions revoked", auth_user.username, count),
+        Err(e) => {
+            error!("Failed to revoke sessions after password change: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    }
+
+    // the change bumped the user's token version, which the caller's own token still carries
+    let grant = match token_grant(pool.inner(), auth_user.user_id).await {
+        Ok(grant) => grant,
+        Err(e) => {
+            error!("Failed to load role for token: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    let token = match generate_jwt(keyring.inner(), &auth_user.username, auth_user.claims.sid, grant, token_config.access_ttl).await {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to generate JWT: {:?}", e);
+            return Err(Status::InternalServerError.into());
+        }
+    };
+    if cookies.get(TOKEN_COOKIE).is_some() {
+        cookies.add(access_cookie(&token));
+    }
+
+    Ok(Json(LoginResponse {
+        status: "success".to_string(),
+        message: "Password changed".to_string(),
+        token: Some(token),
+        refresh_token: None,
+        expires_in: Some(token_config.access_ttl.num_seconds()),
+        challenge_token: None
+    }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/user/unlock",
+    tag = "User",
+    request_body = UnlockRequest,
+    responses(
+        (status = 200, description = "Clear a login lockout", body = GenericResponse)
+    ),
+)]
+#[post("/user/unlock", format = "json", data = "<unlock_data>")]
+pub async fn unlock_user(
+    unlock_data: Json<UnlockRequest>,
+    pool: &State<PgPool>,
+    permitted: Require<perm::UnlockUser>
+) -> Result<Json<GenericResponse>, Status> {
+    let unlock_req = unlock_data.into_inner();
+
+    let mut keys = Vec::new();
+    if let Some(username) = &unlock_req.username {
+        keys.push(user_key(username));
+    }
+    if let Some(ip) = &unlock_req.ip_address {
+        keys.push(ip_key(ip));
+    }
+    if keys.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    for key in keys {
+        match login_throttle::reset(pool.inner(), &key).await {
+            Ok(_) => info!("{} unlocked login for {}", permitted.user.user_id, key),
+            Err(e) => {
+                error!("Unlock API error: {:?}", e);
+                return Err(Status::InternalServerError);
+            }
+        }
+    }
+
+    Ok(Json(GenericResponse { status: "success".to_string(), message: "Login unlocked".to_string() }))
+}
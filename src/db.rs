@@ -3,6 +3,7 @@ use sqlx::PgPool;
 use sqlx::Pool;
 use sqlx::Error;
 use sqlx::postgres::Postgres;
+use sqlx::migrate::MigrateError;
 
 pub async fn init_db() -> PgPool {
     dotenv::dotenv().ok();
@@ -22,3 +23,9 @@ pub async fn test_db_connection(pool: &Pool<Postgres>) -> Result<(), Error> {
     // 嘗試從數據庫獲取當前時間來測試連接
     sqlx::query!("SELECT NOW()").fetch_one(pool).await.map(|_| ())
 }
+
+
+// 套用 migrations/ 底下尚未執行的 schema 變更
+pub async fn run_migrations(pool: &Pool<Postgres>) -> Result<(), MigrateError> {
+    sqlx::migrate!().run(pool).await
+}
@@ -2,17 +2,35 @@
 extern  crate rocket;
 
 // import rocket 
-use std::sync::Mutex;
 use std::collections::HashMap;
-use rocket_cors::{AllowedHeaders, AllowedOrigins, CorsOptions};
+use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket};
+use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
 use dotenv::dotenv;
 use utoipa::OpenApi;
 use utoipa_scalar::{Scalar, Servable};
 
-use crate::controllers::user_controller::{ get_users, register, generate_captcha_handler, login, logout, TokenBlack, get_userinfo, soft_delete_user, edit_password };
-use crate::controllers::permission_controller::{ permission_list, get_role_permission, add_role_permissiom, delete_role_permission, get_role };
+use crate::controllers::user_controller::{ get_users, register, generate_captcha_handler, login, login_totp, login_password, refresh, logout, get_userinfo, soft_delete_user, reactivate_user, assign_user_role, remove_user_role, edit_password, change_own_password, unlock_user };
+use crate::controllers::permission_controller::{ permission_list, get_role_permission, add_role_permissiom, delete_role_permission, get_role, create_role, rename_role, delete_role, set_role_parents, set_mfa_policy, user_permission_list, set_user_permission, delete_user_permission };
 use crate::controllers::worklist_controller::{worklist_setting, sync_worklist};
-use crate::models::captcha::CaptchaInfo;
+use crate::controllers::totp_controller::{totp_setup, totp_confirm, totp_disable};
+use crate::controllers::password_reset_controller::{issue_reset_code, redeem_reset_code};
+use crate::controllers::metrics_controller::password_hashing_metrics;
+use crate::controllers::oidc_controller::{oidc_login, oidc_callback, link_oidc_identity};
+use crate::controllers::service_account_controller::{create_service_account, list_service_accounts, create_api_key, revoke_api_key};
+use crate::controllers::session_controller::{list_sessions, revoke_own_session, revoke_all_sessions, list_user_sessions, terminate_user_sessions};
+use crate::models::captcha::{CaptchaStore, CaptchaConfig, spawn_captcha_sweeper};
+use crate::tools::jwt::{JwtKeyring, TokenConfig};
+use crate::tools::login_throttle::LoginThrottleConfig;
+use crate::tools::client::ClientConfig;
+use crate::tools::totp::TotpConfig;
+use crate::tools::password_policy::PasswordPolicy;
+use crate::tools::password_hash::PasswordHasher;
+use crate::tools::auth_backend::AuthBackends;
+use crate::tools::oidc::{OidcConfig, OidcProvider};
+use crate::tools::password_reset::ResetCodeConfig;
+use crate::tools::permission_cache::PermissionCache;
+use crate::tools::route_policy;
 
 mod db;
 mod responses;
@@ -33,7 +51,85 @@ async fn rocket() -> _ {
         std::process::exit(1);
     }
 
-    let cors = CorsOptions {
+    if let Err(e) = db::run_migrations(&db_pool).await {
+        eprintln!("Failed to run database migrations: {:?}", e);
+        std::process::exit(1);
+    }
+
+    match tools::perm::sync_permission_rows(&db_pool).await {
+        Ok(unknown) if !unknown.is_empty() => {
+            log::warn!("Permissions no route or page knows about: {}", unknown.join(", "));
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to sync the permission table: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let keyring = match JwtKeyring::from_env() {
+        Ok(keyring) => keyring,
+        Err(e) => {
+            eprintln!("Invalid JWT key configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let hasher = match PasswordHasher::from_env() {
+        Ok(hasher) => hasher,
+        Err(e) => {
+            eprintln!("Invalid password hashing parameters: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let backends = match AuthBackends::from_env() {
+        Ok(backends) => backends,
+        Err(e) => {
+            eprintln!("Invalid authentication backend configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let oidc = match OidcConfig::from_env() {
+        Ok(config) => OidcProvider::new(config),
+        Err(e) => {
+            eprintln!("Invalid OIDC configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let prune_pool = db_pool.clone();
+    let captcha_store = CaptchaStore::new(HashMap::new());
+    let captcha_config = CaptchaConfig::from_env();
+    let sweep_store = captcha_store.clone();
+    let sweep_interval = captcha_config.sweep_interval_seconds;
+
+    let rocket = rocket::build()
+    .attach(AdHoc::on_liftoff("Background cleanup", move |_| Box::pin(async move {
+        tools::token_revocation::spawn_pruner(prune_pool);
+        spawn_captcha_sweeper(sweep_store, sweep_interval);
+    })))
+    .manage(db_pool)
+    .manage(keyring)
+    .manage(TokenConfig::from_env())
+    .manage(LoginThrottleConfig::from_env())
+    .manage(ClientConfig::from_env())
+    .manage(TotpConfig::from_env())
+    .manage(PasswordPolicy::from_env())
+    .manage(hasher)
+    .manage(backends)
+    .manage(oidc)
+    .manage(ResetCodeConfig::from_env())
+    .manage(PermissionCache::new())
+    .manage(captcha_store)
+    .manage(captcha_config);
+
+    app(rocket)
+}
+
+fn cors() -> Cors {
+    CorsOptions {
         allowed_origins: AllowedOrigins::all(),
         allowed_methods: vec![rocket::http::Method::Get, rocket::http::Method::Post, rocket::http::Method::Options]
         .into_iter()
@@ -43,37 +139,75 @@ async fn rocket() -> _ {
             "Authorization",
             "Accept",
             "Content-Type",
+            "X-CSRF-Token",
+            "X-API-Key",
         ]),
         allow_credentials: true,
         ..Default::default()
     }
-    .to_cors().unwrap();
+    .to_cors().unwrap()
+}
 
-    rocket::build()
-    .attach(cors)
-    .manage(db_pool)
-    .manage(TokenBlack::new())
-    .manage(Mutex::new(HashMap::<String, CaptchaInfo>::new()))
-    .mount("/", Scalar::with_url("/apidoc", tools::apidoc::ApiDoc::openapi()))
+// cors, the route policy and every route; the managed state is up to the caller
+fn app(rocket: Rocket<Build>) -> Rocket<Build> {
+    let protected = route_policy::ProtectedRoutes::default();
+    rocket
+    .attach(cors())
+    .attach(protected.fairing())
+    .mount("/", protected.protect(Scalar::with_url("/apidoc", tools::apidoc::ApiDoc::openapi()).into()))
     .mount(
         "/api", 
-        routes![
+        protected.protect(routes![
             get_users,
             register,
             generate_captcha_handler,
             login,
+            login_totp,
+            login_password,
+            oidc_login,
+            oidc_callback,
+            link_oidc_identity,
+            refresh,
             logout,
             get_userinfo,
             soft_delete_user,
+            reactivate_user,
+            assign_user_role,
+            remove_user_role,
             edit_password,
+            change_own_password,
+            issue_reset_code,
+            redeem_reset_code,
+            unlock_user,
+            list_sessions,
+            revoke_own_session,
+            revoke_all_sessions,
+            list_user_sessions,
+            terminate_user_sessions,
+            totp_setup,
+            totp_confirm,
+            totp_disable,
             permission_list,
             get_role_permission,
             add_role_permissiom, 
             delete_role_permission,
+            user_permission_list,
+            set_user_permission,
+            delete_user_permission,
             get_role,
+            create_role,
+            rename_role,
+            delete_role,
+            set_role_parents,
+            set_mfa_policy,
             worklist_setting,
             sync_worklist,
-        ]
+            password_hashing_metrics,
+            create_service_account,
+            list_service_accounts,
+            create_api_key,
+            revoke_api_key,
+        ])
     )
 }
 
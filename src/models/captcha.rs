@@ -1,23 +1,67 @@
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, LockResult, Mutex, MutexGuard};
 use uuid:: Uuid;
 use std::time::{SystemTime, Duration};
 use captcha::{Captcha};
+use log::info;
 
 pub struct CaptchaInfo {
     pub captcha: String,
     pub expires: SystemTime,
 }
 
-pub type CaptchaStore = Mutex<HashMap<String, CaptchaInfo>>;
+// shared with the background sweeper, so it lives behind an Arc
+#[derive(Clone)]
+pub struct CaptchaStore(Arc<Mutex<HashMap<String, CaptchaInfo>>>);
 
-pub async fn generate_captcha(store: &CaptchaStore) -> (String, String) {
+impl CaptchaStore {
+    pub fn new(entries: HashMap<String, CaptchaInfo>) -> Self {
+        CaptchaStore(Arc::new(Mutex::new(entries)))
+    }
+
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, HashMap<String, CaptchaInfo>>> {
+        self.0.lock()
+    }
+}
+
+pub struct CaptchaConfig {
+    pub length: u32,
+    pub width: u32,
+    pub height: u32,
+    pub noise: f32,
+    pub ttl_seconds: u64,
+    pub sweep_interval_seconds: u64,
+    // 0: always required, N: only after N failed logins from the same client
+    pub required_after_failures: i32,
+}
+
+impl CaptchaConfig {
+    // CAPTCHA_LENGTH (4), CAPTCHA_WIDTH (220), CAPTCHA_HEIGHT (100), CAPTCHA_NOISE (0.1),
+    // CAPTCHA_TTL_SECONDS (60), CAPTCHA_SWEEP_SECONDS (60), CAPTCHA_AFTER_FAILURES (0)
+    pub fn from_env() -> Self {
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|v| v.parse::<T>().ok()).unwrap_or(default)
+        }
+
+        CaptchaConfig {
+            length: read("CAPTCHA_LENGTH", 4),
+            width: read("CAPTCHA_WIDTH", 220),
+            height: read("CAPTCHA_HEIGHT", 100),
+            noise: read("CAPTCHA_NOISE", 0.1),
+            ttl_seconds: read("CAPTCHA_TTL_SECONDS", 60),
+            sweep_interval_seconds: read("CAPTCHA_SWEEP_SECONDS", 60),
+            required_after_failures: read("CAPTCHA_AFTER_FAILURES", 0),
+        }
+    }
+}
+
+pub async fn generate_captcha(store: &CaptchaStore, config: &CaptchaConfig) -> (String, String) {
     let mut captcha = Captcha::new();
 
     captcha
-        .add_chars(4)
-        .apply_filter(captcha::filters::Noise::new(0.1))
-        .view(220, 100);
+        .add_chars(config.length)
+        .apply_filter(captcha::filters::Noise::new(config.noise))
+        .view(config.width, config.height);
 
     let captcha_id = Uuid::new_v4().to_string();
     let captcha_text = captcha.chars_as_string();
@@ -25,8 +69,25 @@ pub async fn generate_captcha(store: &CaptchaStore) -> (String, String) {
     let mut store = store.lock().expect("Captcha store lock");
     store.insert(captcha_id.clone(), CaptchaInfo {
         captcha: captcha_text.clone(),
-        expires: SystemTime::now() + Duration::new(60, 0)
+        expires: SystemTime::now() + Duration::new(config.ttl_seconds, 0)
     });
 
     (captcha_id, captcha.as_base64().unwrap())
-}
\ No newline at end of file
+}
+
+// captchas that are requested but never used would otherwise stay in memory forever
+pub fn spawn_captcha_sweeper(store: CaptchaStore, interval_seconds: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_seconds.max(1)));
+        loop {
+            interval.tick().await;
+            let now = SystemTime::now();
+            let mut store = store.lock().expect("Captcha store lock");
+            let before = store.len();
+            store.retain(|_, info| info.expires > now);
+            if store.len() < before {
+                info!("Swept {} expired captchas", before - store.len());
+            }
+        }
+    });
+}
@@ -1,4 +1,7 @@
 pub mod user;
 pub mod captcha;
 pub mod permission;
-pub mod worklist;
\ No newline at end of file
+pub mod worklist;
+pub mod session;
+pub mod totp;
+pub mod service_account;
@@ -9,6 +9,8 @@ use chrono::{NaiveDateTime};
 pub struct Permission {
     pub id: i32,
     pub permissions_name: String,
+    pub description: String,
+    pub category: String,
 }
 
 #[derive(Serialize)]
@@ -24,11 +26,25 @@ pub struct RolePermission {
     pub name: String,
 }
 
+// granted to an ancestor of the role
+#[derive(Serialize, ToSchema)]
+pub struct InheritedPermission {
+    pub id: i32,
+    pub name: String,
+    pub from_role_id: i32,
+    pub from_role_name: String,
+}
+
 #[derive(Serialize)]
 pub struct RoleWithPermissions {
     pub id: i32,
     pub role_name: String,
+    // direct parents, their own parents are listed under their id
+    pub parents: Vec<i32>,
+    // granted to the role itself
     pub permissions: Vec<RolePermission>,
+    // one entry per ancestor holding the permission, also when the role holds it directly
+    pub inherited: Vec<InheritedPermission>,
 }
 
 #[derive(Serialize)]
@@ -49,10 +65,111 @@ pub struct RolePermissionRequest {
 #[derive(Serialize, Deserialize, FromRow, Debug, ToSchema)]
 pub struct Role {
     pub id: i32,
-    pub role_name: String
+    pub role_name: String,
+    // members must log in with a second factor
+    pub require_mfa: bool,
 }
 
 #[derive(Serialize, ToResponse)]
 pub struct RoleResponse {
     pub role: Vec<Role>,
-}
\ No newline at end of file
+}
+#[derive(Deserialize, ToSchema)]
+pub struct CreateRoleRequest {
+    #[serde(rename = "roleName")]
+    pub role_name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RenameRoleRequest {
+    #[serde(rename = "roleId")]
+    pub role_id: i32,
+    #[serde(rename = "roleName")]
+    pub role_name: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeleteRoleRequest {
+    #[serde(rename = "roleId")]
+    pub role_id: i32,
+    // users still holding the role move here; without it the delete is refused while there are any
+    #[serde(rename = "reassignTo")]
+    pub reassign_to: Option<i32>,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct RoleCreatedResponse {
+    pub status: String,
+    pub id: i32,
+}
+
+#[derive(Serialize, FromRow, Debug, ToSchema)]
+pub struct RoleMember {
+    pub id: Uuid,
+    pub username: String,
+    pub deleted: bool,
+}
+
+// 409 body of a refused delete
+#[derive(Serialize, ToResponse)]
+pub struct RoleInUseResponse {
+    pub status: String,
+    pub message: String,
+    pub users: Vec<RoleMember>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RoleParentsRequest {
+    #[serde(rename = "roleId")]
+    pub role_id: i32,
+    // replaces the current parents, empty removes them all
+    #[serde(rename = "parentIds")]
+    pub parent_ids: Vec<i32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionEffect {
+    Grant,
+    // wins over the user's roles and over a grant
+    Deny,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct UserPermissionRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+    #[serde(rename = "permissionName")]
+    pub permissions_name: String,
+    pub effect: PermissionEffect,
+    // never expires when missing
+    #[serde(rename = "expiresInDays")]
+    pub expires_in_days: Option<i32>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct DeleteUserPermissionRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+    #[serde(rename = "permissionName")]
+    pub permissions_name: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserPermission {
+    pub user_id: Uuid,
+    pub username: String,
+    pub permissions_name: String,
+    pub effect: PermissionEffect,
+    pub expires_at: Option<NaiveDateTime>,
+    // false once expired, the row is kept until it is removed or replaced
+    pub active: bool,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct UserPermissionListResponse {
+    pub status: String,
+    pub data: Vec<UserPermission>,
+}
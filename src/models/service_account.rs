@@ -0,0 +1,69 @@
+use sqlx::FromRow;
+use serde::{Serialize, Deserialize};
+use utoipa::{ToResponse, ToSchema};
+use uuid::Uuid;
+use chrono::NaiveDateTime;
+
+#[derive(Serialize, FromRow, Debug, ToSchema)]
+pub struct ApiKeyInfo {
+    pub id: Uuid,
+    // the public part of the key, enough to tell keys apart
+    pub lookup_id: String,
+    pub permissions: Vec<String>,
+    pub created_at: NaiveDateTime,
+    pub expires_at: Option<NaiveDateTime>,
+    pub last_used_at: Option<NaiveDateTime>,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ServiceAccountInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub keys: Vec<ApiKeyInfo>,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct ServiceAccountListResponse {
+    pub status: String,
+    pub data: Vec<ServiceAccountInfo>,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct ServiceAccountResponse {
+    pub status: String,
+    pub id: Uuid,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct ApiKeyResponse {
+    pub status: String,
+    pub id: Uuid,
+    // shown once, only its hash is stored
+    pub key: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateServiceAccountRequest {
+    pub name: String,
+    pub description: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateApiKeyRequest {
+    #[serde(rename = "serviceAccountId")]
+    pub service_account_id: String,
+    // permission names, each one has to be held by the caller
+    pub permissions: Vec<String>,
+    // never expires when missing
+    #[serde(rename = "expiresInDays")]
+    pub expires_in_days: Option<i32>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RevokeApiKeyRequest {
+    #[serde(rename = "keyId")]
+    pub key_id: String,
+}
@@ -0,0 +1,34 @@
+use sqlx::FromRow;
+use serde::{Serialize, Deserialize};
+use utoipa::{ToResponse, ToSchema};
+use uuid::Uuid;
+use chrono::NaiveDateTime;
+
+#[derive(Serialize, FromRow, Debug, ToSchema)]
+pub struct SessionInfo {
+    pub id: Uuid,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    // the session the request was made with
+    pub current: bool,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct SessionListResponse {
+    pub status: String,
+    pub data: Vec<SessionInfo>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RevokeSessionRequest {
+    #[serde(rename = "sessionId")]
+    pub session_id: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TerminateSessionsRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+}
@@ -0,0 +1,46 @@
+use serde::{Serialize, Deserialize};
+use utoipa::{ToResponse, ToSchema};
+
+#[derive(Serialize, ToResponse)]
+pub struct TotpSetupResponse {
+    pub status: String,
+    // base32, for typing into the app by hand
+    pub secret: String,
+    pub otpauth_url: String,
+    // base64 png of otpauth_url
+    pub qr_code: String,
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct TotpConfirmResponse {
+    pub status: String,
+    pub message: String,
+    // shown once, each one replaces a code a single time
+    pub recovery_codes: Vec<String>,
+    // filled when the enrollment finished a login
+    pub token: Option<String>,
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<i64>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TotpLoginRequest {
+    #[serde(rename = "challengeToken")]
+    pub challenge_token: String,
+    pub code: Option<String>,
+    #[serde(rename = "recoveryCode")]
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MfaPolicyRequest {
+    #[serde(rename = "roleId")]
+    pub role_id: i32,
+    #[serde(rename = "requireMfa")]
+    pub require_mfa: bool,
+}
@@ -11,7 +11,7 @@ pub struct User {
     voice_attachment: bool,
     created_at: Option<NaiveDateTime>,
     updated_at: Option<NaiveDateTime>,
-    role_id: i32,
+    role_ids: Vec<i32>,
     deleted: bool
 }
 
@@ -20,27 +20,17 @@ pub struct UserWithRole {
     pub id: Option<Uuid>,
     pub username: String,
     pub voice_attachment: Option<bool>,
-    pub role_id: i32,
     pub deleted: Option<bool>,
-    pub role_name: String
+    // every role the user holds, the names in the same order
+    pub role_ids: Vec<i32>,
+    pub role_names: Vec<String>
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug, ToSchema)]
 pub struct UserInfo {
     pub id: Option<Uuid>,
     pub username: String,
-    pub role_id: i32,
-}
-
-#[derive(Serialize, Deserialize, FromRow, Debug)]
-pub struct Role {
-    pub id: i32,
-    pub role_name: String
-}
-#[derive(Serialize, Deserialize, FromRow, Debug)]
-pub struct Permission {
-    pub id: i32,
-    pub permissions_name: String,
+    pub role_ids: Vec<i32>,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug)]
@@ -56,8 +46,10 @@ pub struct RegisterRequest {
 pub struct LoginRequest {
     pub username: String,
     pub password: String,
-    pub captcha: String,
-    pub captchaId: String,
+    // optional when CAPTCHA_AFTER_FAILURES lets this client skip it
+    pub captcha: Option<String>,
+    #[serde(rename = "captchaId")]
+    pub captcha_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, FromRow, Debug)]
@@ -72,3 +64,62 @@ pub struct EditRequest {
     pub newPassword: String,
 }
 
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct RefreshRequest {
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct UnlockRequest {
+    pub username: Option<String>,
+    #[serde(rename = "ipAddress")]
+    pub ip_address: Option<String>,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ExpiredPasswordRequest {
+    #[serde(rename = "challengeToken")]
+    pub challenge_token: String,
+    #[serde(rename = "newPassword")]
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ChangePasswordRequest {
+    #[serde(rename = "oldPassword")]
+    pub old_password: String,
+    #[serde(rename = "newPassword")]
+    pub new_password: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct LinkIdentityRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+    // the `sub` claim the identity provider issues for the account
+    pub subject: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct UserRoleRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+    #[serde(rename = "roleId")]
+    pub role_id: i32,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct ResetCodeRequest {
+    #[serde(rename = "Uid")]
+    pub uid: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct RedeemResetCodeRequest {
+    pub username: String,
+    pub code: String,
+    #[serde(rename = "newPassword")]
+    pub new_password: String,
+}
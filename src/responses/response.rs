@@ -1,4 +1,10 @@
+use crate::models::permission::RoleInUseResponse;
 use crate::models::user::UserWithRole;
+use crate::tools::password_hash::HashMetricsSnapshot;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, status, Responder};
+use rocket::serde::json::Json;
 use serde::Serialize;
 use utoipa::ToResponse;
 use uuid::Uuid;
@@ -9,6 +15,45 @@ pub struct GenericResponse {
     pub message: String,
 }
 
+// error status with a GenericResponse body, for failures the client has to explain to the user
+#[derive(Debug)]
+pub struct ApiError {
+    pub status: Status,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        ApiError { status, message: message.into() }
+    }
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        ApiError::new(status, status.reason().unwrap_or("error"))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let body = GenericResponse { status: "error".to_string(), message: self.message };
+        status::Custom(self.status, Json(body)).respond_to(request)
+    }
+}
+
+// a role delete refused because users still hold the role, or any other failure
+#[derive(Responder)]
+pub enum DeleteRoleError {
+    #[response(status = 409)]
+    InUse(Json<RoleInUseResponse>),
+    Failed(ApiError),
+}
+
+impl From<Status> for DeleteRoleError {
+    fn from(status: Status) -> Self {
+        DeleteRoleError::Failed(status.into())
+    }
+}
 
 #[derive(Serialize, Debug, ToResponse)]
 pub struct UserListResponse {
@@ -33,7 +78,8 @@ pub struct LogoutResponse {
 #[derive(Serialize, ToResponse)]
 pub struct UserInfoResponse {
     pub username: String,
-    pub role: String,
+    pub roles: Vec<String>,
+    // what all the roles allow together, with the user's own grants and denies applied
     pub permissions: Vec<String>
 }
 
@@ -43,5 +89,22 @@ pub struct LoginResponse {
     pub status: String,
     pub message: String,
     pub token: Option<String>, // Add token to response
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<i64>, // access token 秒数
+    // set instead of the tokens when another login step is needed
+    pub challenge_token: Option<String>,
 }
 
+#[derive(Serialize, ToResponse)]
+pub struct ResetCodeResponse {
+    pub status: String,
+    // shown to the admin once, only its hash is stored
+    pub code: String,
+    pub expires_in: i64, // 秒数
+}
+
+#[derive(Serialize, ToResponse)]
+pub struct HashMetricsResponse {
+    pub status: String,
+    pub metrics: HashMetricsSnapshot,
+}
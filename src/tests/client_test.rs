@@ -0,0 +1,25 @@
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::tools::client::ClientConfig;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_forwarded_for_needs_a_trusted_peer() {
+        let config = ClientConfig { trusted_proxies: vec![ip("10.0.0.1"), ip("10.0.0.2")] };
+
+        // anyone else can write the header themselves
+        assert_eq!(config.client_ip(Some(ip("203.0.113.9")), Some("198.51.100.1")), Some(ip("203.0.113.9")));
+        assert_eq!(ClientConfig::default().client_ip(Some(ip("10.0.0.1")), Some("198.51.100.1")), Some(ip("10.0.0.1")));
+
+        // behind the proxies the first address they did not add, not what the client prepended
+        assert_eq!(config.client_ip(Some(ip("10.0.0.1")), Some("1.2.3.4, 198.51.100.1, 10.0.0.2")), Some(ip("198.51.100.1")));
+        assert_eq!(config.client_ip(Some(ip("10.0.0.1")), Some("garbage, 198.51.100.1")), Some(ip("198.51.100.1")));
+        assert_eq!(config.client_ip(Some(ip("10.0.0.1")), None), Some(ip("10.0.0.1")));
+        assert_eq!(config.client_ip(None, Some("198.51.100.1")), None);
+    }
+}
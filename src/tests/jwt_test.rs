@@ -0,0 +1,61 @@
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use jsonwebtoken::{decode_header, Algorithm};
+    use uuid::Uuid;
+
+    use crate::tools::jwt::{Claims, JwtKey, JwtKeyring, RoleVersion};
+
+    fn claims(username: &str) -> Claims {
+        Claims {
+            sub: username.to_string(),
+            exp: (Utc::now().timestamp() + 3600) as usize,
+            iat: Utc::now().timestamp() as usize,
+            jti: Uuid::new_v4(),
+            sid: Uuid::new_v4(),
+            roles: vec![RoleVersion { id: 2, ver: 1 }],
+            tver: 1,
+        }
+    }
+
+    #[test]
+    fn test_token_carries_active_kid() {
+        let keyring = JwtKeyring::new("site-a-2024", vec![
+            JwtKey::hmac("site-a-2024", Algorithm::HS256, b"new-secret"),
+            JwtKey::hmac("site-a-2023", Algorithm::HS256, b"old-secret"),
+        ]).unwrap();
+
+        let token = keyring.sign(&claims("admin")).unwrap();
+        let header = decode_header(&token).unwrap();
+        assert_eq!(header.kid.as_deref(), Some("site-a-2024"));
+        assert_eq!(keyring.verify::<Claims>(&token).unwrap().claims.sub, "admin");
+    }
+
+    #[test]
+    fn test_retired_key_still_validates() {
+        let old = JwtKeyring::new("site-a-2023", vec![
+            JwtKey::hmac("site-a-2023", Algorithm::HS256, b"old-secret"),
+        ]).unwrap();
+        let token = old.sign(&claims("doctor")).unwrap();
+
+        let rotated = JwtKeyring::new("site-a-2024", vec![
+            JwtKey::hmac("site-a-2024", Algorithm::HS256, b"new-secret"),
+            JwtKey::hmac("site-a-2023", Algorithm::HS256, b"old-secret"),
+        ]).unwrap();
+        assert_eq!(rotated.verify::<Claims>(&token).unwrap().claims.sub, "doctor");
+    }
+
+    #[test]
+    fn test_other_site_token_rejected() {
+        let site_a = JwtKeyring::new("site-a", vec![JwtKey::hmac("site-a", Algorithm::HS256, b"secret-a")]).unwrap();
+        let site_b = JwtKeyring::new("site-b", vec![JwtKey::hmac("site-b", Algorithm::HS256, b"secret-b")]).unwrap();
+
+        let token = site_a.sign(&claims("admin")).unwrap();
+        assert!(site_b.verify::<Claims>(&token).is_err());
+    }
+
+    #[test]
+    fn test_active_key_must_exist() {
+        assert!(JwtKeyring::new("missing", vec![JwtKey::hmac("site-a", Algorithm::HS256, b"secret")]).is_err());
+    }
+}
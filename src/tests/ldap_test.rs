@@ -0,0 +1,116 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+
+    use crate::tools::auth_backend::{AuthBackend, BackendContext};
+    use crate::tools::ldap_backend::{parse_group_roles, roles_for_groups, LdapBackend, LdapConfig};
+    use crate::tools::password_hash::PasswordHasher;
+
+    // matches docker/openldap
+    fn config(url: &str, group_roles: &str, default_role: Option<&str>) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            bind_dn: Some("cn=admin,dc=hospital,dc=local".to_string()),
+            bind_password: "admin".to_string(),
+            user_base: "ou=people,dc=hospital,dc=local".to_string(),
+            user_filter: "(uid={username})".to_string(),
+            id_attribute: "entryUUID".to_string(),
+            group_base: Some("ou=groups,dc=hospital,dc=local".to_string()),
+            group_filter: "(member={dn})".to_string(),
+            group_roles: parse_group_roles(group_roles).unwrap(),
+            default_role: default_role.map(str::to_string),
+            starttls: false,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_parse_group_roles() {
+        let roles = parse_group_roles("Radiologists=doctor; CN=IT Admins,OU=Groups,DC=hospital,DC=local=admin;").unwrap();
+        assert_eq!(roles, vec![
+            ("Radiologists".to_string(), "doctor".to_string()),
+            ("CN=IT Admins,OU=Groups,DC=hospital,DC=local".to_string(), "admin".to_string()),
+        ]);
+
+        assert!(parse_group_roles("Radiologists").is_err());
+        assert!(parse_group_roles("Radiologists=").is_err());
+        assert!(parse_group_roles("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_every_mapped_group_gives_a_role() {
+        let config = config("ldap://unused", "IT Admins=admin;cn=radiologists,ou=groups,dc=hospital,dc=local=doctor;Directors=director;Heads=director", None);
+        let radiologist = "CN=Radiologists,OU=Groups,DC=hospital,DC=local".to_string();
+        let it_admin = "CN=IT Admins,OU=Groups,DC=hospital,DC=local".to_string();
+        let director = "CN=Directors,OU=Groups,DC=hospital,DC=local".to_string();
+        let head = "CN=Heads,OU=Groups,DC=hospital,DC=local".to_string();
+
+        // by full DN, case-insensitive
+        assert_eq!(roles_for_groups(&config, std::slice::from_ref(&radiologist)), vec!["doctor"]);
+        // by CN; a teaching doctor gets both roles, in mapping order, each once
+        assert_eq!(roles_for_groups(&config, &[director.clone(), radiologist.clone()]), vec!["doctor", "director"]);
+        assert_eq!(roles_for_groups(&config, &[head, director, radiologist, it_admin]), vec!["admin", "doctor", "director"]);
+        assert!(roles_for_groups(&config, &["CN=Nurses,OU=Groups,DC=hospital,DC=local".to_string()]).is_empty());
+
+        let config = LdapConfig { default_role: Some("director".to_string()), ..config };
+        assert_eq!(roles_for_groups(&config, &[]), vec!["director"]);
+    }
+
+    // needs the directory from docker/openldap, see docker-compose.yml there
+    #[rocket::async_test]
+    #[ignore]
+    async fn test_ldap_bind_creates_user_on_first_login() {
+        dotenv().ok();
+        let url = std::env::var("LDAP_TEST_URL").unwrap_or_else(|_| "ldap://localhost:3389".to_string());
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        let hasher = PasswordHasher::new(1024, 1, 1).unwrap();
+        let ctx = BackendContext { pool: &pool, hasher: &hasher };
+        let backend = LdapBackend::new(config(&url, "IT Admins=admin;Radiologists=doctor", None));
+
+        let cleanup = || async {
+            sqlx::query("DELETE FROM users WHERE auth_source = 'ldap' AND username LIKE 'ldap\\_%'")
+                .execute(&pool)
+                .await
+                .unwrap();
+        };
+        cleanup().await;
+
+        assert_eq!(backend.authenticate(&ctx, "ldap_jdoe", "wrong").await.unwrap(), None);
+        assert_eq!(backend.authenticate(&ctx, "ldap_jdoe", "").await.unwrap(), None);
+        assert_eq!(backend.authenticate(&ctx, "ldap_nobody", "Secret123").await.unwrap(), None);
+        // valid account, but in no mapped group
+        assert_eq!(backend.authenticate(&ctx, "ldap_visitor", "Secret123").await.unwrap(), None);
+
+        let user_id = backend.authenticate(&ctx, "ldap_jdoe", "Secret123").await.unwrap().expect("directory login");
+        let role: String = sqlx::query_scalar("SELECT r.role_name FROM user_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = $1")
+            .bind(user_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(role, "doctor");
+
+        // the same row on the next login
+        assert_eq!(backend.authenticate(&ctx, "ldap_jdoe", "Secret123").await.unwrap(), Some(user_id));
+
+        // the name of a single sign-on user is not taken over, whatever the case
+        sqlx::query("INSERT INTO users (username, password, voice_attachment, auth_source) VALUES ('LDAP_ASMITH', '!', false, 'oidc')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(backend.authenticate(&ctx, "ldap_asmith", "Secret123").await.unwrap(), None);
+        sqlx::query("DELETE FROM users WHERE username = 'LDAP_ASMITH'").execute(&pool).await.unwrap();
+
+        let admin_id = backend.authenticate(&ctx, "ldap_asmith", "Secret123").await.unwrap().expect("directory login");
+        let role: String = sqlx::query_scalar("SELECT r.role_name FROM user_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = $1")
+            .bind(admin_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(role, "admin");
+
+        cleanup().await;
+    }
+}
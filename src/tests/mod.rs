@@ -1 +1,9 @@
-pub mod user_test;
\ No newline at end of file
+pub mod user_test;
+pub mod jwt_test;
+pub mod password_policy_test;
+pub mod password_hash_test;
+pub mod ldap_test;
+pub mod oidc_test;
+pub mod perm_test;
+pub mod route_policy_test;
+pub mod client_test;
@@ -0,0 +1,280 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use dotenv::dotenv;
+    use jsonwebtoken::Algorithm;
+    use openidconnect::core::{CoreGenderClaim, CoreJsonWebKeySet, CoreJwsSigningAlgorithm, CoreRsaPrivateSigningKey};
+    use openidconnect::{
+        Audience, IdTokenClaims, IssuerUrl, JsonWebKeyId, Nonce, PkceCodeChallenge, PkceCodeVerifier, PrivateSigningKey,
+        StandardClaims, SubjectIdentifier,
+    };
+    use rocket::config::{Config, LogLevel};
+    use rocket::form::Form;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::{json, serde_json, Json, Value};
+    use rocket::{get, post, routes, State};
+    use sqlx::PgPool;
+
+    use crate::controllers::oidc_controller::{oidc_login, oidc_callback};
+    use crate::tools::jwt::{JwtKey, JwtKeyring, TokenConfig};
+    use crate::tools::oidc::{self, ExtraClaims, OidcConfig, OidcIdToken, OidcProvider};
+
+    const IDP_PORT: u16 = 18452;
+    const CLIENT_ID: &str = "rocket_work";
+
+    fn issuer() -> String {
+        format!("http://127.0.0.1:{}", IDP_PORT)
+    }
+
+    // what the IdP puts into the ID token for a code
+    struct PendingCode {
+        nonce: String,
+        code_challenge: String,
+        claims: HashMap<String, Value>,
+    }
+
+    struct MockIdp {
+        key: CoreRsaPrivateSigningKey,
+        codes: Mutex<HashMap<String, PendingCode>>,
+    }
+
+    #[get("/.well-known/openid-configuration")]
+    fn discovery() -> Json<Value> {
+        Json(json!({
+            "issuer": issuer(),
+            "authorization_endpoint": format!("{}/authorize", issuer()),
+            "token_endpoint": format!("{}/token", issuer()),
+            "jwks_uri": format!("{}/jwks", issuer()),
+            "response_types_supported": ["code"],
+            "subject_types_supported": ["public"],
+            "id_token_signing_alg_values_supported": ["RS256"],
+        }))
+    }
+
+    #[get("/jwks")]
+    fn jwks(idp: &State<Arc<MockIdp>>) -> Json<CoreJsonWebKeySet> {
+        Json(CoreJsonWebKeySet::new(vec![idp.key.as_verification_key()]))
+    }
+
+    #[post("/token", data = "<form>")]
+    fn token(form: Form<HashMap<String, String>>, idp: &State<Arc<MockIdp>>) -> Result<Json<Value>, Status> {
+        let code = form.get("code").ok_or(Status::BadRequest)?;
+        let pending = idp.codes.lock().unwrap().remove(code).ok_or(Status::BadRequest)?;
+
+        // PKCE: the verifier has to hash to the challenge from the authorize request
+        let verifier = PkceCodeVerifier::new(form.get("code_verifier").ok_or(Status::BadRequest)?.clone());
+        if PkceCodeChallenge::from_code_verifier_sha256(&verifier).as_str() != pending.code_challenge {
+            return Err(Status::BadRequest);
+        }
+
+        // sub is a standard claim, the rest goes in as extra claims
+        let mut extra = pending.claims;
+        let subject = extra.remove("sub").unwrap().as_str().unwrap().to_string();
+        let claims = IdTokenClaims::<ExtraClaims, CoreGenderClaim>::new(
+            IssuerUrl::new(issuer()).unwrap(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            Utc::now() + chrono::Duration::minutes(5),
+            Utc::now(),
+            StandardClaims::new(SubjectIdentifier::new(subject)),
+            ExtraClaims { claims: extra },
+        )
+        .set_nonce(Some(Nonce::new(pending.nonce)));
+
+        let id_token = OidcIdToken::new(claims, &idp.key, CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256, None, None).unwrap();
+        Ok(Json(json!({
+            "access_token": "mock-access-token",
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token.to_string(),
+        })))
+    }
+
+    // a real HTTP server, the client library talks to the IdP over the network
+    async fn start_mock_idp() -> Arc<MockIdp> {
+        let key = CoreRsaPrivateSigningKey::from_pem(
+            include_str!("fixtures/oidc_test_key.pem"),
+            Some(JsonWebKeyId::new("test-key".to_string())),
+        )
+        .unwrap();
+        let idp = Arc::new(MockIdp { key, codes: Mutex::new(HashMap::new()) });
+
+        let config = Config { port: IDP_PORT, log_level: LogLevel::Off, ..Config::debug_default() };
+        let server = rocket::custom(config)
+            .manage(idp.clone())
+            .mount("/", routes![discovery, jwks, token]);
+        tokio::spawn(server.launch());
+
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(("127.0.0.1", IDP_PORT)).await.is_ok() {
+                return idp;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("mock IdP did not start");
+    }
+
+    fn query_param(url: &str, name: &str) -> String {
+        let query = url.split_once('?').unwrap().1;
+        query
+            .split('&')
+            .find_map(|pair| pair.split_once('=').filter(|(key, _)| *key == name).map(|(_, value)| value.to_string()))
+            .unwrap()
+    }
+
+    async fn setup_client() -> Client {
+        dotenv().ok();
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        let keyring = JwtKeyring::new("test", vec![JwtKey::hmac("test", Algorithm::HS256, b"test-secret")]).unwrap();
+        let provider = OidcProvider::new(Some(OidcConfig {
+            issuer_url: issuer(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost/api/user/oidc/callback".to_string(),
+            scopes: vec!["profile".to_string()],
+            role_claim: "role_id".to_string(),
+            username_claim: "preferred_username".to_string(),
+            default_role_id: None,
+            login_ttl_minutes: 10,
+        }));
+
+        let rocket = rocket::build()
+            .manage(pool)
+            .manage(keyring)
+            .manage(TokenConfig::from_env())
+            .manage(provider)
+            .mount("/", routes![oidc_login, oidc_callback]);
+        Client::tracked(rocket).await.expect("valid rocket instance")
+    }
+
+    // what the IdP does between our redirect and the callback: the user signs in, a code is issued
+    async fn sign_in_at_idp(client: &Client, idp: &MockIdp, code: &str, claims: Value, nonce: Option<&str>) -> String {
+        let response = client.get("/user/oidc/login").dispatch().await;
+        assert_eq!(response.status(), Status::SeeOther);
+        let location = response.headers().get_one("Location").unwrap().to_string();
+        assert!(location.starts_with(&format!("{}/authorize", issuer())));
+        assert_eq!(query_param(&location, "code_challenge_method"), "S256");
+
+        idp.codes.lock().unwrap().insert(code.to_string(), PendingCode {
+            nonce: nonce.map(str::to_string).unwrap_or_else(|| query_param(&location, "nonce")),
+            code_challenge: query_param(&location, "code_challenge"),
+            claims: serde_json::from_value(claims).unwrap(),
+        });
+        query_param(&location, "state")
+    }
+
+    async fn callback(client: &Client, code: &str, state: &str) -> (Status, Value) {
+        let response = client.get(format!("/user/oidc/callback?code={}&state={}", code, state)).dispatch().await;
+        let status = response.status();
+        (status, response.into_json::<Value>().await.unwrap_or(Value::Null))
+    }
+
+    #[rocket::async_test]
+    async fn test_oidc_login_creates_and_links_users() {
+        let idp = start_mock_idp().await;
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+
+        // first login of an unknown subject creates the user with the role from the claim
+        let new_username = format!("oidc_{}", suffix);
+        let claims = json!({ "sub": format!("sub-a-{}", suffix), "preferred_username": new_username, "role_id": "2" });
+        let state = sign_in_at_idp(&client, &idp, "code-a", claims, None).await;
+        let (status, body) = callback(&client, "code-a", &state).await;
+        assert_eq!(status, Status::Ok);
+        assert!(body["token"].is_string());
+
+        let (source, role_ids): (String, Vec<i32>) = sqlx::query_as(
+            "SELECT u.auth_source, ARRAY_AGG(ur.role_id) FROM users u JOIN user_roles ur ON ur.user_id = u.id WHERE u.username = $1 GROUP BY u.id"
+        )
+            .bind(&new_username)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(source, "oidc");
+        assert_eq!(role_ids, vec![2]);
+
+        // the state is single use
+        assert_eq!(callback(&client, "code-a", &state).await.0, Status::Unauthorized);
+
+        // a state handed out to another browser is refused (here the first of two logins, the
+        // cookie holds the second), the browser's own login still works
+        let claims = json!({ "sub": format!("sub-a-{}", suffix), "preferred_username": new_username, "role_id": 2 });
+        let other_state = sign_in_at_idp(&client, &idp, "code-g", claims.clone(), None).await;
+        let state = sign_in_at_idp(&client, &idp, "code-h", claims, None).await;
+        assert_eq!(callback(&client, "code-g", &other_state).await.0, Status::Unauthorized);
+        assert_eq!(callback(&client, "code-h", &state).await.0, Status::Ok);
+
+        // a token for another login attempt (wrong nonce) is refused
+        let claims = json!({ "sub": format!("sub-a-{}", suffix), "preferred_username": new_username, "role_id": 2 });
+        let state = sign_in_at_idp(&client, &idp, "code-b", claims, Some("not-the-nonce")).await;
+        assert_eq!(callback(&client, "code-b", &state).await.0, Status::Unauthorized);
+
+        // a local user with the same name is not taken over
+        let local_username = format!("local_{}", suffix);
+        let local_id: uuid::Uuid = sqlx::query_scalar(
+            r#"
+                WITH u AS (INSERT INTO users (username, password, voice_attachment) VALUES ($1, 'x', false) RETURNING id)
+                INSERT INTO user_roles (user_id, role_id) SELECT id, 2 FROM u RETURNING user_id
+            "#
+        )
+        .bind(&local_username)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+        let claims = json!({ "sub": format!("sub-b-{}", suffix), "preferred_username": local_username, "role_id": 3 });
+        let state = sign_in_at_idp(&client, &idp, "code-c", claims.clone(), None).await;
+        assert_eq!(callback(&client, "code-c", &state).await.0, Status::Unauthorized);
+        let links: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user_identities WHERE user_id = $1")
+            .bind(local_id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(links, 0);
+
+        // linked by an admin it signs in, and keeps its roles despite the claim
+        assert!(oidc::link_identity(pool, local_id, &issuer(), &format!("sub-b-{}", suffix)).await.unwrap());
+        assert!(!oidc::link_identity(pool, local_id, &issuer(), &format!("sub-b-{}", suffix)).await.unwrap());
+        let state = sign_in_at_idp(&client, &idp, "code-e", claims, None).await;
+        assert_eq!(callback(&client, "code-e", &state).await.0, Status::Ok);
+
+        let role_ids: Vec<i32> = sqlx::query_scalar("SELECT role_id FROM user_roles WHERE user_id = $1")
+            .bind(local_id)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(role_ids, vec![2]);
+
+        // a single sign-on user with the same name is linked, not duplicated, and gets the claimed role
+        let claims = json!({ "sub": format!("sub-c-{}", suffix), "preferred_username": new_username, "role_id": 3 });
+        let state = sign_in_at_idp(&client, &idp, "code-f", claims, None).await;
+        assert_eq!(callback(&client, "code-f", &state).await.0, Status::Ok);
+
+        let linked: Vec<(String, i32)> = sqlx::query_as(
+            "SELECT i.subject, ur.role_id FROM users u JOIN user_identities i ON i.user_id = u.id JOIN user_roles ur ON ur.user_id = u.id WHERE u.username = $1 ORDER BY i.subject"
+        )
+        .bind(&new_username)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        assert_eq!(linked, vec![(format!("sub-a-{}", suffix), 3), (format!("sub-c-{}", suffix), 3)]);
+
+        // later logins find the user by subject, even when the name claim changed
+        let claims = json!({ "sub": format!("sub-b-{}", suffix), "preferred_username": "renamed", "role_id": 3 });
+        let state = sign_in_at_idp(&client, &idp, "code-d", claims, None).await;
+        let (status, body) = callback(&client, "code-d", &state).await;
+        assert_eq!(status, Status::Ok);
+        assert!(body["token"].is_string());
+
+        sqlx::query("DELETE FROM users WHERE username = ANY($1)")
+            .bind(vec![new_username, local_username])
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+}
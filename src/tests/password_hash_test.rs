@@ -0,0 +1,65 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::tools::password_hash::{HashError, PasswordHasher};
+
+    fn hasher() -> PasswordHasher {
+        PasswordHasher::new(1024, 1, 1).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn test_new_hashes_are_argon2id() {
+        let hasher = hasher();
+        let hash = hasher.hash("Passw0rd1").await.unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(hasher.verify("Passw0rd1", &hash).await.unwrap());
+        assert!(!hasher.verify("Passw0rd2", &hash).await.unwrap());
+        assert!(!hasher.needs_rehash(&hash));
+    }
+
+    #[rocket::async_test]
+    async fn test_legacy_bcrypt_verifies_and_needs_rehash() {
+        let hasher = hasher();
+        let hash = bcrypt::hash("Passw0rd1", 4).unwrap();
+
+        assert!(hash.starts_with("$2b$"));
+        assert!(hasher.verify("Passw0rd1", &hash).await.unwrap());
+        assert!(!hasher.verify("Passw0rd2", &hash).await.unwrap());
+        assert!(hasher.needs_rehash(&hash));
+    }
+
+    #[rocket::async_test]
+    async fn test_changed_parameters_need_rehash() {
+        let old = PasswordHasher::new(2048, 1, 1).unwrap().hash("Passw0rd1").await.unwrap();
+        let hasher = hasher();
+
+        // still verifies with the parameters stored in the hash
+        assert!(hasher.verify("Passw0rd1", &old).await.unwrap());
+        assert!(hasher.needs_rehash(&old));
+    }
+
+    #[rocket::async_test]
+    async fn test_unreadable_hash_never_verifies() {
+        assert!(!hasher().verify("Passw0rd1", "not a hash").await.unwrap());
+        assert!(PasswordHasher::new(1, 1, 1).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn test_full_queue_is_rejected_and_counted() {
+        // one slot and no patience: of several hashes started together only the first gets through
+        let hasher = PasswordHasher::new(65536, 3, 1).unwrap().with_limits(1, Duration::from_millis(1));
+
+        let (a, b, c) = tokio::join!(hasher.hash("Passw0rd1"), hasher.hash("Passw0rd2"), hasher.hash("Passw0rd3"));
+        let rejected = [a, b, c].into_iter().filter(|result| matches!(result, Err(HashError::Busy))).count();
+
+        let metrics = hasher.metrics();
+        assert_eq!(rejected, 2);
+        assert_eq!(metrics.rejected, 2);
+        assert_eq!(metrics.completed, 1);
+        assert_eq!(metrics.queued, 0);
+        assert_eq!(metrics.running, 0);
+        assert_eq!(metrics.max_concurrency, 1);
+    }
+}
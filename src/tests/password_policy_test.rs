@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::tools::password_policy::{CharClass, PasswordPolicy, PolicyViolation};
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 10,
+            required_classes: vec![CharClass::Uppercase, CharClass::Digit, CharClass::Symbol],
+            allowed_symbols: "!@#-".to_string(),
+            banned: HashSet::from(["letmein-123A".to_lowercase()]),
+            history: 5,
+            max_age_days: 0,
+        }
+    }
+
+    #[test]
+    fn test_accepts_symbols_from_the_allowed_set() {
+        assert_eq!(policy().check("doctor", "Endo-scope#42"), Ok(()));
+    }
+
+    #[test]
+    fn test_reports_the_rule_that_failed() {
+        let policy = policy();
+
+        assert_eq!(policy.check("doctor", "Ab1!"), Err(PolicyViolation::TooShort(10)));
+        assert_eq!(policy.check("doctor", "endo-scope#42"), Err(PolicyViolation::MissingClass(CharClass::Uppercase)));
+        assert_eq!(policy.check("doctor", "Endo-scope#ab"), Err(PolicyViolation::MissingClass(CharClass::Digit)));
+        assert_eq!(policy.check("doctor", "Endo-scope$42"), Err(PolicyViolation::DisallowedCharacter('$')));
+        assert_eq!(policy.check("Dr-House#1", "dr-house#1"), Err(PolicyViolation::MissingClass(CharClass::Uppercase)));
+        assert_eq!(policy.check("Dr-House#1", "DR-HOUSE#1"), Err(PolicyViolation::SameAsUsername));
+        assert_eq!(policy.check("doctor", "LetMeIn-123a"), Err(PolicyViolation::Banned));
+    }
+
+    #[test]
+    fn test_messages_name_the_rule() {
+        assert_eq!(PolicyViolation::TooShort(10).to_string(), "password must be at least 10 characters long");
+        assert_eq!(PolicyViolation::MissingClass(CharClass::Symbol).to_string(), "password must contain at least one symbol");
+        assert_eq!(PolicyViolation::Reused(5).to_string(), "password must differ from the last 5 passwords");
+    }
+}
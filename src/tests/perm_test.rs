@@ -0,0 +1,53 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use dotenv::dotenv;
+    use sqlx::PgPool;
+
+    use crate::tools::perm::{sync_permission_rows, Permission};
+
+    #[test]
+    fn test_names_round_trip() {
+        for permission in Permission::ALL {
+            assert_eq!(Permission::from_name(permission.name()), Some(*permission));
+        }
+        assert_eq!(Permission::from_name("newuser"), None);
+    }
+
+    #[test]
+    fn test_catalog_entries_are_complete() {
+        let mut names = HashSet::new();
+        for permission in Permission::ALL {
+            assert!(!permission.description().is_empty(), "{}", permission);
+            assert!(!permission.name().contains(' '), "{}", permission);
+            assert!(names.insert(permission.name()), "{}", permission);
+        }
+        // a former name must never come back as a current one, the row would be renamed away
+        for permission in Permission::ALL {
+            for old in permission.former_names() {
+                assert_eq!(Permission::from_name(old), None, "{}", old);
+            }
+        }
+        assert_eq!(Permission::Examinations.former_names(), &["examinatios"]);
+    }
+
+    #[rocket::async_test]
+    async fn test_sync_fills_the_permission_table() {
+        dotenv().ok();
+        let pool = PgPool::connect(&std::env::var("DATABASE_URL").unwrap()).await.unwrap();
+        assert_eq!(sync_permission_rows(&pool).await.unwrap(), Vec::<String>::new());
+        // a second run changes nothing
+        assert_eq!(sync_permission_rows(&pool).await.unwrap(), Vec::<String>::new());
+
+        let rows: Vec<(String, String, String)> = sqlx::query_as("SELECT permissions_name, description, category FROM permissions")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), Permission::ALL.len());
+        for (name, description, category) in rows {
+            let permission = Permission::from_name(&name).unwrap();
+            assert_eq!((description.as_str(), category.as_str()), (permission.description(), permission.category().name()));
+        }
+    }
+}
@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+
+    use dotenv::dotenv;
+    use jsonwebtoken::Algorithm;
+    use rocket::routes;
+    use rocket::error::ErrorKind;
+    use sqlx::PgPool;
+
+    use crate::controllers::user_controller::{get_users, login};
+    use crate::models::captcha::{CaptchaConfig, CaptchaStore};
+    use crate::tools::auth_backend::{AuthBackends, LocalBackend};
+    use crate::tools::client::ClientConfig;
+    use crate::tools::jwt::{JwtKey, JwtKeyring, TokenConfig};
+    use crate::tools::login_throttle::LoginThrottleConfig;
+    use crate::tools::oidc::OidcProvider;
+    use crate::tools::password_hash::PasswordHasher;
+    use crate::tools::password_policy::PasswordPolicy;
+    use crate::tools::password_reset::ResetCodeConfig;
+    use crate::tools::permission_cache::PermissionCache;
+    use crate::tools::totp::TotpConfig;
+    use crate::tools::perm::Permission;
+    use crate::tools::route_policy::{self, Access, ProtectedRoutes};
+
+    #[get("/user/unlisted")]
+    fn unlisted() -> &'static str {
+        "unlisted"
+    }
+
+    #[test]
+    fn test_policy_is_found_below_the_mount_point() {
+        let routes = routes![get_users, login, unlisted];
+        assert_eq!(route_policy::access_for(&routes[0]), Some(Access::Permission(Permission::ViewUsers)));
+        assert_eq!(route_policy::access_for(&routes[1]), Some(Access::Public));
+        assert_eq!(route_policy::access_for(&routes[2]), None);
+    }
+
+    // same path as a listed route, without the managed state the real handlers need
+    #[get("/role")]
+    fn listed() -> &'static str {
+        "listed"
+    }
+
+    #[rocket::async_test]
+    async fn test_unlisted_route_stops_the_launch() {
+        let protected = ProtectedRoutes::default();
+        let rocket = rocket::build()
+            .attach(protected.fairing())
+            .mount("/api", protected.protect(routes![listed]));
+        assert!(rocket.ignite().await.is_ok());
+
+        let protected = ProtectedRoutes::default();
+        let rocket = rocket::build()
+            .attach(protected.fairing())
+            .mount("/api", protected.protect(routes![listed, unlisted]));
+        let error = rocket.ignite().await.expect_err("launch refused");
+        assert!(matches!(error.kind(), ErrorKind::FailedFairings(failed) if failed[0].name == "Route policy"));
+    }
+
+    #[get("/user/captcha")]
+    fn public() -> &'static str {
+        "public"
+    }
+
+    #[rocket::async_test]
+    async fn test_unwrapped_route_stops_the_launch() {
+        // listed, but mounted without the check in front of the handler
+        let protected = ProtectedRoutes::default();
+        let rocket = rocket::build()
+            .attach(protected.fairing())
+            .mount("/api", routes![listed]);
+        let error = rocket.ignite().await.expect_err("launch refused");
+        assert!(matches!(error.kind(), ErrorKind::FailedFairings(failed) if failed[0].name == "Route policy"));
+
+        // wrapped by another instance does not count
+        ProtectedRoutes::default().protect(routes![listed]);
+        let rocket = rocket::build()
+            .attach(protected.fairing())
+            .mount("/api", routes![listed]);
+        let error = rocket.ignite().await.expect_err("launch refused");
+        assert!(matches!(error.kind(), ErrorKind::FailedFairings(_)));
+
+        // public routes have nothing to check, and routes we did not wrap are not ours to list
+        let rocket = rocket::build()
+            .attach(protected.fairing())
+            .mount("/api", routes![public, unlisted]);
+        assert!(rocket.ignite().await.is_ok());
+    }
+
+    // the server as main.rs builds it, CORS catcher route included
+    #[rocket::async_test]
+    async fn test_the_app_launches() {
+        dotenv().ok();
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let keyring = JwtKeyring::new("test", vec![JwtKey::hmac("test", Algorithm::HS256, b"test-secret")]).unwrap();
+
+        let rocket = rocket::build()
+            .manage(PgPool::connect_lazy(&database_url).unwrap())
+            .manage(keyring)
+            .manage(TokenConfig::from_env())
+            .manage(LoginThrottleConfig::from_env())
+            .manage(ClientConfig::default())
+            .manage(TotpConfig::from_env())
+            .manage(PasswordPolicy::from_env())
+            .manage(PasswordHasher::new(1024, 1, 1).unwrap())
+            .manage(AuthBackends::new(vec![Box::new(LocalBackend)]))
+            .manage(OidcProvider::new(None))
+            .manage(ResetCodeConfig::from_env())
+            .manage(PermissionCache::new())
+            .manage(CaptchaStore::new(HashMap::new()))
+            .manage(CaptchaConfig::from_env());
+        if let Err(error) = crate::app(rocket).ignite().await {
+            panic!("launch refused: {:?}", error.kind());
+        }
+    }
+}
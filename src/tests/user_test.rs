@@ -1,17 +1,38 @@
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rocket::local::asynchronous::Client;
-    use rocket::http::{Status, ContentType};
+    use rocket::local::asynchronous::{Client, LocalResponse};
+    use rocket::http::{Status, ContentType, Header};
     use sqlx::{PgPool, Executor};
     use dotenv::dotenv;
     use std::collections::HashMap;
     use std::env;
     use rocket::serde::json::serde_json;
     use rocket::routes;
+    use jsonwebtoken::Algorithm;
     
-    use crate::controllers::user_controller::{login, register, generate_captcha_handler};
-    use crate::models::captcha::CaptchaStore;
+    use crate::controllers::user_controller::{login, logout, register, refresh, get_userinfo, generate_captcha_handler};
+    use crate::controllers::user_controller::{login_totp, login_password, change_own_password, soft_delete_user, reactivate_user, get_users};
+    use crate::controllers::user_controller::{assign_user_role, remove_user_role};
+    use crate::controllers::session_controller::{list_sessions, revoke_all_sessions};
+    use crate::controllers::totp_controller::{totp_setup, totp_confirm, totp_disable};
+    use crate::controllers::password_reset_controller::{issue_reset_code, redeem_reset_code};
+    use crate::controllers::metrics_controller::password_hashing_metrics;
+    use crate::controllers::permission_controller::{add_role_permissiom, delete_role_permission, permission_list, get_role_permission};
+    use crate::controllers::permission_controller::{create_role, rename_role, delete_role, set_role_parents};
+    use crate::controllers::permission_controller::{user_permission_list, set_user_permission, delete_user_permission};
+    use crate::controllers::worklist_controller::{worklist_setting, sync_worklist};
+    use crate::controllers::service_account_controller::{create_service_account, list_service_accounts, create_api_key, revoke_api_key};
+    use crate::models::captcha::{CaptchaStore, CaptchaConfig};
+    use crate::tools::jwt::{JwtKey, JwtKeyring, RoleVersion, TokenConfig};
+    use crate::tools::login_throttle::{self, LoginThrottleConfig};
+    use crate::tools::totp::{self, TotpConfig};
+    use crate::tools::password_policy::PasswordPolicy;
+    use crate::tools::password_reset::ResetCodeConfig;
+    use crate::tools::password_hash::PasswordHasher;
+    use crate::tools::auth_backend::{AuthBackends, LocalBackend};
+    use crate::tools::permission_cache::{self, PermissionCache};
+    use crate::tools::route_policy;
 
     async fn setup_test_db() -> PgPool {
         dotenv().ok();
@@ -21,18 +42,141 @@ mod tests {
         pool
     }
     
-    #[rocket::async_test]
-    async fn test_login() {
+    async fn setup_client() -> Client {
         let db = setup_test_db().await;
         let captcha_stroe = CaptchaStore::new(HashMap::new());
+        let keyring = JwtKeyring::new("test", vec![JwtKey::hmac("test", Algorithm::HS256, b"test-secret")]).unwrap();
 
         // 启动 Rocket 实例
+        let protected = route_policy::ProtectedRoutes::default();
         let rocket = rocket::build()
             .manage(db) // 管理数据库连接池
             .manage(captcha_stroe) // 管理验证码状态
-            .mount("/", routes![register, login, generate_captcha_handler]); // 挂载路由
+            .manage(CaptchaConfig::from_env())
+            .manage(keyring)
+            .manage(TokenConfig::from_env())
+            .manage(LoginThrottleConfig {
+                max_failures: 3,
+                max_ip_failures: 20,
+                lockout_seconds: 60,
+                max_lockout_seconds: 3600,
+                failure_window_seconds: 900,
+            })
+            .manage(TotpConfig::from_env())
+            .manage(PasswordPolicy { max_age_days: 90, ..PasswordPolicy::from_env() })
+            .manage(ResetCodeConfig::from_env())
+            // cheap parameters, the tests hash a lot
+            .manage(PasswordHasher::new(1024, 1, 1).unwrap())
+            .manage(AuthBackends::new(vec![Box::new(LocalBackend)]))
+            .manage(PermissionCache::new())
+            .attach(protected.fairing())
+            .mount("/", protected.protect(routes![register, login, login_totp, login_password, change_own_password, refresh, logout, get_userinfo, get_users, generate_captcha_handler, list_sessions, revoke_all_sessions, totp_setup, totp_confirm, totp_disable, issue_reset_code, redeem_reset_code, password_hashing_metrics, permission_list, get_role_permission, add_role_permissiom, delete_role_permission, create_role, rename_role, delete_role, set_role_parents, worklist_setting, sync_worklist, soft_delete_user, reactivate_user, assign_user_role, remove_user_role, user_permission_list, set_user_permission, delete_user_permission, create_service_account, list_service_accounts, create_api_key, revoke_api_key])); // 挂载路由
+
+        Client::tracked(rocket).await.expect("valid rocket instance")
+    }
+
+    // throwaway doctor account, so tests that sign out everywhere do not hit each other
+    async fn create_test_user(pool: &PgPool) -> String {
+        create_test_user_with_role(pool, 2).await
+    }
+
+    async fn create_test_user_with_role(pool: &PgPool, role_id: i32) -> String {
+        let username = format!("test_{}", uuid::Uuid::new_v4().simple());
+        let hashed = bcrypt::hash("Passw0rd1", 4).unwrap();
+        sqlx::query(
+            r#"
+                WITH u AS (INSERT INTO users (username, password, voice_attachment) VALUES ($1, $2, false) RETURNING id)
+                INSERT INTO user_roles (user_id, role_id) SELECT id, $3 FROM u
+            "#
+        )
+            .bind(&username)
+            .bind(hashed)
+            .bind(role_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        username
+    }
+
+    async fn delete_test_user(pool: &PgPool, username: &str) {
+        sqlx::query("DELETE FROM users WHERE username = $1")
+            .bind(username)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn set_deleted(pool: &PgPool, username: &str, deleted: bool) {
+        sqlx::query("UPDATE users SET deleted = $1 WHERE username = $2")
+            .bind(deleted)
+            .bind(username)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn login_admin(client: &Client) -> serde_json::Value {
+        login_as(client, "admin", "kenkone8282").await
+    }
+
+    // the answer is read back from the store, the image itself is not decoded
+    async fn solve_captcha(client: &Client) -> (String, String) {
+        let captcha_response = client.get("/user/captcha").dispatch().await;
+        let captcha_body = captcha_response.into_json::<serde_json::Value>().await.unwrap();
+        let captcha_id = captcha_body["captcha_id"].as_str().unwrap().to_string();
+
+        let store = client.rocket().state::<CaptchaStore>().unwrap();
+        let answer = store.lock().unwrap().get(&captcha_id).unwrap().captcha.clone();
+        (captcha_id, answer)
+    }
+
+    async fn attempt_login<'c>(client: &'c Client, username: &str, password: &str) -> LocalResponse<'c> {
+        let (captcha_id, captcha) = solve_captcha(client).await;
+
+        let login_data = serde_json::json!({
+            "username": username,
+            "password": password,
+            "captchaId": captcha_id,
+            "captcha": captcha
+        });
+
+        client.post("/user/login")
+            .header(ContentType::JSON)
+            .body(login_data.to_string())
+            .dispatch()
+            .await
+    }
+
+    async fn login_as(client: &Client, username: &str, password: &str) -> serde_json::Value {
+        let response = attempt_login(client, username, password).await;
+        assert_eq!(response.status(), Status::Ok);
+        response.into_json::<serde_json::Value>().await.unwrap()
+    }
+
+    fn current_totp_code(secret: &str, username: &str) -> String {
+        totp::build_totp(&TotpConfig::from_env(), secret, username).unwrap().generate_current().unwrap()
+    }
+
+    async fn bearer_post<'c>(client: &'c Client, uri: &'static str, bearer: &str, body: serde_json::Value) -> LocalResponse<'c> {
+        client.post(uri)
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", bearer)))
+            .body(body.to_string())
+            .dispatch()
+            .await
+    }
+
+    async fn refresh_with<'c>(client: &'c Client, token: &str) -> LocalResponse<'c> {
+        client.post("/user/refresh")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({ "refreshToken": token }).to_string())
+            .dispatch()
+            .await
+    }
 
-        let client = Client::tracked(rocket).await.expect("valid rocket instance");
+    #[rocket::async_test]
+    async fn test_login() {
+        let client = setup_client().await;
 
         // 生成验证码
         let captcha_response = client.get("/user/captcha")
@@ -41,7 +185,9 @@ mod tests {
         assert_eq!(captcha_response.status(), Status::Ok);
         let captcha_body = captcha_response.into_json::<serde_json::Value>().await.unwrap();
         let captcha_id = captcha_body.get("captcha_id").unwrap().as_str().unwrap();
-        let captcha_value = captcha_body.get("captcha_image").unwrap().as_str().unwrap();
+        assert!(captcha_body.get("captcha_image").is_some());
+        let store = client.rocket().state::<CaptchaStore>().unwrap();
+        let captcha_value = store.lock().unwrap().get(captcha_id).unwrap().captcha.clone();
 
         // 创建登录请求
         let login_data = serde_json::json!({
@@ -65,4 +211,1215 @@ mod tests {
         let body = response.into_json::<serde_json::Value>().await.unwrap();
         assert!(body.get("token").is_some());
     }
+
+    #[rocket::async_test]
+    async fn test_refresh_token_rotation() {
+        let client = setup_client().await;
+        let body = login_admin(&client).await;
+        let first = body["refresh_token"].as_str().unwrap().to_string();
+
+        let response = refresh_with(&client, &first).await;
+        assert_eq!(response.status(), Status::Ok);
+        let rotated = response.into_json::<serde_json::Value>().await.unwrap();
+        let second = rotated["refresh_token"].as_str().unwrap().to_string();
+        assert_ne!(first, second);
+        assert!(rotated.get("token").is_some());
+
+        // reusing the first token revokes the family, including the rotated one
+        assert_eq!(refresh_with(&client, &first).await.status(), Status::Unauthorized);
+        assert_eq!(refresh_with(&client, &second).await.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_logout_revokes_token() {
+        let client = setup_client().await;
+        let body = login_admin(&client).await;
+        let bearer = format!("Bearer {}", body["token"].as_str().unwrap());
+
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", bearer.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Ok);
+
+        let logout = client.post("/user/logout")
+            .header(Header::new("Authorization", bearer.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(logout.status(), Status::Ok);
+
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", bearer))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn test_sign_out_everywhere() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap().clone();
+        let username = create_test_user(&pool).await;
+        let first = login_as(&client, &username, "Passw0rd1").await;
+        let second = login_as(&client, &username, "Passw0rd1").await;
+        let first_bearer = format!("Bearer {}", first["token"].as_str().unwrap());
+        let second_bearer = format!("Bearer {}", second["token"].as_str().unwrap());
+
+        let sessions = client.get("/user/sessions")
+            .header(Header::new("Authorization", first_bearer.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(sessions.status(), Status::Ok);
+        let sessions = sessions.into_json::<serde_json::Value>().await.unwrap();
+        let data = sessions["data"].as_array().unwrap();
+        assert_eq!(data.len(), 2);
+        assert_eq!(data.iter().filter(|s| s["current"] == true).count(), 1);
+
+        let revoke = client.post("/user/sessions/revokeAll")
+            .header(Header::new("Authorization", second_bearer.clone()))
+            .dispatch()
+            .await;
+        assert_eq!(revoke.status(), Status::Ok);
+
+        for bearer in [first_bearer, second_bearer] {
+            let userinfo = client.get("/user/userinfo")
+                .header(Header::new("Authorization", bearer))
+                .dispatch()
+                .await;
+            assert_eq!(userinfo.status(), Status::Unauthorized);
+        }
+        let refresh = refresh_with(&client, first["refresh_token"].as_str().unwrap()).await;
+        assert_eq!(refresh.status(), Status::Unauthorized);
+
+        delete_test_user(&pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_captcha_counts_username_and_address_failures() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let config = client.rocket().state::<LoginThrottleConfig>().unwrap();
+        let suffix = uuid::Uuid::new_v4().simple().to_string();
+        let keys = vec![login_throttle::user_key(&format!("test_{}", suffix)), login_throttle::ip_key(&format!("test-{}", suffix))];
+
+        // two failures for the username, one for the address: the higher count decides, in any order
+        for key in &keys {
+            login_throttle::record_failure(pool, config, key, i32::MAX).await.unwrap();
+        }
+        login_throttle::record_failure(pool, config, &keys[0], i32::MAX).await.unwrap();
+        assert_eq!(login_throttle::most_recent_failures(pool, config, &keys).await.unwrap(), 2);
+        let reversed: Vec<String> = keys.iter().rev().cloned().collect();
+        assert_eq!(login_throttle::most_recent_failures(pool, config, &reversed).await.unwrap(), 2);
+        assert_eq!(login_throttle::most_recent_failures(pool, config, &keys[1..]).await.unwrap(), 1);
+
+        for key in &keys {
+            login_throttle::reset(pool, key).await.unwrap();
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_lockout_after_failed_logins() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap().clone();
+        let username = create_test_user(&pool).await;
+        let missing = format!("missing_{}", uuid::Uuid::new_v4().simple());
+
+        for _ in 0..3 {
+            assert_eq!(attempt_login(&client, &username, "wrong-password").await.status(), Status::Unauthorized);
+            assert_eq!(attempt_login(&client, &missing, "wrong-password").await.status(), Status::Unauthorized);
+        }
+
+        // locked, even with the right password; unknown usernames behave the same
+        assert_eq!(attempt_login(&client, &username, "Passw0rd1").await.status(), Status::TooManyRequests);
+        assert_eq!(attempt_login(&client, &missing, "wrong-password").await.status(), Status::TooManyRequests);
+
+        login_throttle::reset(&pool, &login_throttle::user_key(&username)).await.unwrap();
+        login_throttle::reset(&pool, &login_throttle::user_key(&missing)).await.unwrap();
+        login_as(&client, &username, "Passw0rd1").await;
+
+        delete_test_user(&pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_login_requires_valid_captcha() {
+        let client = setup_client().await;
+        let (captcha_id, answer) = solve_captcha(&client).await;
+
+        let wrong = serde_json::json!({
+            "username": "admin",
+            "password": "kenkone8282",
+            "captchaId": captcha_id,
+            "captcha": format!("{}x", answer)
+        });
+        let response = client.post("/user/login")
+            .header(ContentType::JSON)
+            .body(wrong.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // a captcha can only be used once, even after a wrong guess
+        let reused = serde_json::json!({
+            "username": "admin",
+            "password": "kenkone8282",
+            "captchaId": captcha_id,
+            "captcha": answer
+        });
+        let response = client.post("/user/login")
+            .header(ContentType::JSON)
+            .body(reused.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let missing = serde_json::json!({ "username": "admin", "password": "kenkone8282" });
+        let response = client.post("/user/login")
+            .header(ContentType::JSON)
+            .body(missing.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::PreconditionRequired);
+    }
+
+    #[rocket::async_test]
+    async fn test_totp_enrollment_and_second_factor_login() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        let token = body["token"].as_str().unwrap().to_string();
+
+        let setup = bearer_post(&client, "/user/totp/setup", &token, serde_json::json!({})).await;
+        assert_eq!(setup.status(), Status::Ok);
+        let setup = setup.into_json::<serde_json::Value>().await.unwrap();
+        let secret = setup["secret"].as_str().unwrap().to_string();
+        assert!(setup["otpauth_url"].as_str().unwrap().starts_with("otpauth://totp/"));
+
+        let code = current_totp_code(&secret, &username);
+        let confirm = bearer_post(&client, "/user/totp/confirm", &token, serde_json::json!({ "code": code })).await;
+        assert_eq!(confirm.status(), Status::Ok);
+        let confirm = confirm.into_json::<serde_json::Value>().await.unwrap();
+        let recovery_codes: Vec<String> = confirm["recovery_codes"].as_array().unwrap()
+            .iter().map(|c| c.as_str().unwrap().to_string()).collect();
+        assert_eq!(recovery_codes.len(), 10);
+        assert!(confirm["token"].is_null());
+
+        // the password alone no longer signs in
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        assert_eq!(body["status"], "mfa_required");
+        assert!(body["token"].is_null());
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", challenge)))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Unauthorized);
+
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "code": wrong })).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "recoveryCode": recovery_codes[0].to_uppercase() })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["status"], "success");
+        assert!(body["token"].is_string());
+
+        // challenges and recovery codes are single use
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "recoveryCode": recovery_codes[1] })).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "recoveryCode": recovery_codes[0] })).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        // disabled after the password step: refused, and the recovery code is not spent
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+        set_deleted(pool, &username, true).await;
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "recoveryCode": recovery_codes[2] })).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        set_deleted(pool, &username, false).await;
+
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/user/login/totp", "", serde_json::json!({ "challengeToken": challenge, "recoveryCode": recovery_codes[2] })).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_totp_disable_counts_wrong_codes() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+
+        let setup = bearer_post(&client, "/user/totp/setup", &token, serde_json::json!({})).await;
+        let secret = setup.into_json::<serde_json::Value>().await.unwrap()["secret"].as_str().unwrap().to_string();
+        let code = current_totp_code(&secret, &username);
+        let confirm = bearer_post(&client, "/user/totp/confirm", &token, serde_json::json!({ "code": code })).await;
+        assert_eq!(confirm.status(), Status::Ok);
+
+        // a stolen access token cannot guess its way past the second factor
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        for _ in 0..3 {
+            let response = bearer_post(&client, "/user/totp/disable", &token, serde_json::json!({ "code": wrong })).await;
+            assert_eq!(response.status(), Status::BadRequest);
+        }
+        let response = bearer_post(&client, "/user/totp/disable", &token, serde_json::json!({ "code": current_totp_code(&secret, &username) })).await;
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        login_throttle::reset(pool, &login_throttle::user_key(&username)).await.unwrap();
+        // the confirm step may have used up the current time step
+        sqlx::query("UPDATE user_totp SET last_used_step = NULL WHERE user_id = (SELECT id FROM users WHERE username = $1)")
+            .bind(&username)
+            .execute(pool)
+            .await
+            .unwrap();
+        let response = bearer_post(&client, "/user/totp/disable", &token, serde_json::json!({ "code": current_totp_code(&secret, &username) })).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_role_policy_forces_totp_enrollment() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+
+        let role_name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let role_id: i32 = sqlx::query_scalar("INSERT INTO roles (role_name, require_mfa) VALUES ($1, true) RETURNING id")
+            .bind(&role_name)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let username = create_test_user_with_role(pool, role_id).await;
+
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        assert_eq!(body["status"], "mfa_enrollment_required");
+        assert!(body["token"].is_null());
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+
+        let setup = bearer_post(&client, "/user/totp/setup", &challenge, serde_json::json!({})).await;
+        assert_eq!(setup.status(), Status::Ok);
+        let secret = setup.into_json::<serde_json::Value>().await.unwrap()["secret"].as_str().unwrap().to_string();
+
+        let code = current_totp_code(&secret, &username);
+        let confirm = bearer_post(&client, "/user/totp/confirm", &challenge, serde_json::json!({ "code": code })).await;
+        assert_eq!(confirm.status(), Status::Ok);
+        let confirm = confirm.into_json::<serde_json::Value>().await.unwrap();
+        let token = confirm["token"].as_str().unwrap().to_string();
+
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Ok);
+
+        // the enrollment challenge is spent
+        let setup = bearer_post(&client, "/user/totp/setup", &challenge, serde_json::json!({})).await;
+        assert_eq!(setup.status(), Status::Unauthorized);
+
+        delete_test_user(pool, &username).await;
+        sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role_id).execute(pool).await.unwrap();
+        sqlx::query("DELETE FROM roles WHERE id = $1").bind(role_id).execute(pool).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_cookie_auth_requires_csrf_header() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+
+        // the tracked client keeps the login cookies, no Authorization header from here on
+        login_as(&client, &username, "Passw0rd1").await;
+
+        let userinfo = client.get("/user/userinfo").dispatch().await;
+        assert_eq!(userinfo.status(), Status::Ok);
+        let body = userinfo.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["username"], username.as_str());
+
+        let response = client.post("/user/sessions/revokeAll").dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let response = client.post("/user/sessions/revokeAll")
+            .header(Header::new("X-CSRF-Token", "forged"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let csrf = client.cookies().get("csrf_token").unwrap().value().to_string();
+        let response = client.post("/user/sessions/revokeAll")
+            .header(Header::new("X-CSRF-Token", csrf))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let userinfo = client.get("/user/userinfo").dispatch().await;
+        assert_eq!(userinfo.status(), Status::Unauthorized);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_expired_password_must_be_changed() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+
+        sqlx::query("UPDATE users SET password_changed_at = CURRENT_TIMESTAMP - INTERVAL '100 days' WHERE username = $1")
+            .bind(&username)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        let body = login_as(&client, &username, "Passw0rd1").await;
+        assert_eq!(body["status"], "password_expired");
+        assert!(body["token"].is_null());
+        let challenge = body["challenge_token"].as_str().unwrap().to_string();
+
+        // the reason is reported, and the current password does not count as a new one
+        for (password, reason) in [("short1", "at least 8 characters"), ("Passw0rd1", "differ from the last")] {
+            let response = client.post("/user/login/password")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "challengeToken": challenge, "newPassword": password }).to_string())
+                .dispatch()
+                .await;
+            assert_eq!(response.status(), Status::BadRequest);
+            let body = response.into_json::<serde_json::Value>().await.unwrap();
+            assert!(body["message"].as_str().unwrap().contains(reason), "{}", body["message"]);
+        }
+
+        let change_password = || client.post("/user/login/password")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({ "challengeToken": challenge, "newPassword": "N3w-Passw0rd!" }).to_string())
+            .dispatch();
+
+        // disabled after the password step
+        set_deleted(pool, &username, true).await;
+        assert_eq!(change_password().await.status(), Status::Unauthorized);
+        set_deleted(pool, &username, false).await;
+
+        let response = change_password().await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["status"], "success");
+
+        let body = login_as(&client, &username, "N3w-Passw0rd!").await;
+        assert_eq!(body["status"], "success");
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_change_own_password() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+
+        let other = login_as(&client, &username, "Passw0rd1").await;
+        let current_login = login_as(&client, &username, "Passw0rd1").await;
+        let current = current_login["token"].as_str().unwrap().to_string();
+
+        let change = |old: &str, new: &str| serde_json::json!({ "oldPassword": old, "newPassword": new });
+
+        let response = bearer_post(&client, "/user/me/password", &current, change("wrong-one1", "N3w-Passw0rd!")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = bearer_post(&client, "/user/me/password", &current, change("Passw0rd1", "password1")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["message"], "password is too common");
+
+        let response = bearer_post(&client, "/user/me/password", &current, change("Passw0rd1", "N3w-Passw0rd!")).await;
+        assert_eq!(response.status(), Status::Ok);
+        let reissued = response.into_json::<serde_json::Value>().await.unwrap()["token"].as_str().unwrap().to_string();
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", reissued)))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Ok);
+
+        // access tokens from before the change are refused; the other session is signed out,
+        // this one is kept and can also get a new token on refresh
+        for token in [other["token"].as_str().unwrap(), &current] {
+            let userinfo = client.get("/user/userinfo")
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .dispatch()
+                .await;
+            assert_eq!(userinfo.status(), Status::Unauthorized);
+        }
+        assert_eq!(refresh_with(&client, other["refresh_token"].as_str().unwrap()).await.status(), Status::Unauthorized);
+        let refreshed = refresh_with(&client, current_login["refresh_token"].as_str().unwrap()).await;
+        assert_eq!(refreshed.status(), Status::Ok);
+        let refreshed = refreshed.into_json::<serde_json::Value>().await.unwrap()["token"].as_str().unwrap().to_string();
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", refreshed)))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Ok);
+
+        assert_eq!(attempt_login(&client, &username, "Passw0rd1").await.status(), Status::Unauthorized);
+        assert_eq!(login_as(&client, &username, "N3w-Passw0rd!").await["status"], "success");
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_admin_issued_reset_code() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+        let user_id: uuid::Uuid = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(&username)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+
+        let user_token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/user/resetCode", &user_token, serde_json::json!({ "Uid": user_id.to_string() })).await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/user/resetCode", &admin_token, serde_json::json!({ "Uid": user_id.to_string() })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let code = response.into_json::<serde_json::Value>().await.unwrap()["code"].as_str().unwrap().to_string();
+
+        let redeem = |code: &str, password: &str| serde_json::json!({ "username": username, "code": code, "newPassword": password });
+
+        let response = bearer_post(&client, "/user/resetPassword", "", redeem("AAAA-BBBB-CCCC", "N3w-Passw0rd!")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // a rejected password does not use up the code
+        let response = bearer_post(&client, "/user/resetPassword", "", redeem(&code, "short")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = bearer_post(&client, "/user/resetPassword", "", redeem(&code.to_lowercase(), "N3w-Passw0rd!")).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = bearer_post(&client, "/user/resetPassword", "", redeem(&code, "0ther-Passw0rd!")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // sessions from before the reset are gone
+        let userinfo = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", user_token)))
+            .dispatch()
+            .await;
+        assert_eq!(userinfo.status(), Status::Unauthorized);
+        assert_eq!(login_as(&client, &username, "N3w-Passw0rd!").await["status"], "success");
+
+        let actions: Vec<String> = sqlx::query_scalar("SELECT action FROM audit_log WHERE target = $1 ORDER BY id")
+            .bind(&username)
+            .fetch_all(pool)
+            .await
+            .unwrap();
+        assert_eq!(actions, vec!["password_reset_code_issued", "password_reset_code_rejected", "password_reset_code_redeemed", "password_reset_code_rejected"]);
+
+        sqlx::query("DELETE FROM audit_log WHERE target = $1").bind(&username).execute(pool).await.unwrap();
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_login_upgrades_bcrypt_hash() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        // created with a bcrypt hash, like the accounts from before Argon2
+        let username = create_test_user(pool).await;
+
+        let stored_hash = || async {
+            sqlx::query_scalar::<_, String>("SELECT password FROM users WHERE username = $1")
+                .bind(&username)
+                .fetch_one(pool)
+                .await
+                .unwrap()
+        };
+        assert!(stored_hash().await.starts_with("$2b$"));
+
+        login_as(&client, &username, "Passw0rd1").await;
+        assert!(stored_hash().await.starts_with("$argon2id$"));
+
+        assert_eq!(login_as(&client, &username, "Passw0rd1").await["status"], "success");
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_password_hashing_metrics() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+
+        let admin = login_admin(&client).await;
+        let response = client.get("/metrics/passwordHashing")
+            .header(Header::new("Authorization", format!("Bearer {}", admin["token"].as_str().unwrap())))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        // at least the admin login went through the queue
+        assert!(body["metrics"]["completed"].as_u64().unwrap() >= 1);
+        assert_eq!(body["metrics"]["rejected"], 0);
+
+        let username = create_test_user(pool).await;
+        let user = login_as(&client, &username, "Passw0rd1").await;
+        let response = client.get("/metrics/passwordHashing")
+            .header(Header::new("Authorization", format!("Bearer {}", user["token"].as_str().unwrap())))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_directory_user_cannot_use_local_password() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+
+        // a row created by the ldap backend, with a hash that would otherwise match
+        sqlx::query("UPDATE users SET auth_source = 'ldap', external_id = $1 WHERE username = $1")
+            .bind(&username)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        assert_eq!(attempt_login(&client, &username, "Passw0rd1").await.status(), Status::Unauthorized);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_roles_of_external_users_cannot_be_edited() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        // their roles are replaced at the next login, an edit here would be lost
+        for source in ["ldap", "oidc"] {
+            let username = create_test_user(pool).await;
+            let user_id: uuid::Uuid = sqlx::query_scalar("UPDATE users SET auth_source = $1 WHERE username = $2 RETURNING id")
+                .bind(source)
+                .bind(&username)
+                .fetch_one(pool)
+                .await
+                .unwrap();
+
+            let change = serde_json::json!({ "Uid": user_id.to_string(), "roleId": 1 });
+            let response = bearer_post(&client, "/user/role/assign", &admin_token, change).await;
+            assert_eq!(response.status(), Status::Conflict);
+
+            delete_test_user(pool, &username).await;
+        }
+    }
+
+    async fn metrics_with_key(client: &Client, key: &str) -> Status {
+        client.get("/metrics/passwordHashing")
+            .header(Header::new("X-API-Key", key.to_string()))
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn test_service_account_api_keys() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin = login_admin(&client).await;
+        let bearer = admin["token"].as_str().unwrap();
+
+        let name = format!("station_{}", uuid::Uuid::new_v4().simple());
+        let response = bearer_post(&client, "/serviceAccount", bearer, serde_json::json!({ "name": name })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let account_id = response.into_json::<serde_json::Value>().await.unwrap()["id"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/serviceAccount", bearer, serde_json::json!({ "name": name })).await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        // a key scoped to viewMetrics works on the permission guarded route, with either header
+        let response = bearer_post(&client, "/serviceAccount/key", bearer, serde_json::json!({
+            "serviceAccountId": account_id, "permissions": ["viewMetrics"]
+        })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        let key = body["key"].as_str().unwrap().to_string();
+        let key_id = body["id"].as_str().unwrap().to_string();
+        assert!(key.starts_with("rwk_"));
+        assert_eq!(metrics_with_key(&client, &key).await, Status::Ok);
+        let response = client.get("/metrics/passwordHashing")
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(metrics_with_key(&client, &format!("{}x", key)).await, Status::Unauthorized);
+
+        let response = client.get("/serviceAccount")
+            .header(Header::new("Authorization", format!("Bearer {}", bearer)))
+            .dispatch()
+            .await;
+        let accounts = response.into_json::<serde_json::Value>().await.unwrap();
+        let account = accounts["data"].as_array().unwrap().iter().find(|a| a["name"] == name.as_str()).unwrap().clone();
+        assert_eq!(account["keys"][0]["permissions"], serde_json::json!(["viewMetrics"]));
+        assert!(account["keys"][0]["last_used_at"].is_string());
+
+        // a key without the permission gets 403, unknown and unheld permissions are refused
+        let response = bearer_post(&client, "/serviceAccount/key", bearer, serde_json::json!({
+            "serviceAccountId": account_id, "permissions": ["manageServiceAccounts"], "expiresInDays": 30
+        })).await;
+        let other_key = response.into_json::<serde_json::Value>().await.unwrap()["key"].as_str().unwrap().to_string();
+        assert_eq!(metrics_with_key(&client, &other_key).await, Status::Forbidden);
+        let response = bearer_post(&client, "/serviceAccount/key", bearer, serde_json::json!({
+            "serviceAccountId": account_id, "permissions": ["noSuchPermission"]
+        })).await;
+        assert_eq!(response.status(), Status::Forbidden);
+
+        // expired and revoked keys stop working
+        sqlx::query("UPDATE api_keys SET expires_at = CURRENT_TIMESTAMP - INTERVAL '1 minute' WHERE service_account_id = $1 AND id <> $2::uuid")
+            .bind(uuid::Uuid::parse_str(&account_id).unwrap())
+            .bind(&key_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        assert_eq!(metrics_with_key(&client, &other_key).await, Status::Unauthorized);
+
+        let response = bearer_post(&client, "/serviceAccount/key/revoke", bearer, serde_json::json!({ "keyId": key_id })).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(metrics_with_key(&client, &key).await, Status::Unauthorized);
+
+        sqlx::query("DELETE FROM service_accounts WHERE name = $1").bind(&name).execute(pool).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_deleted_user_cannot_authenticate() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+        let user_id: uuid::Uuid = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(&username)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+
+        let user = login_as(&client, &username, "Passw0rd1").await;
+        let admin = login_admin(&client).await;
+        let admin_bearer = admin["token"].as_str().unwrap();
+        let response = bearer_post(&client, "/user/softDeleted", admin_bearer, serde_json::json!({ "Uid": user_id.to_string() })).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // the access token, the refresh token and the password all stop working
+        let response = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", user["token"].as_str().unwrap())))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert_eq!(refresh_with(&client, user["refresh_token"].as_str().unwrap()).await.status(), Status::Unauthorized);
+        assert_eq!(attempt_login(&client, &username, "Passw0rd1").await.status(), Status::Unauthorized);
+
+        // reactivation has its own permission
+        let response = bearer_post(&client, "/user/reactivate", admin_bearer, serde_json::json!({ "Uid": user_id.to_string() })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = bearer_post(&client, "/user/reactivate", admin_bearer, serde_json::json!({ "Uid": user_id.to_string() })).await;
+        assert_eq!(response.status(), Status::NotFound);
+        login_as(&client, &username, "Passw0rd1").await;
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_permission_cache_follows_other_instances() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let role_name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let role = sqlx::query_as::<_, (i32, i32)>("INSERT INTO roles (role_name) VALUES ($1) RETURNING id, permissions_version")
+            .bind(&role_name)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO role_permissions (role_id, permissions_id) SELECT $1, id FROM permissions WHERE permissions_name = 'viewMetrics'")
+            .bind(role.0)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        // a token issued before the change below, still valid
+        let token_roles = [RoleVersion { id: role.0, ver: role.1 }];
+        let cache = PermissionCache::new();
+        let loaded = cache.roles(pool, &token_roles).await.unwrap();
+        assert!(loaded[0].permissions.contains("viewMetrics"));
+
+        // another instance takes the permission away, this cache hears nothing of it
+        sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role.0).execute(pool).await.unwrap();
+        permission_cache::bump_role_versions(pool, role.0).await.unwrap();
+        let loaded = cache.roles(pool, &token_roles).await.unwrap();
+        assert!(loaded[0].permissions.is_empty());
+
+        sqlx::query("DELETE FROM roles WHERE id = $1").bind(role.0).execute(pool).await.unwrap();
+        assert!(cache.roles(pool, &token_roles).await.unwrap().is_empty());
+    }
+
+    #[rocket::async_test]
+    async fn test_role_permission_changes_apply_immediately() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+
+        // a role of its own, the shared ones are used by tests running at the same time
+        let role_name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let role_id: i32 = sqlx::query_scalar("INSERT INTO roles (role_name) VALUES ($1) RETURNING id")
+            .bind(&role_name)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let username = create_test_user_with_role(pool, role_id).await;
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let metrics = |token: String| {
+            let client = &client;
+            async move {
+                client.get("/metrics/passwordHashing")
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .dispatch()
+                    .await
+                    .status()
+            }
+        };
+        let change = serde_json::json!({ "roleId": role_id, "permissionName": "viewMetrics" });
+
+        assert_eq!(metrics(token.clone()).await, Status::Forbidden);
+        let response = bearer_post(&client, "/permission/addRolePermission", &admin_token, change.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(metrics(token.clone()).await, Status::Ok);
+        let response = bearer_post(&client, "/permission/deleteRolePermission", &admin_token, change).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(metrics(token.clone()).await, Status::Forbidden);
+
+        // changed by another instance: tokens issued after the change carry the newer version
+        sqlx::query(
+            "INSERT INTO role_permissions (role_id, permissions_id) SELECT $1, id FROM permissions WHERE permissions_name = 'viewMetrics'"
+        )
+        .bind(role_id)
+        .execute(pool)
+        .await
+        .unwrap();
+        sqlx::query("UPDATE roles SET permissions_version = permissions_version + 1 WHERE id = $1")
+            .bind(role_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        assert_eq!(metrics(token).await, Status::Ok);
+
+        delete_test_user(pool, &username).await;
+        sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role_id).execute(pool).await.unwrap();
+        sqlx::query("DELETE FROM roles WHERE id = $1").bind(role_id).execute(pool).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_missing_permission_is_forbidden() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+
+        // signed in but without newUser: 403, not 401
+        let new_user = serde_json::json!({ "username": format!("{}_new", username), "password": "Passw0rd1", "voice_attachment": false, "role_id": "2" });
+        let response = bearer_post(&client, "/user/register", &token, new_user.clone()).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = bearer_post(&client, "/user/register", "not-a-token", new_user).await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_register_refuses_taken_username() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let username = create_test_user(pool).await;
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        // names are unique regardless of case
+        let new_user = serde_json::json!({ "username": username.to_uppercase(), "password": "Passw0rd1", "voice_attachment": false, "role_id": "2" });
+        let response = bearer_post(&client, "/user/register", &admin_token, new_user).await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        delete_test_user(pool, &username).await;
+    }
+
+    #[rocket::async_test]
+    async fn test_admin_routes_need_their_permission() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        // a role without any permission
+        let role_name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let role_id: i32 = sqlx::query_scalar("INSERT INTO roles (role_name) VALUES ($1) RETURNING id")
+            .bind(&role_name)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let username = create_test_user_with_role(pool, role_id).await;
+
+        let get = |uri: &'static str, token: Option<String>| {
+            let client = &client;
+            async move {
+                let mut request = client.get(uri);
+                if let Some(token) = token {
+                    request = request.header(Header::new("Authorization", format!("Bearer {}", token)));
+                }
+                request.dispatch().await.status()
+            }
+        };
+        let uris = ["/user", "/permissions", "/permission/userRolePermission"];
+        // before any login, the client keeps the cookie login sets
+        for uri in uris {
+            assert_eq!(get(uri, None).await, Status::Unauthorized, "{}", uri);
+        }
+
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+        for uri in uris {
+            assert_eq!(get(uri, Some(token.clone())).await, Status::Forbidden, "{}", uri);
+            assert_eq!(get(uri, Some(admin_token.clone())).await, Status::Ok, "{}", uri);
+        }
+
+        let change = serde_json::json!({ "roleId": role_id, "permissionName": "viewMetrics" });
+        let worklist = serde_json::json!({ "port": "104", "calling_ae_title": "TEST", "called_ae_title": "TEST" });
+        for (uri, body) in [
+            ("/permission/addRolePermission", change.clone()),
+            ("/permission/deleteRolePermission", change),
+            ("/worklist_setting", worklist),
+            ("/sync_worklist", serde_json::json!({})),
+        ] {
+            assert_eq!(bearer_post(&client, uri, "not-a-token", body.clone()).await.status(), Status::Unauthorized, "{}", uri);
+            assert_eq!(bearer_post(&client, uri, &token, body).await.status(), Status::Forbidden, "{}", uri);
+        }
+        let granted: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM role_permissions WHERE role_id = $1")
+            .bind(role_id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(granted, 0);
+
+        delete_test_user(pool, &username).await;
+        sqlx::query("DELETE FROM roles WHERE id = $1").bind(role_id).execute(pool).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn test_role_create_rename_delete() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": name })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let role_id = response.into_json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+        let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": name.to_uppercase() })).await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        let other = format!("{}_other", name);
+        let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": other })).await;
+        let other_id = response.into_json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+        let response = bearer_post(&client, "/role/rename", &admin_token, serde_json::json!({ "roleId": other_id, "roleName": name })).await;
+        assert_eq!(response.status(), Status::Conflict);
+        let renamed = format!("{}_renamed", name);
+        let response = bearer_post(&client, "/role/rename", &admin_token, serde_json::json!({ "roleId": role_id, "roleName": renamed })).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // a member of the role blocks the delete and is listed
+        let username = create_test_user_with_role(pool, role_id as i32).await;
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        let response = bearer_post(&client, "/role/delete", &token, serde_json::json!({ "roleId": role_id })).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id })).await;
+        assert_eq!(response.status(), Status::Conflict);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(body["users"][0]["username"], username.as_str());
+
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id, "reassignTo": role_id })).await;
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id, "reassignTo": other_id })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let (moved_to, roles_left): (i32, i64) = sqlx::query_as(
+            "SELECT ur.role_id, (SELECT COUNT(*) FROM roles WHERE id = $2) FROM users u JOIN user_roles ur ON ur.user_id = u.id WHERE u.username = $1"
+        )
+        .bind(&username)
+        .bind(role_id as i32)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        assert_eq!((moved_to, roles_left), (other_id as i32, 0));
+
+        // the move retired the user's token
+        let response = client.get("/user/userinfo")
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        delete_test_user(pool, &username).await;
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": other_id })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": other_id })).await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[rocket::async_test]
+    async fn test_permission_list_has_descriptions() {
+        let client = setup_client().await;
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let response = client.get("/permissions")
+            .header(Header::new("Authorization", format!("Bearer {}", admin_token)))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        let data = body["data"].as_array().unwrap();
+        assert_eq!(data[0]["permissions_name"], "viewUsers");
+        assert_eq!(data[0]["category"], "users");
+        let examinations = data.iter().find(|p| p["permissions_name"] == "examinations").unwrap();
+        assert_eq!(examinations["description"], "Open the examination list");
+        assert!(data.iter().all(|p| p["permissions_name"] != "examinatios"));
+    }
+
+    #[rocket::async_test]
+    async fn test_roles_inherit_parent_permissions() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for suffix in ["grandparent", "parent", "child"] {
+            let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": format!("{}_{}", name, suffix) })).await;
+            ids.push(response.into_json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap());
+        }
+        let (grandparent, parent, child) = (ids[0], ids[1], ids[2]);
+        let set_parents = |role_id: i64, parent_ids: Vec<i64>| {
+            let (client, admin_token) = (&client, admin_token.clone());
+            async move {
+                bearer_post(client, "/role/parents", &admin_token, serde_json::json!({ "roleId": role_id, "parentIds": parent_ids })).await.status()
+            }
+        };
+        assert_eq!(set_parents(parent, vec![grandparent]).await, Status::Ok);
+        assert_eq!(set_parents(child, vec![parent]).await, Status::Ok);
+        assert_eq!(set_parents(grandparent, vec![child]).await, Status::Conflict);
+        assert_eq!(set_parents(child, vec![child]).await, Status::Conflict);
+        assert_eq!(set_parents(child, vec![-1]).await, Status::BadRequest);
+
+        let username = create_test_user_with_role(pool, child as i32).await;
+        let token = login_as(&client, &username, "Passw0rd1").await["token"].as_str().unwrap().to_string();
+        let metrics = |token: String| {
+            let client = &client;
+            async move {
+                client.get("/metrics/passwordHashing")
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .dispatch()
+                    .await
+                    .status()
+            }
+        };
+        let change = serde_json::json!({ "roleId": grandparent, "permissionName": "viewMetrics" });
+
+        assert_eq!(metrics(token.clone()).await, Status::Forbidden);
+        let response = bearer_post(&client, "/permission/addRolePermission", &admin_token, change.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(metrics(token.clone()).await, Status::Ok);
+
+        let response = client.get("/permission/userRolePermission")
+            .header(Header::new("Authorization", format!("Bearer {}", admin_token)))
+            .dispatch()
+            .await;
+        let body = response.into_json::<serde_json::Value>().await.unwrap();
+        let role = body["data"].as_array().unwrap().iter().find(|role| role["id"] == child).unwrap();
+        assert_eq!(role["parents"], serde_json::json!([parent]));
+        assert_eq!(role["permissions"], serde_json::json!([]));
+        assert_eq!(role["inherited"][0]["name"], "viewMetrics");
+        assert_eq!(role["inherited"][0]["from_role_id"], grandparent);
+
+        // dropping the link takes the permission away at once
+        assert_eq!(set_parents(parent, vec![]).await, Status::Ok);
+        assert_eq!(metrics(token.clone()).await, Status::Forbidden);
+
+        delete_test_user(pool, &username).await;
+        for role_id in [child, parent, grandparent] {
+            sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role_id as i32).execute(pool).await.unwrap();
+            let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id })).await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_user_holds_several_roles() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let mut ids = Vec::new();
+        for (suffix, permission) in [("metrics", "viewMetrics"), ("users", "viewUsers")] {
+            let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": format!("{}_{}", name, suffix) })).await;
+            let role_id = response.into_json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+            let change = serde_json::json!({ "roleId": role_id, "permissionName": permission });
+            assert_eq!(bearer_post(&client, "/permission/addRolePermission", &admin_token, change).await.status(), Status::Ok);
+            ids.push(role_id);
+        }
+        let (metrics_role, users_role) = (ids[0], ids[1]);
+
+        let username = create_test_user_with_role(pool, metrics_role as i32).await;
+        let user_id: uuid::Uuid = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(&username)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let login = login_as(&client, &username, "Passw0rd1").await;
+        let token = login["token"].as_str().unwrap().to_string();
+        let get = |uri: &'static str, token: String| {
+            let client = &client;
+            async move {
+                client.get(uri)
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .dispatch()
+                    .await
+            }
+        };
+        assert_eq!(get("/user", token.clone()).await.status(), Status::Forbidden);
+
+        let change = serde_json::json!({ "Uid": user_id.to_string(), "roleId": users_role });
+        let response = bearer_post(&client, "/user/role/assign", &token, change.clone()).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = bearer_post(&client, "/user/role/assign", &admin_token, change.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // the change retired the token, a refresh names both roles
+        assert_eq!(get("/user/userinfo", token.clone()).await.status(), Status::Unauthorized);
+        let response = refresh_with(&client, login["refresh_token"].as_str().unwrap()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let token = response.into_json::<serde_json::Value>().await.unwrap()["token"].as_str().unwrap().to_string();
+
+        let info = get("/user/userinfo", token.clone()).await.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(info["roles"], serde_json::json!([format!("{}_metrics", name), format!("{}_users", name)]));
+        assert_eq!(info["permissions"], serde_json::json!(["viewMetrics", "viewUsers"]));
+
+        let response = get("/user", token.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let users = response.into_json::<serde_json::Value>().await.unwrap();
+        let listed = users["data"].as_array().unwrap().iter().find(|user| user["username"] == username.as_str()).unwrap().clone();
+        assert_eq!(listed["role_ids"], serde_json::json!([metrics_role, users_role]));
+
+        let response = bearer_post(&client, "/user/role/remove", &admin_token, serde_json::json!({ "Uid": user_id.to_string(), "roleId": metrics_role })).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = bearer_post(&client, "/user/role/remove", &admin_token, serde_json::json!({ "Uid": user_id.to_string(), "roleId": metrics_role })).await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = bearer_post(&client, "/user/role/remove", &admin_token, change).await;
+        assert_eq!(response.status(), Status::Conflict);
+
+        delete_test_user(pool, &username).await;
+        for role_id in [metrics_role, users_role] {
+            sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role_id as i32).execute(pool).await.unwrap();
+            let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id })).await;
+            assert_eq!(response.status(), Status::Ok);
+        }
+    }
+
+    #[rocket::async_test]
+    async fn test_user_permission_grants_and_denies() {
+        let client = setup_client().await;
+        let pool = client.rocket().state::<PgPool>().unwrap();
+        let admin_token = login_admin(&client).await["token"].as_str().unwrap().to_string();
+
+        let name = format!("test_role_{}", uuid::Uuid::new_v4().simple());
+        let response = bearer_post(&client, "/role", &admin_token, serde_json::json!({ "roleName": name })).await;
+        let role_id = response.into_json::<serde_json::Value>().await.unwrap()["id"].as_i64().unwrap();
+        let change = serde_json::json!({ "roleId": role_id, "permissionName": "viewMetrics" });
+        assert_eq!(bearer_post(&client, "/permission/addRolePermission", &admin_token, change).await.status(), Status::Ok);
+
+        let username = create_test_user_with_role(pool, role_id as i32).await;
+        let user_id: uuid::Uuid = sqlx::query_scalar("SELECT id FROM users WHERE username = $1")
+            .bind(&username)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        let login = login_as(&client, &username, "Passw0rd1").await;
+        let token = login["token"].as_str().unwrap().to_string();
+        let get = |uri: &'static str, token: String| {
+            let client = &client;
+            async move {
+                client.get(uri)
+                    .header(Header::new("Authorization", format!("Bearer {}", token)))
+                    .dispatch()
+                    .await
+            }
+        };
+        let set = |permission: &str, effect: &str| serde_json::json!({ "Uid": user_id.to_string(), "permissionName": permission, "effect": effect });
+
+        let response = bearer_post(&client, "/permission/setUserPermission", &token, set("viewUsers", "grant")).await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = bearer_post(&client, "/permission/setUserPermission", &admin_token, set("noSuchPermission", "deny")).await;
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // a grant on top of the role, and a deny that beats the role
+        let response = bearer_post(&client, "/permission/setUserPermission", &admin_token, set("viewUsers", "grant")).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = bearer_post(&client, "/permission/setUserPermission", &admin_token, set("viewMetrics", "deny")).await;
+        assert_eq!(response.status(), Status::Ok);
+        let mut temporary = set("viewPermissions", "grant");
+        temporary["expiresInDays"] = serde_json::json!(1);
+        let response = bearer_post(&client, "/permission/setUserPermission", &admin_token, temporary).await;
+        assert_eq!(response.status(), Status::Ok);
+        sqlx::query("UPDATE user_permissions SET expires_at = CURRENT_TIMESTAMP - interval '1 minute' WHERE user_id = $1 AND permissions_id = (SELECT id FROM permissions WHERE permissions_name = 'viewPermissions')")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        // the changes retired the token, the refreshed one sees them and not the expired grant
+        assert_eq!(get("/user/userinfo", token.clone()).await.status(), Status::Unauthorized);
+        let response = refresh_with(&client, login["refresh_token"].as_str().unwrap()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let refreshed = response.into_json::<serde_json::Value>().await.unwrap();
+        let token = refreshed["token"].as_str().unwrap().to_string();
+
+        let info = get("/user/userinfo", token.clone()).await.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(info["permissions"], serde_json::json!(["viewUsers"]));
+        assert_eq!(get("/user", token.clone()).await.status(), Status::Ok);
+        assert_eq!(get("/metrics/passwordHashing", token.clone()).await.status(), Status::Forbidden);
+        assert_eq!(get("/permissions", token.clone()).await.status(), Status::Forbidden);
+
+        let response = get("/permission/userPermissions", admin_token.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let listing = response.into_json::<serde_json::Value>().await.unwrap();
+        let overrides: Vec<(String, String, bool)> = listing["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|row| row["username"] == username.as_str())
+            .map(|row| (row["permissions_name"].as_str().unwrap().to_string(), row["effect"].as_str().unwrap().to_string(), row["active"].as_bool().unwrap()))
+            .collect();
+        assert_eq!(overrides, vec![
+            ("viewMetrics".to_string(), "deny".to_string(), true),
+            ("viewPermissions".to_string(), "grant".to_string(), false),
+            ("viewUsers".to_string(), "grant".to_string(), true),
+        ]);
+
+        // without the deny the role decides again
+        let removal = serde_json::json!({ "Uid": user_id.to_string(), "permissionName": "viewMetrics" });
+        let response = bearer_post(&client, "/permission/deleteUserPermission", &admin_token, removal.clone()).await;
+        assert_eq!(response.status(), Status::Ok);
+        let response = bearer_post(&client, "/permission/deleteUserPermission", &admin_token, removal).await;
+        assert_eq!(response.status(), Status::NotFound);
+        let response = refresh_with(&client, refreshed["refresh_token"].as_str().unwrap()).await;
+        let token = response.into_json::<serde_json::Value>().await.unwrap()["token"].as_str().unwrap().to_string();
+        let info = get("/user/userinfo", token).await.into_json::<serde_json::Value>().await.unwrap();
+        assert_eq!(info["permissions"], serde_json::json!(["viewMetrics", "viewUsers"]));
+
+        delete_test_user(pool, &username).await;
+        sqlx::query("DELETE FROM role_permissions WHERE role_id = $1").bind(role_id as i32).execute(pool).await.unwrap();
+        let response = bearer_post(&client, "/role/delete", &admin_token, serde_json::json!({ "roleId": role_id })).await;
+        assert_eq!(response.status(), Status::Ok);
+    }
 }
@@ -0,0 +1,148 @@
+use std::collections::HashSet;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use rocket::request::Request;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::refresh_token::hash_token;
+
+// every key starts with this, so a leaked key is easy to recognise (and to scan for)
+pub const API_KEY_PREFIX: &str = "rwk_";
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+const LOOKUP_LENGTH: usize = 12;
+const SECRET_LENGTH: usize = 40;
+
+// a service account authenticated by one of its keys
+pub struct ApiKeyPrincipal {
+    pub account_name: String,
+    pub permissions: HashSet<String>,
+}
+
+fn random_string(length: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// rwk_<lookup id>_<secret>
+fn split_key(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(API_KEY_PREFIX)?;
+    let (lookup_id, secret) = rest.split_once('_')?;
+    if lookup_id.len() != LOOKUP_LENGTH || secret.len() != SECRET_LENGTH {
+        return None;
+    }
+    Some(lookup_id)
+}
+
+// X-API-Key, or a bearer token that carries the key prefix
+pub fn presented_key<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request.headers().get_one(API_KEY_HEADER).or_else(|| {
+        request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|h| h.split_whitespace().nth(1))
+            .filter(|token| token.starts_with(API_KEY_PREFIX))
+    })
+}
+
+// returns the key id and the key itself, which is not stored anywhere
+pub async fn create_key(
+    pool: &PgPool,
+    service_account_id: Uuid,
+    permission_ids: &[i32],
+    expires_in_days: Option<i32>,
+    created_by: Uuid
+) -> Result<(Uuid, String), sqlx::Error> {
+    let lookup_id = random_string(LOOKUP_LENGTH);
+    let key = format!("{}{}_{}", API_KEY_PREFIX, lookup_id, random_string(SECRET_LENGTH));
+
+    let mut tx = pool.begin().await?;
+    let key_id = sqlx::query_scalar!(
+        r#"
+            INSERT INTO api_keys (service_account_id, lookup_id, key_hash, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP + make_interval(days => $5))
+            RETURNING id
+        "#,
+        service_account_id, lookup_id, hash_token(&key), created_by, expires_in_days
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO api_key_permissions (api_key_id, permissions_id) SELECT $1, UNNEST($2::int4[])",
+        key_id, permission_ids
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok((key_id, key))
+}
+
+// None for unknown, revoked and expired keys
+pub async fn authenticate_key(pool: &PgPool, key: &str) -> Result<Option<ApiKeyPrincipal>, sqlx::Error> {
+    let lookup_id = match split_key(key) {
+        Some(lookup_id) => lookup_id,
+        None => return Ok(None),
+    };
+
+    let found = sqlx::query!(
+        r#"
+            SELECT k.id, k.key_hash, a.name
+            FROM api_keys k JOIN service_accounts a ON a.id = k.service_account_id
+            WHERE k.lookup_id = $1 AND k.revoked_at IS NULL
+              AND (k.expires_at IS NULL OR k.expires_at > CURRENT_TIMESTAMP)
+        "#,
+        lookup_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    let found = match found {
+        Some(found) if found.key_hash == hash_token(key) => found,
+        _ => return Ok(None),
+    };
+
+    let permissions = sqlx::query_scalar!(
+        r#"
+            SELECT p.permissions_name FROM api_key_permissions kp
+            JOIN permissions p ON p.id = kp.permissions_id
+            WHERE kp.api_key_id = $1
+        "#,
+        found.id
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // once a minute is precise enough and keeps busy stations from writing on every call
+    sqlx::query!(
+        r#"
+            UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP
+            WHERE id = $1 AND (last_used_at IS NULL OR last_used_at < CURRENT_TIMESTAMP - INTERVAL '1 minute')
+        "#,
+        found.id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(Some(ApiKeyPrincipal {
+        account_name: found.name,
+        permissions: permissions.into_iter().collect(),
+    }))
+}
+
+// false when there was no such active key
+pub async fn revoke_key(pool: &PgPool, key_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND revoked_at IS NULL",
+        key_id
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
@@ -1,7 +1,11 @@
-use crate::controllers::{permission_controller, user_controller};
-use crate::models::permission::{Permission, Role, RolePermission, RoleResponse};
+use crate::controllers::{metrics_controller, oidc_controller, password_reset_controller, permission_controller, service_account_controller, session_controller, totp_controller, user_controller};
+use crate::models::permission::{Permission, Role, InheritedPermission, RoleCreatedResponse, RoleInUseResponse, RoleMember, RolePermission, RoleResponse, PermissionEffect, UserPermission, UserPermissionListResponse};
 use crate::models::user::{User, UserInfo};
-use crate::responses::response::{GenericResponse, UserInfoResponse, UserListResponse};
+use crate::models::session::{SessionInfo, SessionListResponse};
+use crate::models::totp::{TotpSetupResponse, TotpConfirmResponse};
+use crate::models::service_account::{ApiKeyInfo, ApiKeyResponse, ServiceAccountInfo, ServiceAccountListResponse, ServiceAccountResponse};
+use crate::tools::password_hash::HashMetricsSnapshot;
+use crate::responses::response::{GenericResponse, HashMetricsResponse, ResetCodeResponse, UserInfoResponse, UserListResponse};
 
 use utoipa::OpenApi;
 
@@ -11,20 +15,54 @@ use utoipa::OpenApi;
         user_controller::get_users,
         user_controller::get_userinfo,
         user_controller::soft_delete_user,
+        user_controller::reactivate_user,
+        user_controller::assign_user_role,
+        user_controller::remove_user_role,
         user_controller::edit_password,
+        user_controller::change_own_password,
+        password_reset_controller::issue_reset_code,
+        password_reset_controller::redeem_reset_code,
+        user_controller::unlock_user,
         user_controller::register,
         user_controller::generate_captcha_handler,
         user_controller::login,
+        user_controller::login_totp,
+        user_controller::login_password,
+        oidc_controller::oidc_login,
+        oidc_controller::oidc_callback,
+        oidc_controller::link_oidc_identity,
+        user_controller::refresh,
         user_controller::logout,
+        session_controller::list_sessions,
+        session_controller::revoke_own_session,
+        session_controller::revoke_all_sessions,
+        session_controller::list_user_sessions,
+        session_controller::terminate_user_sessions,
+        totp_controller::totp_setup,
+        totp_controller::totp_confirm,
+        totp_controller::totp_disable,
         permission_controller::permission_list,
         permission_controller::get_role_permission,
         permission_controller::add_role_permissiom,
         permission_controller::delete_role_permission,
-        permission_controller::get_role
+        permission_controller::user_permission_list,
+        permission_controller::set_user_permission,
+        permission_controller::delete_user_permission,
+        permission_controller::get_role,
+        permission_controller::create_role,
+        permission_controller::rename_role,
+        permission_controller::delete_role,
+        permission_controller::set_role_parents,
+        permission_controller::set_mfa_policy,
+        metrics_controller::password_hashing_metrics,
+        service_account_controller::create_service_account,
+        service_account_controller::list_service_accounts,
+        service_account_controller::create_api_key,
+        service_account_controller::revoke_api_key
     ),
     components(
-        schemas(User, UserInfo, Permission, RolePermission, Role),
-        responses(UserListResponse,UserInfoResponse,GenericResponse, RoleResponse),
+        schemas(User, UserInfo, Permission, RolePermission, InheritedPermission, PermissionEffect, UserPermission, Role, RoleMember, SessionInfo, HashMetricsSnapshot, ServiceAccountInfo, ApiKeyInfo),
+        responses(UserListResponse,UserInfoResponse,GenericResponse, RoleResponse, UserPermissionListResponse, RoleCreatedResponse, RoleInUseResponse, SessionListResponse, TotpSetupResponse, TotpConfirmResponse, ResetCodeResponse, HashMetricsResponse, ServiceAccountListResponse, ServiceAccountResponse, ApiKeyResponse),
     ),
     // tags(
     //     (name = "user::api", description = "User management endpoints."),
@@ -0,0 +1,35 @@
+use log::info;
+use sqlx::PgPool;
+
+use crate::tools::client::ClientInfo;
+
+pub const RESET_CODE_ISSUED: &str = "password_reset_code_issued";
+pub const RESET_CODE_REDEEMED: &str = "password_reset_code_redeemed";
+pub const RESET_CODE_REJECTED: &str = "password_reset_code_rejected";
+pub const API_KEY_CREATED: &str = "api_key_created";
+pub const API_KEY_REVOKED: &str = "api_key_revoked";
+pub const USER_PERMISSION_SET: &str = "user_permission_set";
+pub const USER_PERMISSION_REMOVED: &str = "user_permission_removed";
+
+// written to the audit_log table and mirrored to the application log
+pub async fn record(
+    pool: &PgPool,
+    action: &str,
+    actor: Option<&str>,
+    target: Option<&str>,
+    client: &ClientInfo,
+    detail: Option<&str>
+) -> Result<(), sqlx::Error> {
+    info!("AUDIT {} actor={:?} target={:?} ip={:?} {}", action, actor, target, client.ip_address, detail.unwrap_or(""));
+
+    sqlx::query!(
+        r#"
+            INSERT INTO audit_log (action, actor, target, ip_address, detail)
+            VALUES ($1, $2, $3, $4, $5)
+        "#,
+        action, actor, target, client.ip_address, detail
+    )
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
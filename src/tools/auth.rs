@@ -0,0 +1,196 @@
+use log::{error, warn};
+use rand::Rng;
+use rocket::http::{Method, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::outcome::Outcome;
+use rocket::State;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::jwt::{validate_jwt, validate_challenge, Claims, ChallengeClaims, JwtKeyring, RoleVersion, MFA_ENROLL_CHALLENGE};
+use crate::tools::token_revocation::is_revoked;
+use crate::tools::session::touch_session;
+
+pub const TOKEN_COOKIE: &str = "user_token";
+// readable by the front end, echoed back in CSRF_HEADER (double-submit)
+pub const CSRF_COOKIE: &str = "csrf_token";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// valid, non revoked access token from the Authorization header or the user_token cookie,
+// with the user and role it belongs to; every authenticated route goes through this guard
+#[derive(Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: Uuid,
+    pub username: String,
+    // from the token, which is refused once the user's roles changed
+    pub roles: Vec<RoleVersion>,
+    pub claims: Claims,
+}
+
+// setting up a second factor: a signed in user, or one whose role requires it and
+// who only got an enrollment challenge from /user/login
+pub struct TotpEnrollee {
+    pub username: String,
+    pub challenge: Option<ChallengeClaims>,
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Revoked,
+    Csrf,
+    Internal,
+}
+
+pub fn new_csrf_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn bearer_token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    // "Bearer <token>"
+    request.headers().get_one("Authorization").and_then(|h| h.split_whitespace().nth(1))
+}
+
+// the browser attaches cookies to cross-site requests too, so a state changing request
+// authenticated by cookie must also carry the csrf cookie value in a header
+fn csrf_ok(request: &Request<'_>) -> bool {
+    if matches!(request.method(), Method::Get | Method::Head | Method::Options) {
+        return true;
+    }
+
+    match (request.cookies().get(CSRF_COOKIE), request.headers().get_one(CSRF_HEADER)) {
+        (Some(cookie), Some(header)) => !header.is_empty() && cookie.value() == header,
+        _ => false
+    }
+}
+
+async fn authenticate(request: &Request<'_>) -> Result<AuthenticatedUser, (Status, AuthError)> {
+    let pool = request.guard::<&State<PgPool>>().await.unwrap();
+    let keyring = request.guard::<&State<JwtKeyring>>().await.unwrap();
+
+    // the header wins, API clients never need the csrf check
+    let token = match bearer_token(request) {
+        Some(token) => token.to_string(),
+        None => match request.cookies().get(TOKEN_COOKIE) {
+            Some(cookie) => {
+                if !csrf_ok(request) {
+                    warn!("Rejected cookie authenticated {} {}: csrf token missing or wrong", request.method(), request.uri());
+                    return Err((Status::Forbidden, AuthError::Csrf));
+                }
+                cookie.value().to_string()
+            }
+            None => return Err((Status::Unauthorized, AuthError::Missing))
+        }
+    };
+
+    let claims = match validate_jwt(keyring.inner(), &token) {
+        Ok(token_data) => token_data.claims,
+        Err(_) => return Err((Status::Unauthorized, AuthError::Invalid))
+    };
+
+    match is_revoked(pool.inner(), claims.jti).await {
+        Ok(false) => {},
+        Ok(true) => return Err((Status::Unauthorized, AuthError::Revoked)),
+        Err(e) => {
+            error!("Token revocation lookup failed: {:?}", e);
+            return Err((Status::InternalServerError, AuthError::Internal));
+        }
+    }
+
+    // signed out elsewhere or terminated by an admin
+    match touch_session(pool.inner(), claims.sid).await {
+        Ok(true) => {},
+        Ok(false) => return Err((Status::Unauthorized, AuthError::Revoked)),
+        Err(e) => {
+            error!("Session lookup failed: {:?}", e);
+            return Err((Status::InternalServerError, AuthError::Internal));
+        }
+    }
+
+    let user = sqlx::query!(
+        "SELECT id, token_version FROM users WHERE username = $1 AND deleted IS NOT TRUE",
+        claims.sub
+    )
+    .fetch_optional(pool.inner())
+    .await;
+
+    match user {
+        // password or roles changed since the token was issued
+        Ok(Some(user)) if user.token_version != claims.tver => Err((Status::Unauthorized, AuthError::Revoked)),
+        Ok(Some(user)) => Ok(AuthenticatedUser {
+            user_id: user.id,
+            username: claims.sub.clone(),
+            roles: claims.roles.clone(),
+            claims,
+        }),
+        Ok(None) => Err((Status::Unauthorized, AuthError::Invalid)),
+        Err(e) => {
+            error!("User lookup failed: {:?}", e);
+            Err((Status::InternalServerError, AuthError::Internal))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // several guards of one request build on this one, authenticate only once
+        let result = request.local_cache_async(authenticate(request)).await;
+
+        match result {
+            Ok(auth_user) => Outcome::Success(auth_user.clone()),
+            Err(e) => Outcome::Error(e.clone())
+        }
+    }
+}
+
+// challenge tokens are single use, the step that consumes one revokes its jti
+pub async fn check_challenge(
+    pool: &PgPool,
+    keyring: &JwtKeyring,
+    token: &str,
+    purpose: &str
+) -> Result<ChallengeClaims, (Status, AuthError)> {
+    let claims = validate_challenge(keyring, token, purpose)
+        .map_err(|_| (Status::Unauthorized, AuthError::Invalid))?;
+
+    match is_revoked(pool, claims.jti).await {
+        Ok(false) => Ok(claims),
+        Ok(true) => Err((Status::Unauthorized, AuthError::Revoked)),
+        Err(e) => {
+            error!("Token revocation lookup failed: {:?}", e);
+            Err((Status::InternalServerError, AuthError::Internal))
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TotpEnrollee {
+    type Error = AuthError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(auth_user) => return Outcome::Success(TotpEnrollee { username: auth_user.username, challenge: None }),
+            // only a missing or unusable access token falls through to the challenge
+            Outcome::Error((status, e)) if status != Status::Unauthorized => return Outcome::Error((status, e)),
+            _ => {}
+        }
+
+        let pool = request.guard::<&State<PgPool>>().await.unwrap();
+        let keyring = request.guard::<&State<JwtKeyring>>().await.unwrap();
+
+        let token = match bearer_token(request) {
+            Some(token) => token,
+            None => return Outcome::Error((Status::Unauthorized, AuthError::Missing))
+        };
+
+        match check_challenge(pool.inner(), keyring.inner(), token, MFA_ENROLL_CHALLENGE).await {
+            Ok(claims) => Outcome::Success(TotpEnrollee { username: claims.sub.clone(), challenge: Some(claims) }),
+            Err(e) => Outcome::Error(e)
+        }
+    }
+}
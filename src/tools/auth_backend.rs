@@ -0,0 +1,183 @@
+use std::fmt;
+
+use log::{error, info};
+use rocket::http::Status;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::ldap_backend::{LdapBackend, LdapConfig};
+use crate::tools::password_hash::{HashError, PasswordHasher};
+
+// users.auth_source
+pub const LOCAL_SOURCE: &str = "local";
+pub const LDAP_SOURCE: &str = "ldap";
+// created on the first OIDC login, see tools::oidc
+pub const OIDC_SOURCE: &str = "oidc";
+
+#[derive(Debug)]
+pub enum BackendError {
+    Database(sqlx::Error),
+    Hash(HashError),
+    // directory unreachable or misconfigured
+    Directory(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Database(e) => write!(f, "database error: {}", e),
+            BackendError::Hash(e) => write!(f, "password hashing error: {}", e),
+            BackendError::Directory(message) => write!(f, "directory error: {}", message),
+        }
+    }
+}
+
+impl From<sqlx::Error> for BackendError {
+    fn from(error: sqlx::Error) -> Self {
+        BackendError::Database(error)
+    }
+}
+
+impl From<HashError> for BackendError {
+    fn from(error: HashError) -> Self {
+        BackendError::Hash(error)
+    }
+}
+
+impl From<BackendError> for Status {
+    fn from(error: BackendError) -> Self {
+        match error {
+            BackendError::Hash(e) => e.into(),
+            BackendError::Directory(_) => Status::ServiceUnavailable,
+            BackendError::Database(_) => Status::InternalServerError,
+        }
+    }
+}
+
+// what every backend gets from the managed state
+pub struct BackendContext<'a> {
+    pub pool: &'a PgPool,
+    pub hasher: &'a PasswordHasher,
+}
+
+#[derive(Debug)]
+pub struct AuthenticatedAccount {
+    pub user_id: Uuid,
+    pub source: &'static str,
+}
+
+#[rocket::async_trait]
+pub trait AuthBackend: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // the local user the credentials belong to, None when this backend does not accept them
+    async fn authenticate(&self, ctx: &BackendContext<'_>, username: &str, password: &str) -> Result<Option<Uuid>, BackendError>;
+}
+
+// the password hash in users.password
+pub struct LocalBackend;
+
+#[rocket::async_trait]
+impl AuthBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        LOCAL_SOURCE
+    }
+
+    async fn authenticate(&self, ctx: &BackendContext<'_>, username: &str, password: &str) -> Result<Option<Uuid>, BackendError> {
+        let user = sqlx::query!(
+            "SELECT id, password FROM users WHERE username = $1 AND auth_source = $2 AND deleted IS NOT TRUE",
+            username, LOCAL_SOURCE
+        )
+        .fetch_optional(ctx.pool)
+        .await?;
+
+        // a disabled user is treated like an unknown one
+        let user = match user {
+            Some(user) => user,
+            None => {
+                // burn the same time as a real check so response timing does not leak the username
+                ctx.hasher.verify(password, ctx.hasher.dummy_hash()).await?;
+                return Ok(None);
+            }
+        };
+
+        if !ctx.hasher.verify(password, &user.password).await? {
+            return Ok(None);
+        }
+
+        // legacy bcrypt or outdated parameters, the plain password is only at hand now
+        if ctx.hasher.needs_rehash(&user.password) {
+            match ctx.hasher.hash(password).await {
+                Ok(new_hash) => match sqlx::query!("UPDATE users SET password = $1 WHERE id = $2", new_hash, user.id)
+                    .execute(ctx.pool)
+                    .await
+                {
+                    Ok(_) => info!("Upgraded password hash of user {}", username),
+                    Err(e) => error!("Failed to store upgraded password hash: {:?}", e),
+                },
+                Err(e) => error!("Failed to rehash password: {}", e),
+            }
+        }
+
+        Ok(Some(user.id))
+    }
+}
+
+// tried in the configured order, the first backend that accepts the password wins
+pub struct AuthBackends {
+    backends: Vec<Box<dyn AuthBackend>>,
+}
+
+impl AuthBackends {
+    pub fn new(backends: Vec<Box<dyn AuthBackend>>) -> Self {
+        AuthBackends { backends }
+    }
+
+    // AUTH_BACKENDS (local), comma separated from `local`, `ldap`
+    pub fn from_env() -> Result<Self, String> {
+        let names = std::env::var("AUTH_BACKENDS").unwrap_or_else(|_| LOCAL_SOURCE.to_string());
+
+        let mut backends: Vec<Box<dyn AuthBackend>> = Vec::new();
+        for name in names.split(',').map(|name| name.trim().to_lowercase()).filter(|name| !name.is_empty()) {
+            match name.as_str() {
+                LOCAL_SOURCE => backends.push(Box::new(LocalBackend)),
+                LDAP_SOURCE => backends.push(Box::new(LdapBackend::new(LdapConfig::from_env()?))),
+                other => return Err(format!("unknown authentication backend: {}", other)),
+            }
+        }
+
+        if backends.is_empty() {
+            return Err("AUTH_BACKENDS is empty".to_string());
+        }
+        Ok(AuthBackends::new(backends))
+    }
+
+    pub async fn authenticate(&self, ctx: &BackendContext<'_>, username: &str, password: &str) -> Result<Option<AuthenticatedAccount>, BackendError> {
+        // a directory that is down must not lock out the local accounts
+        let mut unavailable = None;
+        for backend in &self.backends {
+            match backend.authenticate(ctx, username, password).await {
+                Ok(Some(user_id)) => return Ok(Some(AuthenticatedAccount { user_id, source: backend.name() })),
+                Ok(None) => {},
+                Err(BackendError::Directory(message)) => {
+                    error!("Authentication backend {} failed: {}", backend.name(), message);
+                    unavailable = Some(BackendError::Directory(message));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        match unavailable {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+}
+
+// password changes and resets only make sense for passwords stored here
+pub async fn has_local_password(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!("SELECT auth_source FROM users WHERE id = $1", user_id)
+        .fetch_optional(pool)
+        .await
+        .map(|source| source.as_deref() == Some(LOCAL_SOURCE))
+}
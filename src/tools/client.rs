@@ -0,0 +1,82 @@
+use std::convert::Infallible;
+use std::net::IpAddr;
+
+use log::warn;
+use rocket::request::{self, FromRequest, Request};
+use rocket::outcome::Outcome;
+
+// where a request comes from, recorded on sessions
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ClientConfig {
+    // reverse proxies whose X-Forwarded-For is believed; from anyone else the header is ignored,
+    // so the address lockouts and audit entries rely on cannot be made up by the client
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl ClientConfig {
+    // TRUSTED_PROXIES, comma separated addresses, none by default
+    pub fn from_env() -> Self {
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .filter_map(|entry| match entry.parse() {
+                Ok(ip) => Some(ip),
+                Err(_) => {
+                    warn!("Ignoring TRUSTED_PROXIES entry {}, not an IP address", entry);
+                    None
+                }
+            })
+            .collect();
+        ClientConfig { trusted_proxies }
+    }
+
+    // the connecting peer, or behind trusted proxies the last address in X-Forwarded-For
+    // that is not one of them; entries further left were written by the client
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let peer = peer?;
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            match hop.trim().parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.trusted_proxies.contains(&ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        // not Request::client_ip, which takes X-Real-IP from anyone
+        let peer = request.remote().map(|addr| addr.ip());
+        let forwarded_for = request.headers().get_one("X-Forwarded-For");
+        let ip = match request.rocket().state::<ClientConfig>() {
+            Some(config) => config.client_ip(peer, forwarded_for),
+            None => peer,
+        };
+
+        Outcome::Success(ClientInfo {
+            ip_address: ip.map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(|ua| ua.to_string()),
+        })
+    }
+}
@@ -1,35 +1,276 @@
-use jsonwebtoken::{encode, Header, EncodingKey, decode, DecodingKey, Validation, Algorithm, TokenData};
+use std::collections::HashMap;
+use std::fmt;
+
+use jsonwebtoken::{encode, decode, decode_header, Header, EncodingKey, DecodingKey, Validation, Algorithm, TokenData};
+use jsonwebtoken::errors::{Error, ErrorKind};
 use serde::{Serialize, Deserialize};
 use chrono::{Utc, Duration};
+use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub exp: usize,
+    pub iat: usize,
+    // token id, used to revoke a single token
+    pub jti: Uuid,
+    // login session the token belongs to
+    pub sid: Uuid,
+    // the user's roles with roles.permissions_version at issue time, the guard takes the permissions from its cache
+    pub roles: Vec<RoleVersion>,
+    // users.token_version, bumped on password and role changes to retire older tokens
+    pub tver: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleVersion {
+    pub id: i32,
+    pub ver: i32,
+}
+
+// the parts of an access token that come from the user and role rows, see tools::permission_cache
+#[derive(Debug, Clone)]
+pub struct TokenGrant {
+    pub roles: Vec<RoleVersion>,
+    pub token_version: i32,
+}
+
+// short lived token handed out between login steps (second factor, enrollment);
+// it has no `sid`, so it is never accepted as an access token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub sub: String,
+    pub exp: usize,
+    pub iat: usize,
+    pub jti: Uuid,
+    pub purpose: String,
+}
+
+pub const MFA_CHALLENGE: &str = "mfa";
+pub const MFA_ENROLL_CHALLENGE: &str = "mfa_enroll";
+pub const PASSWORD_CHANGE_CHALLENGE: &str = "password_change";
+
+#[derive(Debug)]
+pub struct JwtConfigError(pub String);
+
+impl fmt::Display for JwtConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// one signing / verification key, identified by the `kid` header
+pub struct JwtKey {
+    pub kid: String,
+    pub algorithm: Algorithm,
+    encoding: Option<EncodingKey>,
+    decoding: DecodingKey,
+}
+
+impl JwtKey {
+    pub fn hmac(kid: &str, algorithm: Algorithm, secret: &[u8]) -> Self {
+        JwtKey {
+            kid: kid.to_owned(),
+            algorithm,
+            encoding: Some(EncodingKey::from_secret(secret)),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    // private key is optional: retired keys only need to verify
+    pub fn asymmetric(kid: &str, algorithm: Algorithm, public_pem: &[u8], private_pem: Option<&[u8]>) -> Result<Self, Error> {
+        let (decoding, encoding) = match algorithm {
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+            | Algorithm::PS256 | Algorithm::PS384 | Algorithm::PS512 => (
+                DecodingKey::from_rsa_pem(public_pem)?,
+                private_pem.map(EncodingKey::from_rsa_pem).transpose()?,
+            ),
+            Algorithm::ES256 | Algorithm::ES384 => (
+                DecodingKey::from_ec_pem(public_pem)?,
+                private_pem.map(EncodingKey::from_ec_pem).transpose()?,
+            ),
+            Algorithm::EdDSA => (
+                DecodingKey::from_ed_pem(public_pem)?,
+                private_pem.map(EncodingKey::from_ed_pem).transpose()?,
+            ),
+            _ => return Err(ErrorKind::InvalidAlgorithm.into()),
+        };
+
+        Ok(JwtKey { kid: kid.to_owned(), algorithm, encoding, decoding })
+    }
+}
+
+// current signing key plus retired keys that are still accepted
+pub struct JwtKeyring {
+    active_kid: String,
+    keys: HashMap<String, JwtKey>,
+}
+
+impl JwtKeyring {
+    pub fn new(active_kid: &str, keys: Vec<JwtKey>) -> Result<Self, JwtConfigError> {
+        let keys: HashMap<String, JwtKey> = keys.into_iter().map(|k| (k.kid.clone(), k)).collect();
+
+        match keys.get(active_kid) {
+            Some(key) if key.encoding.is_some() => {}
+            Some(_) => return Err(JwtConfigError(format!("active JWT key '{}' has no private key", active_kid))),
+            None => return Err(JwtConfigError(format!("active JWT key '{}' is not configured", active_kid))),
+        }
+
+        Ok(JwtKeyring { active_kid: active_kid.to_owned(), keys })
+    }
+
+    // JWT_KEYS=site-a-2024,site-a-2023
+    // JWT_ACTIVE_KID=site-a-2024
+    // JWT_KEY_SITE_A_2024_ALG=HS256, JWT_KEY_SITE_A_2024_SECRET=...
+    // JWT_KEY_SITE_A_2023_ALG=RS256, JWT_KEY_SITE_A_2023_PUBLIC_KEY_FILE=..., JWT_KEY_SITE_A_2023_PRIVATE_KEY_FILE=...
+    // without JWT_KEYS a single HS256 key is read from JWT_SECRET
+    pub fn from_env() -> Result<Self, JwtConfigError> {
+        let kids = match std::env::var("JWT_KEYS") {
+            Ok(kids) => kids,
+            Err(_) => {
+                let secret = std::env::var("JWT_SECRET")
+                    .map_err(|_| JwtConfigError("JWT_KEYS or JWT_SECRET must be set".to_string()))?;
+                return JwtKeyring::new("default", vec![JwtKey::hmac("default", Algorithm::HS256, secret.as_bytes())]);
+            }
+        };
+
+        let mut keys = Vec::new();
+        for kid in kids.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+            keys.push(load_key(kid)?);
+        }
+
+        let active_kid = std::env::var("JWT_ACTIVE_KID")
+            .map_err(|_| JwtConfigError("JWT_ACTIVE_KID must be set when JWT_KEYS is used".to_string()))?;
+
+        JwtKeyring::new(&active_kid, keys)
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, Error> {
+        let key = &self.keys[&self.active_kid];
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+
+        // checked in `new`, the active key always has an encoding key
+        encode(&header, claims, key.encoding.as_ref().expect("active key can sign"))
+    }
+
+    pub fn verify<T: for<'de> Deserialize<'de>>(&self, token: &str) -> Result<TokenData<T>, Error> {
+        let header = decode_header(token)?;
+        let kid = header.kid.unwrap_or_else(|| self.active_kid.clone());
+
+        let key = match self.keys.get(&kid) {
+            Some(key) => key,
+            None => return Err(ErrorKind::InvalidToken.into()),
+        };
+
+        decode::<T>(token, &key.decoding, &Validation::new(key.algorithm))
+    }
+}
+
+fn load_key(kid: &str) -> Result<JwtKey, JwtConfigError> {
+    let prefix = format!(
+        "JWT_KEY_{}_",
+        kid.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect::<String>()
+    );
+    let var = |name: &str| std::env::var(format!("{}{}", prefix, name)).ok();
+
+    let algorithm: Algorithm = var("ALG")
+        .unwrap_or_else(|| "HS256".to_string())
+        .parse()
+        .map_err(|_| JwtConfigError(format!("unknown algorithm for JWT key '{}'", kid)))?;
+
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = var("SECRET")
+                .ok_or_else(|| JwtConfigError(format!("{}SECRET must be set", prefix)))?;
+            Ok(JwtKey::hmac(kid, algorithm, secret.as_bytes()))
+        }
+        _ => {
+            let read = |path: String| std::fs::read(&path)
+                .map_err(|e| JwtConfigError(format!("cannot read {}: {}", path, e)));
+
+            let public_pem = read(var("PUBLIC_KEY_FILE")
+                .ok_or_else(|| JwtConfigError(format!("{}PUBLIC_KEY_FILE must be set", prefix)))?)?;
+            let private_pem = var("PRIVATE_KEY_FILE").map(read).transpose()?;
+
+            JwtKey::asymmetric(kid, algorithm, &public_pem, private_pem.as_deref())
+                .map_err(|e| JwtConfigError(format!("invalid key material for JWT key '{}': {}", kid, e)))
+        }
+    }
+}
+
+// lifetimes of issued access / refresh tokens
+pub struct TokenConfig {
+    pub access_ttl: Duration,
+    pub refresh_ttl: Duration,
+    pub challenge_ttl: Duration,
+}
+
+impl TokenConfig {
+    // ACCESS_TOKEN_TTL_MINUTES (default 15), REFRESH_TOKEN_TTL_HOURS (default 24),
+    // CHALLENGE_TOKEN_TTL_MINUTES (default 5)
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: i64| std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(default);
+
+        TokenConfig {
+            access_ttl: Duration::minutes(read("ACCESS_TOKEN_TTL_MINUTES", 15)),
+            refresh_ttl: Duration::hours(read("REFRESH_TOKEN_TTL_HOURS", 24)),
+            challenge_ttl: Duration::minutes(read("CHALLENGE_TOKEN_TTL_MINUTES", 5)),
+        }
+    }
 }
 
 // create jwt
-pub async fn generate_jwt(username: &str) -> Result<String, jsonwebtoken::errors::Error> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::hours(8))
+pub async fn generate_jwt(keyring: &JwtKeyring, username: &str, session_id: Uuid, grant: TokenGrant, ttl: Duration) -> Result<String, Error> {
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(ttl)
         .expect("valid timestamp")
         .timestamp();
 
     let claims = Claims {
         sub: username.to_owned(),
         exp: expiration as usize,
+        iat: now.timestamp() as usize,
+        jti: Uuid::new_v4(),
+        sid: session_id,
+        roles: grant.roles,
+        tver: grant.token_version,
     };
 
-    let sercet = "kenkone8282";
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(sercet.as_ref()))
+    keyring.sign(&claims)
 }
 
 // 驗證 JWT
-pub fn validate_jwt(token: &str) -> Result<TokenData<Claims>, jsonwebtoken::errors::Error> {
-    let secret = "kenkone8282";
-    decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &Validation::new(Algorithm::HS256),
-    )
-}
\ No newline at end of file
+pub fn validate_jwt(keyring: &JwtKeyring, token: &str) -> Result<TokenData<Claims>, Error> {
+    keyring.verify::<Claims>(token)
+}
+
+pub fn generate_challenge(keyring: &JwtKeyring, username: &str, purpose: &str, ttl: Duration) -> Result<String, Error> {
+    let now = Utc::now();
+    let expiration = now
+        .checked_add_signed(ttl)
+        .expect("valid timestamp")
+        .timestamp();
+
+    let claims = ChallengeClaims {
+        sub: username.to_owned(),
+        exp: expiration as usize,
+        iat: now.timestamp() as usize,
+        jti: Uuid::new_v4(),
+        purpose: purpose.to_owned(),
+    };
+
+    keyring.sign(&claims)
+}
+
+pub fn validate_challenge(keyring: &JwtKeyring, token: &str, purpose: &str) -> Result<ChallengeClaims, Error> {
+    let claims = keyring.verify::<ChallengeClaims>(token)?.claims;
+    if claims.purpose != purpose {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
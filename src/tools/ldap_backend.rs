@@ -0,0 +1,291 @@
+use std::time::Duration;
+
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::tools::auth_backend::{AuthBackend, BackendContext, BackendError, LDAP_SOURCE};
+use crate::tools::user_roles;
+
+// LDAP result code for a wrong password or an unknown bind DN
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct LdapConfig {
+    pub url: String,
+    // service account used to look up users and groups, anonymous when unset
+    pub bind_dn: Option<String>,
+    pub bind_password: String,
+    pub user_base: String,
+    // `{username}` is replaced with the escaped login name
+    pub user_filter: String,
+    // attribute with a stable id of the account, the DN is used when it is missing
+    pub id_attribute: String,
+    // when set groups are searched here with `group_filter`, otherwise `memberOf` of the user is read
+    pub group_base: Option<String>,
+    // `{dn}` and `{username}` are replaced, escaped
+    pub group_filter: String,
+    // group (CN or full DN) -> role name, the user gets the role of every group here they are in
+    pub group_roles: Vec<(String, String)>,
+    // role for directory users in none of the mapped groups, they cannot log in when unset
+    pub default_role: Option<String>,
+    pub starttls: bool,
+    pub timeout: Duration,
+}
+
+impl LdapConfig {
+    // LDAP_URL, LDAP_BIND_DN, LDAP_BIND_PASSWORD, LDAP_USER_BASE, LDAP_USER_FILTER ((sAMAccountName={username})),
+    // LDAP_ID_ATTRIBUTE (objectGUID), LDAP_GROUP_BASE, LDAP_GROUP_FILTER ((member={dn})),
+    // LDAP_GROUP_ROLES (`group=role;group=role`), LDAP_DEFAULT_ROLE, LDAP_STARTTLS (false), LDAP_TIMEOUT_SECONDS (5)
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set for the ldap backend", name));
+
+        Ok(LdapConfig {
+            url: required("LDAP_URL")?,
+            bind_dn: var("LDAP_BIND_DN"),
+            bind_password: var("LDAP_BIND_PASSWORD").unwrap_or_default(),
+            user_base: required("LDAP_USER_BASE")?,
+            user_filter: var("LDAP_USER_FILTER").unwrap_or_else(|| "(sAMAccountName={username})".to_string()),
+            id_attribute: var("LDAP_ID_ATTRIBUTE").unwrap_or_else(|| "objectGUID".to_string()),
+            group_base: var("LDAP_GROUP_BASE"),
+            group_filter: var("LDAP_GROUP_FILTER").unwrap_or_else(|| "(member={dn})".to_string()),
+            group_roles: parse_group_roles(&var("LDAP_GROUP_ROLES").unwrap_or_default())?,
+            default_role: var("LDAP_DEFAULT_ROLE"),
+            starttls: var("LDAP_STARTTLS").map(|v| v.eq_ignore_ascii_case("true")).unwrap_or(false),
+            timeout: Duration::from_secs(var("LDAP_TIMEOUT_SECONDS").and_then(|v| v.parse().ok()).unwrap_or(5)),
+        })
+    }
+}
+
+// `Radiologists=doctor;CN=IT Admins,OU=Groups,DC=hospital,DC=local=admin`, split at the last `=`
+pub fn parse_group_roles(value: &str) -> Result<Vec<(String, String)>, String> {
+    value
+        .split(';')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| match entry.rsplit_once('=') {
+            Some((group, role)) if !group.trim().is_empty() && !role.trim().is_empty() => {
+                Ok((group.trim().to_string(), role.trim().to_string()))
+            }
+            _ => Err(format!("invalid LDAP_GROUP_ROLES entry: {}", entry)),
+        })
+        .collect()
+}
+
+// value of the first RDN, `Radiologists` for `CN=Radiologists,OU=Groups,...`
+fn group_cn(dn: &str) -> &str {
+    let first = dn.split(',').next().unwrap_or(dn);
+    first.split_once('=').map(|(_, value)| value.trim()).unwrap_or(first)
+}
+
+// the roles of every mapping that matches one of the groups, in mapping order without
+// repeats; the default role when none matches
+pub fn roles_for_groups<'a>(config: &'a LdapConfig, groups: &[String]) -> Vec<&'a str> {
+    let mut roles: Vec<&str> = Vec::new();
+    for (group, role) in &config.group_roles {
+        let member = groups.iter().any(|dn| dn.eq_ignore_ascii_case(group) || group_cn(dn).eq_ignore_ascii_case(group));
+        if member && !roles.contains(&role.as_str()) {
+            roles.push(role);
+        }
+    }
+    if roles.is_empty() {
+        roles.extend(config.default_role.as_deref());
+    }
+    roles
+}
+
+fn attribute<'a>(entry: &'a SearchEntry, name: &str) -> Option<&'a Vec<String>> {
+    entry.attrs.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, values)| values)
+}
+
+fn external_id(entry: &SearchEntry, name: &str) -> String {
+    if let Some(value) = attribute(entry, name).and_then(|values| values.first()) {
+        return value.clone();
+    }
+    // binary ids such as the AD objectGUID
+    if let Some(value) = entry.bin_attrs.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).and_then(|(_, values)| values.first()) {
+        return value.iter().map(|byte| format!("{:02x}", byte)).collect();
+    }
+    entry.dn.clone()
+}
+
+fn directory_error(e: LdapError) -> BackendError {
+    BackendError::Directory(e.to_string())
+}
+
+// simple bind against the directory; the local row is created on the first login
+// and its role follows the AD groups on every login after that
+pub struct LdapBackend {
+    config: LdapConfig,
+}
+
+impl LdapBackend {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapBackend { config }
+    }
+
+    async fn connect(&self) -> Result<Ldap, BackendError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.config.timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await.map_err(directory_error)?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    async fn bind_service(&self, ldap: &mut Ldap) -> Result<(), BackendError> {
+        if let Some(bind_dn) = &self.config.bind_dn {
+            ldap.simple_bind(bind_dn, &self.config.bind_password)
+                .await
+                .and_then(|result| result.success())
+                .map_err(directory_error)?;
+        }
+        Ok(())
+    }
+
+    async fn find_user(&self, ldap: &mut Ldap, username: &str) -> Result<Option<SearchEntry>, BackendError> {
+        let filter = self.config.user_filter.replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(&self.config.user_base, Scope::Subtree, &filter, vec!["memberOf", self.config.id_attribute.as_str()])
+            .await
+            .and_then(|result| result.success())
+            .map_err(directory_error)?;
+
+        // an ambiguous filter must not pick one of several accounts
+        if entries.len() != 1 {
+            return Ok(None);
+        }
+        Ok(entries.into_iter().next().map(SearchEntry::construct))
+    }
+
+    async fn find_groups(&self, ldap: &mut Ldap, user: &SearchEntry, username: &str) -> Result<Vec<String>, BackendError> {
+        let group_base = match &self.config.group_base {
+            Some(group_base) => group_base,
+            None => return Ok(attribute(user, "memberOf").cloned().unwrap_or_default()),
+        };
+
+        let filter = self.config.group_filter
+            .replace("{dn}", &ldap_escape(user.dn.as_str()))
+            .replace("{username}", &ldap_escape(username));
+        let (entries, _) = ldap
+            .search(group_base, Scope::Subtree, &filter, vec!["1.1"])
+            .await
+            .and_then(|result| result.success())
+            .map_err(directory_error)?;
+
+        Ok(entries.into_iter().map(|entry| SearchEntry::construct(entry).dn).collect())
+    }
+
+    // user DN and groups when the password is right
+    async fn check_password(&self, username: &str, password: &str) -> Result<Option<(SearchEntry, Vec<String>)>, BackendError> {
+        let mut ldap = self.connect().await?;
+        self.bind_service(&mut ldap).await?;
+
+        let user = match self.find_user(&mut ldap, username).await? {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+
+        let bind = ldap.simple_bind(&user.dn, password).await.map_err(directory_error)?;
+        if bind.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bind.success().map_err(directory_error)?;
+
+        // groups are read with the service account again, users may not see their groups
+        self.bind_service(&mut ldap).await?;
+        let groups = self.find_groups(&mut ldap, &user, username).await?;
+        let _ = ldap.unbind().await;
+
+        Ok(Some((user, groups)))
+    }
+}
+
+#[rocket::async_trait]
+impl AuthBackend for LdapBackend {
+    fn name(&self) -> &'static str {
+        LDAP_SOURCE
+    }
+
+    async fn authenticate(&self, ctx: &BackendContext<'_>, username: &str, password: &str) -> Result<Option<Uuid>, BackendError> {
+        // an empty password would be an anonymous bind, which most servers accept
+        if password.is_empty() || username.trim().is_empty() {
+            return Ok(None);
+        }
+
+        // directory accounts never take over a local or single sign-on one with the same name
+        let other = sqlx::query_scalar!(
+            "SELECT id FROM users WHERE lower(username) = lower($1) AND auth_source <> $2",
+            username, LDAP_SOURCE
+        )
+        .fetch_optional(ctx.pool)
+        .await?;
+        if other.is_some() {
+            return Ok(None);
+        }
+
+        // one limit for the whole exchange, a hanging directory must not hold the login
+        let checked = tokio::time::timeout(self.config.timeout, self.check_password(username, password))
+            .await
+            .map_err(|_| BackendError::Directory(format!("no answer from {} within {:?}", self.config.url, self.config.timeout)))?;
+        let (user, groups) = match checked? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        let role_names = roles_for_groups(&self.config, &groups);
+        if role_names.is_empty() {
+            warn!("Directory user {} is in no group mapped to a role", username);
+            return Ok(None);
+        }
+
+        let roles = sqlx::query!("SELECT id, role_name FROM roles WHERE role_name = ANY($1)", &role_names as &[&str])
+            .fetch_all(ctx.pool)
+            .await?;
+        if let Some(missing) = role_names.iter().find(|name| !roles.iter().any(|role| role.role_name == **name)) {
+            return Err(BackendError::Directory(format!("role {} from LDAP_GROUP_ROLES does not exist", missing)));
+        }
+        let role_ids: Vec<i32> = roles.iter().map(|role| role.id).collect();
+
+        // nor another directory entry that had the name before, e.g. a deleted and recreated account
+        let external_id = external_id(&user, &self.config.id_attribute);
+        let taken = sqlx::query_scalar!(
+            "SELECT id FROM users WHERE lower(username) = lower($1) AND external_id IS DISTINCT FROM $2",
+            username, external_id
+        )
+        .fetch_optional(ctx.pool)
+        .await?;
+        if taken.is_some() {
+            warn!("Directory user {} has the name of another account", username);
+            return Ok(None);
+        }
+
+        // created on the first login, name and role follow the directory afterwards;
+        // the password column holds a value no hash ever matches
+        let mut tx = ctx.pool.begin().await?;
+        let account = sqlx::query!(
+            r#"
+                INSERT INTO users (username, password, voice_attachment, auth_source, external_id)
+                VALUES ($1, '!', false, $2, $3)
+                ON CONFLICT (auth_source, external_id) WHERE external_id IS NOT NULL
+                DO UPDATE SET username = EXCLUDED.username, updated_at = CURRENT_TIMESTAMP
+                RETURNING id, deleted
+            "#,
+            username, LDAP_SOURCE, external_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        // disabled here stays disabled, whatever the directory says
+        if account.deleted == Some(true) {
+            warn!("Directory user {} is disabled", username);
+            return Ok(None);
+        }
+
+        user_roles::replace_roles(&mut tx, account.id, &role_ids).await?;
+        tx.commit().await?;
+
+        info!("Directory user {} signed in with roles {}", username, role_names.join(", "));
+        Ok(Some(account.id))
+    }
+}
@@ -0,0 +1,134 @@
+use log::warn;
+use sqlx::PgPool;
+
+pub struct LoginThrottleConfig {
+    // failed attempts before a username is locked
+    pub max_failures: i32,
+    // failed attempts before a client address is locked, shared workstations need more room
+    pub max_ip_failures: i32,
+    // first lockout, doubled on every following one
+    pub lockout_seconds: i64,
+    pub max_lockout_seconds: i64,
+    // failures older than this are forgotten
+    pub failure_window_seconds: i64,
+}
+
+impl LoginThrottleConfig {
+    // LOGIN_MAX_FAILURES (5), LOGIN_IP_MAX_FAILURES (20), LOGIN_LOCKOUT_SECONDS (60),
+    // LOGIN_MAX_LOCKOUT_SECONDS (3600), LOGIN_FAILURE_WINDOW_MINUTES (15)
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: i64| std::env::var(name)
+            .ok()
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(default);
+
+        LoginThrottleConfig {
+            max_failures: read("LOGIN_MAX_FAILURES", 5) as i32,
+            max_ip_failures: read("LOGIN_IP_MAX_FAILURES", 20) as i32,
+            lockout_seconds: read("LOGIN_LOCKOUT_SECONDS", 60),
+            max_lockout_seconds: read("LOGIN_MAX_LOCKOUT_SECONDS", 3600),
+            failure_window_seconds: read("LOGIN_FAILURE_WINDOW_MINUTES", 15) * 60,
+        }
+    }
+
+    pub fn lockout_for(&self, previous_lockouts: i32) -> i64 {
+        let factor = 2i64.saturating_pow(previous_lockouts.clamp(0, 32) as u32);
+        self.lockout_seconds.saturating_mul(factor).min(self.max_lockout_seconds)
+    }
+}
+
+// usernames are compared case-insensitively so "Admin" and "admin" share a counter
+pub fn user_key(username: &str) -> String {
+    format!("user:{}", username.trim().to_lowercase())
+}
+
+pub fn ip_key(ip_address: &str) -> String {
+    format!("ip:{}", ip_address)
+}
+
+// true when any of the keys is currently locked
+pub async fn is_locked(pool: &PgPool, keys: &[String]) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        "SELECT throttle_key FROM login_throttle WHERE throttle_key = ANY($1) AND locked_until > CURRENT_TIMESTAMP",
+        keys
+    )
+    .fetch_optional(pool)
+    .await
+    .map(|row| row.is_some())
+}
+
+// failures still inside the window, used to decide whether a captcha is needed
+pub async fn recent_failures(pool: &PgPool, config: &LoginThrottleConfig, key: &str) -> Result<i32, sqlx::Error> {
+    sqlx::query!(
+        r#"
+            SELECT failures, lockouts FROM login_throttle
+            WHERE throttle_key = $1 AND last_failure_at > CURRENT_TIMESTAMP - make_interval(secs => $2)
+        "#,
+        key, config.failure_window_seconds as f64
+    )
+    .fetch_optional(pool)
+    .await
+    // a client that has been locked before keeps needing a captcha until it logs in
+    .map(|row| row.map(|r| if r.lockouts > 0 { i32::MAX } else { r.failures }).unwrap_or(0))
+}
+
+// the highest count among the keys, e.g. username and client address, so neither
+// a fresh address nor a fresh username avoids what the other one has collected
+pub async fn most_recent_failures(pool: &PgPool, config: &LoginThrottleConfig, keys: &[String]) -> Result<i32, sqlx::Error> {
+    let mut most = 0;
+    for key in keys {
+        most = most.max(recent_failures(pool, config, key).await?);
+    }
+    Ok(most)
+}
+
+pub async fn record_failure(
+    pool: &PgPool,
+    config: &LoginThrottleConfig,
+    key: &str,
+    threshold: i32
+) -> Result<(), sqlx::Error> {
+    let counter = sqlx::query!(
+        r#"
+            INSERT INTO login_throttle (throttle_key, failures) VALUES ($1, 1)
+            ON CONFLICT (throttle_key) DO UPDATE SET
+                failures = CASE
+                    WHEN login_throttle.last_failure_at < CURRENT_TIMESTAMP - make_interval(secs => $2) THEN 1
+                    ELSE login_throttle.failures + 1
+                END,
+                last_failure_at = CURRENT_TIMESTAMP
+            RETURNING failures, lockouts
+        "#,
+        key, config.failure_window_seconds as f64
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if counter.failures < threshold {
+        return Ok(());
+    }
+
+    let seconds = config.lockout_for(counter.lockouts);
+    sqlx::query!(
+        r#"
+            UPDATE login_throttle
+            SET failures = 0, lockouts = lockouts + 1,
+                locked_until = CURRENT_TIMESTAMP + make_interval(secs => $2)
+            WHERE throttle_key = $1
+        "#,
+        key, seconds as f64
+    )
+    .execute(pool)
+    .await?;
+
+    warn!("Login locked for {} after {} failed attempts, {} seconds (lockout #{})", key, counter.failures, seconds, counter.lockouts + 1);
+    Ok(())
+}
+
+// successful login or admin unlock: forget failures and the backoff level
+pub async fn reset(pool: &PgPool, key: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query!("DELETE FROM login_throttle WHERE throttle_key = $1", key)
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected() > 0)
+}
@@ -1,4 +1,24 @@
 pub mod jwt;
+pub mod auth;
+pub mod api_key;
+pub mod token_revocation;
+pub mod refresh_token;
+pub mod session;
+pub mod client;
+pub mod login_throttle;
+pub mod totp;
+pub mod password_hash;
+pub mod auth_backend;
+pub mod ldap_backend;
+pub mod oidc;
+pub mod password_policy;
+pub mod password_reset;
+pub mod audit;
+pub mod perm;
 pub mod permission_control;
+pub mod permission_cache;
+pub mod user_roles;
+pub mod user_permissions;
+pub mod route_policy;
 pub mod apidoc;
 pub mod dicom;
\ No newline at end of file
@@ -0,0 +1,346 @@
+use std::collections::HashMap;
+
+use openidconnect::core::{
+    CoreClient, CoreGenderClaim, CoreJsonWebKeyType, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm,
+    CoreProviderMetadata, CoreResponseType,
+};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::{
+    AdditionalClaims, AuthenticationFlow, AuthorizationCode, ClientId, ClientSecret, CsrfToken, IdToken, IssuerUrl,
+    Nonce, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+};
+use rocket::http::Status;
+use rocket::serde::json::{serde_json, Value};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::tools::auth_backend::OIDC_SOURCE;
+use crate::tools::user_roles;
+
+// the claims of the ID token that are not standard OIDC claims, e.g. a role claim
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ExtraClaims {
+    #[serde(flatten)]
+    pub claims: HashMap<String, Value>,
+}
+
+impl AdditionalClaims for ExtraClaims {}
+
+pub type OidcIdToken = IdToken<ExtraClaims, CoreGenderClaim, CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreJsonWebKeyType>;
+
+pub struct OidcConfig {
+    pub issuer_url: String,
+    pub client_id: String,
+    // unset for a public client, PKCE protects the code either way
+    pub client_secret: Option<String>,
+    // our callback, registered with the IdP
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    // claim holding roles.id, a number or a numeric string
+    pub role_claim: String,
+    // claim matched against users.username when a subject is seen the first time
+    pub username_claim: String,
+    // role for new users whose token has no role claim, they are refused when unset
+    pub default_role_id: Option<i32>,
+    pub login_ttl_minutes: i64,
+}
+
+impl OidcConfig {
+    // OIDC_ISSUER_URL (turns OIDC on), OIDC_CLIENT_ID, OIDC_CLIENT_SECRET, OIDC_REDIRECT_URL,
+    // OIDC_SCOPES (profile,email), OIDC_ROLE_CLAIM (role_id), OIDC_USERNAME_CLAIM (preferred_username),
+    // OIDC_DEFAULT_ROLE_ID, OIDC_LOGIN_TTL_MINUTES (10)
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.trim().is_empty());
+        let issuer_url = match var("OIDC_ISSUER_URL") {
+            Some(url) => url,
+            None => return Ok(None),
+        };
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} must be set when OIDC_ISSUER_URL is", name));
+
+        Ok(Some(OidcConfig {
+            issuer_url,
+            client_id: required("OIDC_CLIENT_ID")?,
+            client_secret: var("OIDC_CLIENT_SECRET"),
+            redirect_url: required("OIDC_REDIRECT_URL")?,
+            scopes: var("OIDC_SCOPES")
+                .unwrap_or_else(|| "profile,email".to_string())
+                .split(',')
+                .map(|scope| scope.trim().to_string())
+                .filter(|scope| !scope.is_empty())
+                .collect(),
+            role_claim: var("OIDC_ROLE_CLAIM").unwrap_or_else(|| "role_id".to_string()),
+            username_claim: var("OIDC_USERNAME_CLAIM").unwrap_or_else(|| "preferred_username".to_string()),
+            default_role_id: var("OIDC_DEFAULT_ROLE_ID").and_then(|v| v.parse().ok()),
+            login_ttl_minutes: var("OIDC_LOGIN_TTL_MINUTES").and_then(|v| v.parse().ok()).unwrap_or(10),
+        }))
+    }
+}
+
+// who the IdP says signed in
+#[derive(Debug)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+    pub username: Option<String>,
+    pub role_id: Option<i32>,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    Disabled,
+    // unknown or expired state, or the IdP refused the code
+    Rejected(String),
+    // IdP unreachable or answering nonsense
+    Provider(String),
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for OidcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OidcError::Disabled => write!(f, "OIDC login is not configured"),
+            OidcError::Rejected(message) | OidcError::Provider(message) => write!(f, "{}", message),
+            OidcError::Database(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl From<OidcError> for Status {
+    fn from(error: OidcError) -> Self {
+        match error {
+            OidcError::Disabled => Status::NotFound,
+            OidcError::Rejected(_) => Status::Unauthorized,
+            OidcError::Provider(_) => Status::BadGateway,
+            OidcError::Database(_) => Status::InternalServerError,
+        }
+    }
+}
+
+impl From<sqlx::Error> for OidcError {
+    fn from(error: sqlx::Error) -> Self {
+        OidcError::Database(error)
+    }
+}
+
+// the IdP metadata and keys are fetched on first use, so a down IdP does not stop the launch
+pub struct OidcProvider {
+    config: Option<OidcConfig>,
+    client: OnceCell<CoreClient>,
+}
+
+impl OidcProvider {
+    pub fn new(config: Option<OidcConfig>) -> Self {
+        OidcProvider { config, client: OnceCell::new() }
+    }
+
+    pub fn config(&self) -> Option<&OidcConfig> {
+        self.config.as_ref()
+    }
+
+    async fn client(&self) -> Result<&CoreClient, OidcError> {
+        let config = self.config.as_ref().ok_or(OidcError::Disabled)?;
+
+        self.client
+            .get_or_try_init(|| async {
+                let issuer = IssuerUrl::new(config.issuer_url.clone()).map_err(|e| OidcError::Provider(e.to_string()))?;
+                let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
+                    .await
+                    .map_err(|e| OidcError::Provider(format!("discovery failed: {}", e)))?;
+                let redirect_url = RedirectUrl::new(config.redirect_url.clone()).map_err(|e| OidcError::Provider(e.to_string()))?;
+
+                Ok(CoreClient::from_provider_metadata(
+                    metadata,
+                    ClientId::new(config.client_id.clone()),
+                    config.client_secret.clone().map(ClientSecret::new),
+                )
+                .set_redirect_uri(redirect_url))
+            })
+            .await
+    }
+
+    // the IdP URL to send the browser to and the state; state, nonce and PKCE verifier wait in the database
+    pub async fn begin_login(&self, pool: &PgPool) -> Result<(String, String), OidcError> {
+        let client = self.client().await?;
+        let config = self.config.as_ref().ok_or(OidcError::Disabled)?;
+
+        let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+        let mut request = client
+            .authorize_url(AuthenticationFlow::<CoreResponseType>::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .set_pkce_challenge(challenge);
+        for scope in &config.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (url, state, nonce) = request.url();
+
+        sqlx::query!("DELETE FROM oidc_login_states WHERE expires_at < CURRENT_TIMESTAMP")
+            .execute(pool)
+            .await?;
+        sqlx::query!(
+            r#"
+                INSERT INTO oidc_login_states (state, pkce_verifier, nonce, expires_at)
+                VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(mins => $4))
+            "#,
+            state.secret(), verifier.secret(), nonce.secret(), config.login_ttl_minutes as i32
+        )
+        .execute(pool)
+        .await?;
+
+        Ok((url.to_string(), state.secret().to_string()))
+    }
+
+    // trades the code for the ID token and checks signature, audience, expiry and nonce
+    pub async fn finish_login(&self, pool: &PgPool, code: &str, state: &str) -> Result<OidcIdentity, OidcError> {
+        let client = self.client().await?;
+        let config = self.config.as_ref().ok_or(OidcError::Disabled)?;
+
+        // single use, whatever happens next
+        let pending = sqlx::query!(
+            "DELETE FROM oidc_login_states WHERE state = $1 AND expires_at > CURRENT_TIMESTAMP RETURNING pkce_verifier, nonce",
+            state
+        )
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| OidcError::Rejected("unknown or expired login state".to_string()))?;
+
+        let response = client
+            .exchange_code(AuthorizationCode::new(code.to_string()))
+            .set_pkce_verifier(PkceCodeVerifier::new(pending.pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|e| OidcError::Rejected(format!("code exchange failed: {}", e)))?;
+
+        let id_token = response
+            .extra_fields()
+            .id_token()
+            .ok_or_else(|| OidcError::Provider("no ID token in the token response".to_string()))?
+            .to_string()
+            .parse::<OidcIdToken>()
+            .map_err(|e| OidcError::Provider(e.to_string()))?;
+
+        let claims = id_token
+            .claims(&client.id_token_verifier(), &Nonce::new(pending.nonce))
+            .map_err(|e| OidcError::Rejected(format!("invalid ID token: {}", e)))?;
+
+        // standard and extra claims in one object, either kind can be configured
+        let all_claims = serde_json::to_value(claims).map_err(|e| OidcError::Provider(e.to_string()))?;
+        Ok(OidcIdentity {
+            issuer: claims.issuer().to_string(),
+            subject: claims.subject().to_string(),
+            username: all_claims.get(&config.username_claim).and_then(Value::as_str).map(str::to_string),
+            role_id: all_claims.get(&config.role_claim).and_then(role_id_from_claim),
+        })
+    }
+}
+
+// `3` and `"3"` both name roles.id 3; a list uses its first entry
+pub fn role_id_from_claim(value: &Value) -> Option<i32> {
+    match value {
+        Value::Number(number) => number.as_i64().and_then(|id| i32::try_from(id).ok()),
+        Value::String(text) => text.trim().parse().ok(),
+        Value::Array(values) => values.first().and_then(role_id_from_claim),
+        _ => None,
+    }
+}
+
+// the users row of an IdP account: linked before, an existing single sign-on user with the same
+// name, or a new one. local and directory accounts are only linked by an admin (`link_identity`).
+// a role claim in the token replaces the roles of single sign-on users every time
+pub async fn find_or_link_user(pool: &PgPool, config: &OidcConfig, identity: &OidcIdentity) -> Result<Uuid, OidcError> {
+    if let Some(role_id) = identity.role_id {
+        let exists = sqlx::query_scalar!("SELECT id FROM roles WHERE id = $1", role_id)
+            .fetch_optional(pool)
+            .await?;
+        if exists.is_none() {
+            return Err(OidcError::Rejected(format!("role claim {} is no role", role_id)));
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let linked = sqlx::query_scalar!(
+        "UPDATE user_identities SET last_login_at = CURRENT_TIMESTAMP WHERE issuer = $1 AND subject = $2 RETURNING user_id",
+        identity.issuer, identity.subject
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match linked {
+        Some(user_id) => user_id,
+        None => {
+            let username = identity.username.as_deref()
+                .ok_or_else(|| OidcError::Rejected(format!("no {} claim to link the account by", config.username_claim)))?;
+
+            let existing = sqlx::query!("SELECT id, auth_source FROM users WHERE lower(username) = lower($1)", username)
+                .fetch_optional(&mut *tx)
+                .await?;
+
+            let user_id = match existing {
+                // whoever controls the name at the IdP must not take over a local account
+                Some(user) if user.auth_source != OIDC_SOURCE => {
+                    return Err(OidcError::Rejected(format!("{} is a {} account, an admin has to link it", username, user.auth_source)));
+                }
+                Some(user) => user.id,
+                None => {
+                    let role_id = identity.role_id.or(config.default_role_id)
+                        .ok_or_else(|| OidcError::Rejected(format!("no {} claim for the new user", config.role_claim)))?;
+
+                    // the password column holds a value no hash ever matches
+                    let user_id = sqlx::query_scalar!(
+                        r#"
+                            INSERT INTO users (username, password, voice_attachment, auth_source)
+                            VALUES ($1, '!', false, $2)
+                            RETURNING id
+                        "#,
+                        username, OIDC_SOURCE
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    user_roles::replace_roles(&mut tx, user_id, &[role_id]).await?;
+                    user_id
+                }
+            };
+
+            sqlx::query!(
+                r#"
+                    INSERT INTO user_identities (user_id, issuer, subject, last_login_at)
+                    VALUES ($1, $2, $3, CURRENT_TIMESTAMP)
+                "#,
+                user_id, identity.issuer, identity.subject
+            )
+            .execute(&mut *tx)
+            .await?;
+            user_id
+        }
+    };
+
+    // dropping the transaction also drops a link made just now
+    let user = sqlx::query!("SELECT deleted, auth_source FROM users WHERE id = $1", user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if user.deleted == Some(true) {
+        return Err(OidcError::Rejected("the account is disabled".to_string()));
+    }
+
+    // accounts linked by an admin keep the roles they have here
+    if let Some(role_id) = identity.role_id.filter(|_| user.auth_source == OIDC_SOURCE) {
+        user_roles::replace_roles(&mut tx, user_id, &[role_id]).await?;
+    }
+
+    tx.commit().await?;
+    Ok(user_id)
+}
+
+// lets an IdP account sign in as an existing user of any source, the user keeps their roles;
+// false when the subject is linked already
+pub async fn link_identity(pool: &PgPool, user_id: Uuid, issuer: &str, subject: &str) -> Result<bool, sqlx::Error> {
+    let linked = sqlx::query!(
+        "INSERT INTO user_identities (user_id, issuer, subject) VALUES ($1, $2, $3) ON CONFLICT (issuer, subject) DO NOTHING",
+        user_id, issuer, subject
+    )
+    .execute(pool)
+    .await?
+    .rows_affected() > 0;
+    Ok(linked)
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rocket::http::Status;
+use tokio::sync::Semaphore;
+
+#[derive(Debug)]
+pub enum HashError {
+    Failed(String),
+    // no hashing slot freed up within the queue timeout
+    Busy,
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashError::Failed(message) => write!(f, "{}", message),
+            HashError::Busy => write!(f, "password hashing queue is full"),
+        }
+    }
+}
+
+impl From<HashError> for Status {
+    fn from(error: HashError) -> Self {
+        match error {
+            HashError::Busy => Status::ServiceUnavailable,
+            HashError::Failed(_) => Status::InternalServerError,
+        }
+    }
+}
+
+#[derive(Default)]
+struct HashMetrics {
+    queued: AtomicU64,
+    running: AtomicU64,
+    completed: AtomicU64,
+    rejected: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+// counts a job while it is alive, also when the request that started it goes away
+struct Gauge(Arc<HashMetrics>, fn(&HashMetrics) -> &AtomicU64);
+
+impl Gauge {
+    fn enter(metrics: &Arc<HashMetrics>, counter: fn(&HashMetrics) -> &AtomicU64) -> Self {
+        counter(metrics).fetch_add(1, Ordering::Relaxed);
+        Gauge(metrics.clone(), counter)
+    }
+}
+
+impl Drop for Gauge {
+    fn drop(&mut self) {
+        (self.1)(&self.0).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub struct HashMetricsSnapshot {
+    pub max_concurrency: usize,
+    pub running: u64,
+    pub queued: u64,
+    pub completed: u64,
+    // gave up waiting for a slot and answered 503
+    pub rejected: u64,
+    pub average_wait_ms: f64,
+    pub max_wait_ms: f64,
+}
+
+// new hashes are Argon2id; bcrypt hashes from before the switch still verify
+// and are replaced on the next successful login.
+// hashing runs on the blocking pool, at most `max_concurrency` at a time, so a
+// login burst queues here instead of occupying every Rocket worker
+pub struct PasswordHasher {
+    params: Params,
+    dummy: String,
+    max_concurrency: usize,
+    queue_timeout: Duration,
+    permits: Arc<Semaphore>,
+    metrics: Arc<HashMetrics>,
+}
+
+impl PasswordHasher {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<Self, HashError> {
+        let params = Params::new(memory_kib, iterations, parallelism, None).map_err(|e| HashError::Failed(e.to_string()))?;
+        let dummy = hash_blocking(&params, "not-a-real-password")?;
+        let max_concurrency = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(2);
+
+        Ok(PasswordHasher {
+            params,
+            dummy,
+            max_concurrency,
+            queue_timeout: Duration::from_secs(5),
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            metrics: Arc::new(HashMetrics::default()),
+        })
+    }
+
+    pub fn with_limits(mut self, max_concurrency: usize, queue_timeout: Duration) -> Self {
+        self.max_concurrency = max_concurrency.max(1);
+        self.queue_timeout = queue_timeout;
+        self.permits = Arc::new(Semaphore::new(self.max_concurrency));
+        self
+    }
+
+    // ARGON2_MEMORY_KIB (19456), ARGON2_ITERATIONS (2), ARGON2_PARALLELISM (1),
+    // PASSWORD_HASH_CONCURRENCY (number of cpus), PASSWORD_HASH_QUEUE_TIMEOUT_MS (5000)
+    pub fn from_env() -> Result<Self, HashError> {
+        let read = |name: &str| std::env::var(name).ok().and_then(|v| v.parse::<u64>().ok());
+
+        let hasher = PasswordHasher::new(
+            read("ARGON2_MEMORY_KIB").unwrap_or(19456) as u32,
+            read("ARGON2_ITERATIONS").unwrap_or(2) as u32,
+            read("ARGON2_PARALLELISM").unwrap_or(1) as u32,
+        )?;
+
+        let max_concurrency = read("PASSWORD_HASH_CONCURRENCY").map(|n| n as usize).unwrap_or(hasher.max_concurrency);
+        let queue_timeout = Duration::from_millis(read("PASSWORD_HASH_QUEUE_TIMEOUT_MS").unwrap_or(5000));
+        Ok(hasher.with_limits(max_concurrency, queue_timeout))
+    }
+
+    // waits for a slot, then runs `job` on the blocking pool
+    async fn run<T, F>(&self, job: F) -> Result<T, HashError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let waiting = Gauge::enter(&self.metrics, |m| &m.queued);
+        let started = Instant::now();
+        let permit = tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned()).await;
+        drop(waiting);
+
+        let permit = match permit {
+            Ok(Ok(permit)) => permit,
+            _ => {
+                self.metrics.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(HashError::Busy);
+            }
+        };
+
+        let waited = started.elapsed().as_micros() as u64;
+        self.metrics.total_wait_micros.fetch_add(waited, Ordering::Relaxed);
+        self.metrics.max_wait_micros.fetch_max(waited, Ordering::Relaxed);
+
+        let metrics = self.metrics.clone();
+        tokio::task::spawn_blocking(move || {
+            let _running = Gauge::enter(&metrics, |m| &m.running);
+            let result = job();
+            metrics.completed.fetch_add(1, Ordering::Relaxed);
+            drop(permit);
+            result
+        })
+        .await
+        .map_err(|e| HashError::Failed(e.to_string()))
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, HashError> {
+        let params = self.params.clone();
+        let password = password.to_string();
+        self.run(move || hash_blocking(&params, &password)).await?
+    }
+
+    // Ok(false) for a wrong password as well as for a hash that cannot be read
+    pub async fn verify(&self, password: &str, hash: &str) -> Result<bool, HashError> {
+        self.verify_any(password, vec![hash.to_string()]).await
+    }
+
+    // one queue slot for the whole list, e.g. the password history
+    pub async fn verify_any(&self, password: &str, hashes: Vec<String>) -> Result<bool, HashError> {
+        if hashes.is_empty() {
+            return Ok(false);
+        }
+
+        let params = self.params.clone();
+        let password = password.to_string();
+        self.run(move || hashes.iter().any(|hash| verify_blocking(&params, &password, hash))).await
+    }
+
+    // bcrypt, or Argon2 with other parameters than configured now
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        let parsed = match PasswordHash::new(hash) {
+            Ok(parsed) => parsed,
+            Err(_) => return true
+        };
+
+        if parsed.algorithm != Algorithm::Argon2id.ident() {
+            return true;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params) => params.m_cost() != self.params.m_cost()
+                || params.t_cost() != self.params.t_cost()
+                || params.p_cost() != self.params.p_cost(),
+            Err(_) => true
+        }
+    }
+
+    // compared against when the username does not exist, so the response takes as long as a real check
+    pub fn dummy_hash(&self) -> &str {
+        &self.dummy
+    }
+
+    pub fn metrics(&self) -> HashMetricsSnapshot {
+        let completed = self.metrics.completed.load(Ordering::Relaxed);
+        let total_wait = self.metrics.total_wait_micros.load(Ordering::Relaxed);
+
+        HashMetricsSnapshot {
+            max_concurrency: self.max_concurrency,
+            running: self.metrics.running.load(Ordering::Relaxed),
+            queued: self.metrics.queued.load(Ordering::Relaxed),
+            completed,
+            rejected: self.metrics.rejected.load(Ordering::Relaxed),
+            average_wait_ms: if completed == 0 { 0.0 } else { total_wait as f64 / completed as f64 / 1000.0 },
+            max_wait_ms: self.metrics.max_wait_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+fn argon2(params: &Params) -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+}
+
+fn hash_blocking(params: &Params, password: &str) -> Result<String, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2(params)
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| HashError::Failed(e.to_string()))
+}
+
+fn verify_blocking(params: &Params, password: &str, hash: &str) -> bool {
+    if is_bcrypt(hash) {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+
+    match PasswordHash::new(hash) {
+        // the parameters stored in the hash are used, not the configured ones
+        Ok(parsed) => argon2(params).verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(_) => false
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use log::{error, warn};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// always rejected, on top of PASSWORD_BANNED_FILE
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "password1", "password123", "passw0rd", "12345678", "123456789", "1234567890",
+    "qwerty123", "qwertyuiop", "abc12345", "abcd1234", "1qaz2wsx", "iloveyou1", "welcome1",
+    "admin123", "administrator", "letmein1", "hospital1", "doctor123", "changeme1",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharClass {
+    Lowercase,
+    Uppercase,
+    Letter,
+    Digit,
+    Symbol,
+}
+
+impl CharClass {
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "lower" | "lowercase" => Some(CharClass::Lowercase),
+            "upper" | "uppercase" => Some(CharClass::Uppercase),
+            "letter" => Some(CharClass::Letter),
+            "digit" => Some(CharClass::Digit),
+            "symbol" => Some(CharClass::Symbol),
+            _ => None,
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            CharClass::Lowercase => "lowercase letter",
+            CharClass::Uppercase => "uppercase letter",
+            CharClass::Letter => "letter",
+            CharClass::Digit => "digit",
+            CharClass::Symbol => "symbol",
+        }
+    }
+
+    fn matches(&self, c: char, allowed_symbols: &str) -> bool {
+        match self {
+            CharClass::Lowercase => c.is_ascii_lowercase(),
+            CharClass::Uppercase => c.is_ascii_uppercase(),
+            CharClass::Letter => c.is_ascii_alphabetic(),
+            CharClass::Digit => c.is_ascii_digit(),
+            CharClass::Symbol => allowed_symbols.contains(c),
+        }
+    }
+}
+
+// the rule a password broke, shown to the user as is
+#[derive(Debug, PartialEq)]
+pub enum PolicyViolation {
+    TooShort(usize),
+    MissingClass(CharClass),
+    DisallowedCharacter(char),
+    Banned,
+    SameAsUsername,
+    Reused(i64),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::TooShort(min) => write!(f, "password must be at least {} characters long", min),
+            PolicyViolation::MissingClass(class) => write!(f, "password must contain at least one {}", class.describe()),
+            PolicyViolation::DisallowedCharacter(c) => write!(f, "password contains a character that is not allowed: '{}'", c),
+            PolicyViolation::Banned => write!(f, "password is too common"),
+            PolicyViolation::SameAsUsername => write!(f, "password must not be the username"),
+            PolicyViolation::Reused(count) => write!(f, "password must differ from the last {} passwords", count),
+        }
+    }
+}
+
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub required_classes: Vec<CharClass>,
+    // the only non alphanumeric characters a password may contain
+    pub allowed_symbols: String,
+    // lowercase
+    pub banned: HashSet<String>,
+    // earlier passwords that cannot be used again, 0 turns the check off
+    pub history: i64,
+    // days until a password has to be changed, 0 turns expiry off
+    pub max_age_days: i64,
+}
+
+impl PasswordPolicy {
+    // PASSWORD_MIN_LENGTH (8), PASSWORD_REQUIRED_CLASSES (letter,digit), PASSWORD_ALLOWED_SYMBOLS,
+    // PASSWORD_BANNED_FILE, PASSWORD_HISTORY (5), PASSWORD_MAX_AGE_DAYS (0)
+    pub fn from_env() -> Self {
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|v| v.parse::<T>().ok()).unwrap_or(default)
+        }
+
+        let required_classes = read("PASSWORD_REQUIRED_CLASSES", "letter,digit".to_string())
+            .split(',')
+            .filter(|name| !name.trim().is_empty())
+            .filter_map(|name| {
+                let class = CharClass::parse(name);
+                if class.is_none() {
+                    warn!("Ignoring unknown password character class: {}", name);
+                }
+                class
+            })
+            .collect();
+
+        let mut banned: HashSet<String> = COMMON_PASSWORDS.iter().map(|p| p.to_string()).collect();
+        if let Ok(path) = std::env::var("PASSWORD_BANNED_FILE") {
+            match std::fs::read_to_string(&path) {
+                Ok(content) => banned.extend(
+                    content.lines().map(|line| line.trim().to_lowercase()).filter(|line| !line.is_empty())
+                ),
+                Err(e) => error!("Cannot read PASSWORD_BANNED_FILE {}: {}", path, e),
+            }
+        }
+
+        PasswordPolicy {
+            min_length: read("PASSWORD_MIN_LENGTH", 8),
+            required_classes,
+            allowed_symbols: read("PASSWORD_ALLOWED_SYMBOLS", "!@#$%^&*()-_=+[]{};:'\",.<>/?\\|`~".to_string()),
+            banned,
+            history: read("PASSWORD_HISTORY", 5),
+            max_age_days: read("PASSWORD_MAX_AGE_DAYS", 0),
+        }
+    }
+
+    // every rule that only needs the password itself; history is checked against `recent_password_hashes`
+    pub fn check(&self, username: &str, password: &str) -> Result<(), PolicyViolation> {
+        if password.chars().count() < self.min_length {
+            return Err(PolicyViolation::TooShort(self.min_length));
+        }
+
+        if let Some(c) = password.chars().find(|c| !c.is_ascii_alphanumeric() && !self.allowed_symbols.contains(*c)) {
+            return Err(PolicyViolation::DisallowedCharacter(c));
+        }
+
+        for class in &self.required_classes {
+            if !password.chars().any(|c| class.matches(c, &self.allowed_symbols)) {
+                return Err(PolicyViolation::MissingClass(*class));
+            }
+        }
+
+        if password.eq_ignore_ascii_case(username.trim()) {
+            return Err(PolicyViolation::SameAsUsername);
+        }
+
+        if self.banned.contains(&password.to_lowercase()) {
+            return Err(PolicyViolation::Banned);
+        }
+
+        Ok(())
+    }
+}
+
+// the current password and the last `history` ones, empty when the check is off
+pub async fn recent_password_hashes(
+    pool: &PgPool,
+    policy: &PasswordPolicy,
+    user_id: Uuid
+) -> Result<Vec<String>, sqlx::Error> {
+    if policy.history <= 0 {
+        return Ok(Vec::new());
+    }
+
+    sqlx::query_scalar!(
+        r#"
+            SELECT password AS "hash!" FROM users WHERE id = $1
+            UNION ALL
+            (SELECT password_hash FROM password_history WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2)
+        "#,
+        user_id, policy.history
+    )
+    .fetch_all(pool)
+    .await
+}
+
+// stores the new hash, restarts the password age and keeps only the history that is still checked;
+// access tokens issued before are refused from now on, sessions that are kept get new ones on refresh
+pub async fn record_password_change(
+    pool: &PgPool,
+    policy: &PasswordPolicy,
+    user_id: Uuid,
+    password_hash: &str
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+            UPDATE users SET password = $1, password_changed_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP,
+                             token_version = token_version + 1
+            WHERE id = $2
+        "#,
+        password_hash, user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO password_history (user_id, password_hash) VALUES ($1, $2)",
+        user_id, password_hash
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM password_history WHERE user_id = $1 AND id NOT IN (
+                SELECT id FROM password_history WHERE user_id = $1 ORDER BY created_at DESC LIMIT $2
+            )
+        "#,
+        user_id, policy.history.max(0)
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await
+}
+
+pub async fn is_expired(pool: &PgPool, policy: &PasswordPolicy, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    if policy.max_age_days <= 0 {
+        return Ok(false);
+    }
+
+    sqlx::query_scalar!(
+        r#"
+            SELECT password_changed_at < CURRENT_TIMESTAMP - make_interval(days => $2) AS "expired!"
+            FROM users WHERE id = $1
+        "#,
+        user_id, policy.max_age_days as i32
+    )
+    .fetch_optional(pool)
+    .await
+    .map(|expired| expired.unwrap_or(false))
+}
@@ -0,0 +1,92 @@
+use rand::Rng;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::refresh_token::hash_token;
+
+pub struct ResetCodeConfig {
+    pub ttl_minutes: i64,
+}
+
+impl ResetCodeConfig {
+    // PASSWORD_RESET_CODE_TTL_MINUTES (default 30)
+    pub fn from_env() -> Self {
+        ResetCodeConfig {
+            ttl_minutes: std::env::var("PASSWORD_RESET_CODE_TTL_MINUTES")
+                .ok()
+                .and_then(|v| v.parse::<i64>().ok())
+                .unwrap_or(30),
+        }
+    }
+}
+
+// xxxx-xxxx-xxxx, read out over the phone so no easily confused characters
+fn generate_code() -> String {
+    const CHARSET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+    let mut rng = rand::thread_rng();
+    let chars: Vec<char> = (0..12).map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char).collect();
+    chars.chunks(4).map(|group| group.iter().collect::<String>()).collect::<Vec<_>>().join("-")
+}
+
+fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+// a new code replaces any unused one of the same user
+pub async fn issue_code(
+    pool: &PgPool,
+    config: &ResetCodeConfig,
+    user_id: Uuid,
+    issued_by: Uuid
+) -> Result<String, sqlx::Error> {
+    let code = generate_code();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE password_reset_codes SET used_at = CURRENT_TIMESTAMP WHERE user_id = $1 AND used_at IS NULL",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+            INSERT INTO password_reset_codes (user_id, code_hash, issued_by, expires_at)
+            VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(mins => $4))
+        "#,
+        user_id, hash_code(&code), issued_by, config.ttl_minutes as i32
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(code)
+}
+
+// id of the unused, unexpired code of this user, the code is not consumed yet
+pub async fn find_valid_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT id FROM password_reset_codes
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL AND expires_at > CURRENT_TIMESTAMP
+        "#,
+        user_id, hash_code(code)
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// false when a concurrent request used it first
+pub async fn consume_code(pool: &PgPool, code_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        "UPDATE password_reset_codes SET used_at = CURRENT_TIMESTAMP WHERE id = $1 AND used_at IS NULL",
+        code_id
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
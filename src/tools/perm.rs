@@ -0,0 +1,194 @@
+use std::fmt;
+
+use sqlx::PgPool;
+
+// implemented by the marker types below, `Require<perm::NewUser>` checks NewUser::PERMISSION
+pub trait RequiredPermission: Send + Sync + 'static {
+    const PERMISSION: Permission;
+}
+
+// what the admin UI groups the permission checkboxes by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    Users,
+    Roles,
+    Examination,
+    Reports,
+    Settings,
+    System,
+}
+
+impl Category {
+    // permissions.category
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Users => "users",
+            Category::Roles => "roles",
+            Category::Examination => "examination",
+            Category::Reports => "reports",
+            Category::Settings => "settings",
+            Category::System => "system",
+        }
+    }
+}
+
+// one variant per row of `permissions`, plus a marker type of the same name;
+// `formerly` lists names the row had before, it is renamed at startup and keeps its grants
+macro_rules! permissions {
+    ($($variant:ident => $name:literal $(formerly $($old:literal),+)?, $category:ident, $description:literal;)*) => {
+        // AddPermission, DeletedPermission are the row names
+        #[allow(clippy::enum_variant_names)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Permission {
+            $($variant),*
+        }
+
+        impl Permission {
+            pub const ALL: &'static [Permission] = &[$(Permission::$variant),*];
+
+            // permissions.permissions_name
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Permission::$variant => $name),*
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Permission> {
+                match name {
+                    $($name => Some(Permission::$variant),)*
+                    _ => None,
+                }
+            }
+
+            pub fn category(self) -> Category {
+                match self {
+                    $(Permission::$variant => Category::$category),*
+                }
+            }
+
+            pub fn description(self) -> &'static str {
+                match self {
+                    $(Permission::$variant => $description),*
+                }
+            }
+
+            pub fn former_names(self) -> &'static [&'static str] {
+                match self {
+                    $(Permission::$variant => &[$($($old),+)?]),*
+                }
+            }
+        }
+
+        $(
+            // only used as a type; several belong to front end pages no route checks
+            #[allow(dead_code)]
+            pub struct $variant;
+
+            impl RequiredPermission for $variant {
+                const PERMISSION: Permission = Permission::$variant;
+            }
+        )*
+    };
+}
+
+permissions! {
+    ViewUsers => "viewUsers", Users, "List users and their roles";
+    NewUser => "newUser", Users, "Create user accounts";
+    AssignRoles => "assignRoles", Users, "Give users roles and take them away";
+    ManageUserPermissions => "manageUserPermissions", Users, "Grant or deny single permissions to a user";
+    LinkIdentity => "linkIdentity", Users, "Link single sign-on accounts to existing users";
+    DeletedUser => "deletedUser", Users, "Disable user accounts";
+    ReactivateUser => "reactivateUser", Users, "Enable disabled user accounts again";
+    EditPassword => "editPassword", Users, "Set the password of another user";
+    IssueResetCode => "issueResetCode", Users, "Issue one-time password reset codes";
+    UnlockUser => "unlockUser", Users, "Unlock accounts locked after failed logins";
+    TerminateSession => "terminateSession", Users, "List and sign out the sessions of other users";
+    ViewPermissions => "viewPermissions", Roles, "List permissions and what each role holds";
+    AddPermission => "addPermission", Roles, "Grant permissions to a role";
+    DeletedPermission => "deletedPermission", Roles, "Take permissions away from a role";
+    CreateRole => "createRole", Roles, "Create roles";
+    RenameRole => "renameRole", Roles, "Rename roles";
+    DeleteRole => "deleteRole", Roles, "Delete roles and move their users to another role";
+    SetRoleParents => "setRoleParents", Roles, "Choose the roles a role inherits permissions from";
+    MfaPolicy => "mfaPolicy", Roles, "Require a second factor for the members of a role";
+    Evas => "evas", Examination, "Open EVAS";
+    Examinations => "examinations" formerly "examinatios", Examination, "Open the examination list";
+    Patients => "patients" formerly "Patiens", Examination, "Open patient records";
+    DoctorList => "doctorList" formerly "docterLisst", Examination, "Open the doctor list";
+    CheckRecordForm => "checkRecordForm", Examination, "Fill in the check record form";
+    Screenshot => "screenshot", Examination, "Take screenshots during an examination";
+    CleansingLevel => "cleansingLevel", Examination, "Record the cleansing level";
+    InsertionLevel => "insertionLevel" formerly "insertion level", Examination, "Record the insertion level";
+    Complication => "complication", Examination, "Record complications";
+    Indication => "indication", Examination, "Record indications";
+    CloTest => "CloTest", Examination, "Record CLO test results";
+    EditReport => "editReport", Reports, "Edit reports";
+    ChineseReport => "chineseReport", Reports, "Write reports in Chinese";
+    EnglishReport => "EnglishReport", Reports, "Write reports in English";
+    Setting => "setting", Settings, "Open the settings page";
+    Customize => "customize", Settings, "Customize the pages and forms";
+    MedicationSetting => "medicationSetting", Settings, "Manage the medication list";
+    WorkListSettings => "workListSettings", Settings, "Configure and sync the DICOM worklist";
+    ViewMetrics => "viewMetrics", System, "View password hashing metrics";
+    ManageServiceAccounts => "manageServiceAccounts", System, "Manage service accounts and their API keys";
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+// run at startup, after the migrations: renames rows listed under `formerly`, adds the
+// permissions the table is missing and updates descriptions and categories.
+// Returns the rows no permission here knows about, they are left alone
+pub async fn sync_permission_rows(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut renamed = 0;
+
+    for permission in Permission::ALL {
+        let former: Vec<String> = permission.former_names().iter().map(|name| name.to_string()).collect();
+        if !former.is_empty() {
+            renamed += sqlx::query!(
+                r#"
+                    UPDATE permissions SET permissions_name = $1
+                    WHERE permissions_name = ANY($2)
+                      AND NOT EXISTS (SELECT 1 FROM permissions WHERE permissions_name = $1)
+                "#,
+                permission.name(), &former
+            )
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+
+        sqlx::query!(
+            r#"
+                INSERT INTO permissions (permissions_name, description, category) VALUES ($1, $2, $3)
+                ON CONFLICT (permissions_name) DO UPDATE
+                SET description = EXCLUDED.description, category = EXCLUDED.category
+            "#,
+            permission.name(), permission.description(), permission.category().name()
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // roles hold the renamed rows by id, only the names cached with them changed
+    if renamed > 0 {
+        sqlx::query!("UPDATE roles SET permissions_version = permissions_version + 1")
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let known: Vec<String> = Permission::ALL.iter().map(|p| p.name().to_string()).collect();
+    let unknown = sqlx::query_scalar!(
+        "SELECT permissions_name FROM permissions WHERE permissions_name <> ALL($1) ORDER BY permissions_name",
+        &known
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(unknown)
+}
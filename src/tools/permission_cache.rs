@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use sqlx::{PgExecutor, PgPool};
+use uuid::Uuid;
+
+use crate::tools::auth::AuthenticatedUser;
+use crate::tools::jwt::{RoleVersion, TokenGrant};
+
+pub struct RolePermissions {
+    pub role_name: String,
+    // roles.permissions_version the set was loaded at
+    pub version: i32,
+    pub permissions: HashSet<String>,
+}
+
+// what a single user was granted or denied on top of their roles, expired ones left out
+pub struct UserOverrides {
+    // users.token_version they were loaded at, every change of them bumps it
+    pub token_version: i32,
+    pub grants: HashSet<String>,
+    pub denies: HashSet<String>,
+    // when the first of them expires
+    reload_at: Option<Instant>,
+}
+
+// role id -> permission set, so the permission guard does not join on every request; each
+// request only reads the roles' current versions, so a change made by another instance
+// applies at once. user overrides are kept by user id and follow the token version, which
+// the auth guard compares with the database on every request
+#[derive(Default)]
+pub struct PermissionCache {
+    roles: RwLock<HashMap<i32, Arc<RolePermissions>>>,
+    users: RwLock<HashMap<Uuid, Arc<UserOverrides>>>,
+}
+
+impl PermissionCache {
+    pub fn new() -> Self {
+        PermissionCache::default()
+    }
+
+    // cached set unless it is older than `min_version`
+    pub async fn role(&self, pool: &PgPool, role_id: i32, min_version: i32) -> Result<Option<Arc<RolePermissions>>, sqlx::Error> {
+        if let Some(cached) = self.roles.read().unwrap().get(&role_id) {
+            if cached.version >= min_version {
+                return Ok(Some(cached.clone()));
+            }
+        }
+
+        let role = match sqlx::query!("SELECT role_name, permissions_version FROM roles WHERE id = $1", role_id)
+            .fetch_optional(pool)
+            .await?
+        {
+            Some(role) => role,
+            None => return Ok(None),
+        };
+
+        // granted to the role itself or to any of its ancestors
+        let permissions = sqlx::query_scalar!(
+            r#"
+                WITH RECURSIVE lineage(id) AS (
+                    SELECT $1::int
+                    UNION
+                    SELECT rpa.parent_id FROM role_parents rpa JOIN lineage l ON rpa.role_id = l.id
+                )
+                SELECT DISTINCT p.permissions_name FROM lineage l
+                JOIN role_permissions rp ON rp.role_id = l.id
+                JOIN permissions p ON rp.permissions_id = p.id
+            "#,
+            role_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let loaded = Arc::new(RolePermissions {
+            role_name: role.role_name,
+            version: role.permissions_version,
+            permissions: permissions.into_iter().collect(),
+        });
+        // a concurrent load may have stored a newer set meanwhile
+        let mut roles = self.roles.write().unwrap();
+        match roles.get(&role_id) {
+            Some(cached) if cached.version >= loaded.version => Ok(Some(cached.clone())),
+            _ => {
+                roles.insert(role_id, loaded.clone());
+                Ok(Some(loaded))
+            }
+        }
+    }
+
+    // the roles a token names at their current version, those deleted since are left out
+    pub async fn roles(&self, pool: &PgPool, roles: &[RoleVersion]) -> Result<Vec<Arc<RolePermissions>>, sqlx::Error> {
+        let ids: Vec<i32> = roles.iter().map(|grant| grant.id).collect();
+        let current = sqlx::query!(
+            "SELECT id, permissions_version FROM roles WHERE id = ANY($1) ORDER BY id",
+            &ids
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut loaded = Vec::with_capacity(current.len());
+        for row in current {
+            if let Some(role) = self.role(pool, row.id, row.permissions_version).await? {
+                loaded.push(role);
+            }
+        }
+        Ok(loaded)
+    }
+
+    // cached overrides unless they are older than `min_token_version` or one of them expired
+    pub async fn user_overrides(&self, pool: &PgPool, user_id: Uuid, min_token_version: i32) -> Result<Arc<UserOverrides>, sqlx::Error> {
+        if let Some(cached) = self.users.read().unwrap().get(&user_id) {
+            if cached.token_version >= min_token_version && cached.reload_at.is_none_or(|at| at > Instant::now()) {
+                return Ok(cached.clone());
+            }
+        }
+
+        // the remaining lifetime is worked out by the database, which also decides what has expired
+        let rows = sqlx::query!(
+            r#"
+                SELECT p.permissions_name, up.granted,
+                       EXTRACT(EPOCH FROM up.expires_at - CURRENT_TIMESTAMP)::float8 AS expires_in
+                FROM user_permissions up
+                JOIN permissions p ON up.permissions_id = p.id
+                WHERE up.user_id = $1 AND (up.expires_at IS NULL OR up.expires_at > CURRENT_TIMESTAMP)
+            "#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+
+        let mut loaded = UserOverrides {
+            token_version: min_token_version,
+            grants: HashSet::new(),
+            denies: HashSet::new(),
+            reload_at: rows
+                .iter()
+                .filter_map(|row| row.expires_in)
+                .reduce(f64::min)
+                .map(|seconds| Instant::now() + Duration::from_secs_f64(seconds.max(0.0))),
+        };
+        for row in rows {
+            if row.granted {
+                loaded.grants.insert(row.permissions_name);
+            } else {
+                loaded.denies.insert(row.permissions_name);
+            }
+        }
+
+        let loaded = Arc::new(loaded);
+        self.users.write().unwrap().insert(user_id, loaded.clone());
+        Ok(loaded)
+    }
+
+    // the roles the user's token names and what they may do with their overrides applied
+    pub async fn user_permissions(&self, pool: &PgPool, user: &AuthenticatedUser) -> Result<(Vec<Arc<RolePermissions>>, HashSet<String>), sqlx::Error> {
+        let roles = self.roles(pool, &user.roles).await?;
+        let overrides = self.user_overrides(pool, user.user_id, user.claims.tver).await?;
+        let permissions = effective(&roles, &overrides);
+        Ok((roles, permissions))
+    }
+
+    pub fn invalidate(&self, role_id: i32) {
+        self.roles.write().unwrap().remove(&role_id);
+    }
+}
+
+// call after changing what a role may do
+pub async fn bump_role_version(pool: &PgPool, cache: &PermissionCache, role_id: i32) -> Result<(), sqlx::Error> {
+    for id in bump_role_versions(pool, role_id).await? {
+        cache.invalidate(id);
+    }
+    Ok(())
+}
+
+// bumps the role and every role inheriting from it, returns their ids; inside a
+// transaction, invalidate the cache entries once it is committed
+pub async fn bump_role_versions<'e>(executor: impl PgExecutor<'e>, role_id: i32) -> Result<Vec<i32>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            WITH RECURSIVE affected(id) AS (
+                SELECT $1::int
+                UNION
+                SELECT rpa.role_id FROM role_parents rpa JOIN affected a ON rpa.parent_id = a.id
+            )
+            UPDATE roles SET permissions_version = permissions_version + 1
+            WHERE id IN (SELECT id FROM affected)
+            RETURNING id
+        "#,
+        role_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+// what goes into a new access token for the user; the version is read first, so a role
+// change in between yields a token that is already retired rather than a stale one
+pub async fn token_grant(pool: &PgPool, user_id: Uuid) -> Result<TokenGrant, sqlx::Error> {
+    let token_version = sqlx::query_scalar!("SELECT token_version FROM users WHERE id = $1", user_id)
+        .fetch_one(pool)
+        .await?;
+    let roles = sqlx::query_as!(
+        RoleVersion,
+        r#"
+            SELECT r.id, r.permissions_version AS ver FROM user_roles ur
+            JOIN roles r ON ur.role_id = r.id
+            WHERE ur.user_id = $1
+            ORDER BY r.id
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(TokenGrant { roles, token_version })
+}
+
+// everything the roles allow or the user was granted, except what the user was denied
+pub fn effective(roles: &[Arc<RolePermissions>], overrides: &UserOverrides) -> HashSet<String> {
+    roles
+        .iter()
+        .flat_map(|role| role.permissions.iter())
+        .chain(overrides.grants.iter())
+        .filter(|name| !overrides.denies.contains(*name))
+        .cloned()
+        .collect()
+}
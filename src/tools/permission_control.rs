@@ -4,15 +4,35 @@ use rocket::outcome::Outcome;
 use rocket::State;
 use sqlx::PgPool;
 use std::collections::HashSet;
+use std::marker::PhantomData;
+use log::{error, warn};
 
 
-use crate::tools::jwt::validate_jwt;
+use crate::tools::api_key::{authenticate_key, presented_key};
+use crate::tools::auth::AuthenticatedUser;
+use crate::tools::perm::{Permission, RequiredPermission};
+use crate::tools::permission_cache::PermissionCache;
 
+// a signed in user with the permissions of all their roles and their own grants and
+// denies applied, or a service account
+// with the permissions its API key was issued with (user_id is then the account name)
 pub struct UserWithPermissions {
     pub user_id: String,
     pub permissions: HashSet<String>
 }
 
+impl UserWithPermissions {
+    pub fn has(&self, permission: Permission) -> bool {
+        self.permissions.contains(permission.name())
+    }
+}
+
+// `_permitted: Require<perm::NewUser>` in a handler: 401 without a valid token or API key, 403 without the permission
+pub struct Require<P: RequiredPermission> {
+    pub user: UserWithPermissions,
+    permission: PhantomData<P>,
+}
+
 #[derive(Debug)]
 pub enum PermissionError {
     Unauthorized,
@@ -25,46 +45,59 @@ impl<'r> FromRequest<'r> for UserWithPermissions {
 
     async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
         let pool = request.guard::<&State<PgPool>>().await.unwrap();
-        let auth_header = request.headers().get_one("Authorization");
 
-        let token = match auth_header {
-            Some(auth_header) => auth_header.split_whitespace().nth(1),
-            None => return Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized)),
-        };
+        if let Some(key) = presented_key(request) {
+            return match authenticate_key(pool.inner(), key).await {
+                Ok(Some(principal)) => Outcome::Success(UserWithPermissions {
+                    user_id: principal.account_name,
+                    permissions: principal.permissions
+                }),
+                Ok(None) => Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized)),
+                Err(e) => {
+                    error!("API key lookup failed: {:?}", e);
+                    Outcome::Error((Status::InternalServerError, PermissionError::Unauthorized))
+                }
+            };
+        }
 
-        let token = match token {
-            Some(token) => token,
-            None => return Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized))
+        let auth_user = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(auth_user) => auth_user,
+            Outcome::Error((status, _)) => return Outcome::Error((status, PermissionError::Unauthorized)),
+            Outcome::Forward(_) => return Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized))
         };
 
-        let token_data = match validate_jwt(token) {
-            Ok(c) => c,
-            Err(_) => return Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized))
+        let cache = request.guard::<&State<PermissionCache>>().await.unwrap();
+        let permissions = match cache.user_permissions(pool.inner(), &auth_user).await {
+            Ok((_, permissions)) => permissions,
+            Err(e) => {
+                error!("Permission lookup failed: {:?}", e);
+                return Outcome::Error((Status::InternalServerError, PermissionError::Unauthorized));
+            }
         };
 
-        let username = token_data.claims.sub;
-        let user = sqlx::query!(
-            r#"
-                SELECT p.permissions_name FROM users u
-                JOIN role_permissions rp ON u.role_id = rp.role_id
-                JOIN permissions p ON rp.permissions_id = p.id
-                WHERE u.username = $1
-            "#,
-            username
-        )
-        .fetch_all(pool.inner())
-        .await;
-
-        let user = match user{
-            Ok(permissions) => permissions,
-            Err(_) => return Outcome::Error((Status::Unauthorized, PermissionError::Unauthorized))
+        Outcome::Success(UserWithPermissions {
+            user_id: auth_user.username,
+            permissions
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, P: RequiredPermission> FromRequest<'r> for Require<P> {
+    type Error = PermissionError;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let user = match request.guard::<UserWithPermissions>().await {
+            Outcome::Success(user) => user,
+            Outcome::Error(e) => return Outcome::Error(e),
+            Outcome::Forward(status) => return Outcome::Forward(status)
         };
 
-        let permissions_set: HashSet<String> = user.into_iter().map(|record| record.permissions_name).collect();
+        if !user.has(P::PERMISSION) {
+            warn!("{} lacks permission {} for {} {}", user.user_id, P::PERMISSION, request.method(), request.uri());
+            return Outcome::Error((Status::Forbidden, PermissionError::Forbidden));
+        }
 
-        Outcome::Success(UserWithPermissions {
-            user_id: username,
-            permissions: permissions_set
-        })
+        Outcome::Success(Require { user, permission: PhantomData })
     }
 }
@@ -0,0 +1,109 @@
+use chrono::Duration;
+use log::warn;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::session::touch_session;
+
+pub enum RefreshOutcome {
+    Rotated { user_id: Uuid, username: String, session_id: Uuid, token: String },
+    // an already used token came back, the whole family is now revoked
+    Reused,
+    Invalid,
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(64)
+        .map(char::from)
+        .collect()
+}
+
+// only the hash is stored, a leaked table cannot be replayed
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// start a new family, or continue one when rotating
+pub async fn issue_refresh_token(
+    pool: &PgPool,
+    user_id: Uuid,
+    session_id: Uuid,
+    family_id: Option<Uuid>,
+    ttl: Duration
+) -> Result<String, sqlx::Error> {
+    let token = generate_token();
+    let family_id = family_id.unwrap_or_else(Uuid::new_v4);
+
+    sqlx::query!(
+        r#"
+            INSERT INTO refresh_tokens (user_id, session_id, family_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4, CURRENT_TIMESTAMP + make_interval(secs => $5))
+        "#,
+        user_id, session_id, family_id, hash_token(&token), ttl.num_seconds() as f64
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(token)
+}
+
+pub async fn rotate_refresh_token(
+    pool: &PgPool,
+    token: &str,
+    ttl: Duration
+) -> Result<RefreshOutcome, sqlx::Error> {
+    let token_hash = hash_token(token);
+
+    // mark as used first, so two concurrent refreshes cannot both win
+    let current = sqlx::query!(
+        r#"
+            UPDATE refresh_tokens rt SET used_at = CURRENT_TIMESTAMP
+            FROM users u, sessions s
+            WHERE rt.token_hash = $1 AND rt.user_id = u.id AND rt.session_id = s.id
+              AND rt.used_at IS NULL AND rt.revoked_at IS NULL AND s.revoked_at IS NULL
+              AND u.deleted IS NOT TRUE
+              AND rt.expires_at > CURRENT_TIMESTAMP
+            RETURNING rt.user_id, rt.family_id, s.id AS session_id, u.username
+        "#,
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    if let Some(current) = current {
+        let token = issue_refresh_token(pool, current.user_id, current.session_id, Some(current.family_id), ttl).await?;
+        touch_session(pool, current.session_id).await?;
+        return Ok(RefreshOutcome::Rotated { user_id: current.user_id, username: current.username, session_id: current.session_id, token });
+    }
+
+    let reused = sqlx::query!(
+        "SELECT family_id FROM refresh_tokens WHERE token_hash = $1 AND used_at IS NOT NULL",
+        token_hash
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match reused {
+        Some(reused) => {
+            warn!("Refresh token reuse detected, revoking family {}", reused.family_id);
+            revoke_refresh_family(pool, reused.family_id).await?;
+            Ok(RefreshOutcome::Reused)
+        }
+        None => Ok(RefreshOutcome::Invalid)
+    }
+}
+
+pub async fn revoke_refresh_family(pool: &PgPool, family_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE family_id = $1 AND revoked_at IS NULL",
+        family_id
+    )
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
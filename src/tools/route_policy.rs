@@ -0,0 +1,179 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use log::{error, warn};
+use rocket::fairing::AdHoc;
+use rocket::http::{Method, Status};
+use rocket::outcome::Outcome;
+use rocket::route::{self, Handler};
+use rocket::{Data, Request, Route};
+
+use crate::tools::perm::Permission;
+use crate::tools::permission_control::UserWithPermissions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    // the handler does its own checks (login steps, challenge tokens) or there is nothing to protect
+    Public,
+    // any valid access token or API key, for the caller's own data
+    Authenticated,
+    Permission(Permission),
+}
+
+use Access::{Authenticated, Public};
+
+// every route the server mounts through `protect`, by method and path below the mount point
+const POLICY: &[(Method, &str, Access)] = &[
+    (Method::Get, "/apidoc", Public),
+    (Method::Get, "/user/captcha", Public),
+    (Method::Post, "/user/login", Public),
+    (Method::Post, "/user/login/totp", Public),
+    (Method::Post, "/user/login/password", Public),
+    (Method::Get, "/user/oidc/login", Public),
+    (Method::Get, "/user/oidc/callback", Public),
+    (Method::Post, "/user/oidc/link", Access::Permission(Permission::LinkIdentity)),
+    (Method::Post, "/user/refresh", Public),
+    (Method::Post, "/user/resetPassword", Public),
+    // also reached with an enrollment challenge instead of an access token
+    (Method::Post, "/user/totp/setup", Public),
+    (Method::Post, "/user/totp/confirm", Public),
+    (Method::Post, "/user/logout", Authenticated),
+    (Method::Get, "/user/userinfo", Authenticated),
+    (Method::Post, "/user/me/password", Authenticated),
+    (Method::Post, "/user/totp/disable", Authenticated),
+    (Method::Get, "/user/sessions", Authenticated),
+    (Method::Post, "/user/sessions/revoke", Authenticated),
+    (Method::Post, "/user/sessions/revokeAll", Authenticated),
+    (Method::Get, "/role", Authenticated),
+    (Method::Get, "/user", Access::Permission(Permission::ViewUsers)),
+    (Method::Post, "/user/register", Access::Permission(Permission::NewUser)),
+    (Method::Post, "/user/softDeleted", Access::Permission(Permission::DeletedUser)),
+    (Method::Post, "/user/reactivate", Access::Permission(Permission::ReactivateUser)),
+    (Method::Post, "/user/role/assign", Access::Permission(Permission::AssignRoles)),
+    (Method::Post, "/user/role/remove", Access::Permission(Permission::AssignRoles)),
+    (Method::Post, "/user/editpassword", Access::Permission(Permission::EditPassword)),
+    (Method::Post, "/user/resetCode", Access::Permission(Permission::IssueResetCode)),
+    (Method::Post, "/user/unlock", Access::Permission(Permission::UnlockUser)),
+    (Method::Get, "/user/sessions/<uid>", Access::Permission(Permission::TerminateSession)),
+    (Method::Post, "/user/sessions/terminate", Access::Permission(Permission::TerminateSession)),
+    (Method::Get, "/permissions", Access::Permission(Permission::ViewPermissions)),
+    (Method::Get, "/permission/userRolePermission", Access::Permission(Permission::ViewPermissions)),
+    (Method::Get, "/permission/userPermissions", Access::Permission(Permission::ViewPermissions)),
+    (Method::Post, "/permission/setUserPermission", Access::Permission(Permission::ManageUserPermissions)),
+    (Method::Post, "/permission/deleteUserPermission", Access::Permission(Permission::ManageUserPermissions)),
+    (Method::Post, "/permission/addRolePermission", Access::Permission(Permission::AddPermission)),
+    (Method::Post, "/permission/deleteRolePermission", Access::Permission(Permission::DeletedPermission)),
+    (Method::Post, "/role", Access::Permission(Permission::CreateRole)),
+    (Method::Post, "/role/rename", Access::Permission(Permission::RenameRole)),
+    (Method::Post, "/role/delete", Access::Permission(Permission::DeleteRole)),
+    (Method::Post, "/role/parents", Access::Permission(Permission::SetRoleParents)),
+    (Method::Post, "/role/mfaPolicy", Access::Permission(Permission::MfaPolicy)),
+    (Method::Post, "/worklist_setting", Access::Permission(Permission::WorkListSettings)),
+    (Method::Post, "/sync_worklist", Access::Permission(Permission::WorkListSettings)),
+    (Method::Get, "/metrics/passwordHashing", Access::Permission(Permission::ViewMetrics)),
+    (Method::Post, "/serviceAccount", Access::Permission(Permission::ManageServiceAccounts)),
+    (Method::Get, "/serviceAccount", Access::Permission(Permission::ManageServiceAccounts)),
+    (Method::Post, "/serviceAccount/key", Access::Permission(Permission::ManageServiceAccounts)),
+    (Method::Post, "/serviceAccount/key/revoke", Access::Permission(Permission::ManageServiceAccounts)),
+];
+
+pub fn access_for(route: &Route) -> Option<Access> {
+    let path = route.uri.unmounted_origin.path().as_str();
+    POLICY
+        .iter()
+        .find(|(method, policy_path, _)| *method == route.method && *policy_path == path)
+        .map(|(_, _, access)| *access)
+}
+
+#[derive(Clone)]
+struct Protected {
+    access: Access,
+    handler: Box<dyn Handler>,
+}
+
+#[rocket::async_trait]
+impl Handler for Protected {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let required = match self.access {
+            Public => return self.handler.handle(request, data).await,
+            Authenticated => None,
+            Access::Permission(permission) => Some(permission),
+        };
+
+        match request.guard::<UserWithPermissions>().await {
+            Outcome::Success(user) => {
+                if let Some(permission) = required.filter(|permission| !user.has(*permission)) {
+                    warn!("{} lacks permission {} for {} {}", user.user_id, permission, request.method(), request.uri());
+                    return route::Outcome::Error(Status::Forbidden);
+                }
+            }
+            Outcome::Error((status, _)) => return route::Outcome::Error(status),
+            Outcome::Forward(status) => return route::Outcome::Error(status),
+        }
+
+        self.handler.handle(request, data).await
+    }
+}
+
+// remembers which routes went through `protect`, so the launch check does not go by anything
+// a route could carry by accident; routes mounted by fairings (the CORS catcher) are not ours
+// to check and only matter if they are listed here and need a login or permission
+#[derive(Clone, Default)]
+pub struct ProtectedRoutes {
+    wrapped: Arc<Mutex<HashSet<(Method, String)>>>,
+}
+
+fn policy_key(route: &Route) -> (Method, String) {
+    (route.method, route.uri.unmounted_origin.path().to_string())
+}
+
+impl ProtectedRoutes {
+    // checks the policy before the handler runs; routes without an entry are left alone
+    // here and stop the launch in `fairing`
+    pub fn protect(&self, routes: Vec<Route>) -> Vec<Route> {
+        let mut wrapped = self.wrapped.lock().unwrap();
+        routes
+            .into_iter()
+            .map(|mut route| {
+                wrapped.insert(policy_key(&route));
+                if let Some(access) = access_for(&route) {
+                    route.handler = Box::new(Protected { access, handler: route.handler });
+                }
+                route
+            })
+            .collect()
+    }
+
+    pub fn fairing(&self) -> AdHoc {
+        let wrapped = self.wrapped.clone();
+        AdHoc::try_on_ignite("Route policy", |rocket| async move {
+            let mut missing = Vec::new();
+            let mut unprotected = Vec::new();
+            {
+                let wrapped = wrapped.lock().unwrap();
+                for route in rocket.routes() {
+                    let is_wrapped = wrapped.contains(&policy_key(route));
+                    match access_for(route) {
+                        None if is_wrapped => missing.push(format!("{} {}", route.method, route.uri)),
+                        // mounted without `protect`, the handler would have no check at all
+                        Some(access) if access != Public && !is_wrapped => {
+                            unprotected.push(format!("{} {}", route.method, route.uri))
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            if missing.is_empty() && unprotected.is_empty() {
+                return Ok(rocket);
+            }
+            if !missing.is_empty() {
+                error!("Routes without an entry in the route policy: {}", missing.join(", "));
+            }
+            if !unprotected.is_empty() {
+                error!("Routes mounted without ProtectedRoutes::protect: {}", unprotected.join(", "));
+            }
+            Err(rocket)
+        })
+    }
+}
@@ -0,0 +1,87 @@
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::tools::client::ClientInfo;
+
+pub async fn create_session(pool: &PgPool, user_id: Uuid, client: &ClientInfo) -> Result<Uuid, sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO sessions (user_id, ip_address, user_agent) VALUES ($1, $2, $3) RETURNING id",
+        user_id, client.ip_address, client.user_agent
+    )
+    .fetch_one(pool)
+    .await
+    .map(|row| row.id)
+}
+
+// true while the session has not been revoked; also bumps last_seen_at (at most once a minute)
+pub async fn touch_session(pool: &PgPool, session_id: Uuid) -> Result<bool, sqlx::Error> {
+    let session = sqlx::query!(
+        "SELECT revoked_at FROM sessions WHERE id = $1",
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    match session {
+        Some(session) if session.revoked_at.is_none() => {
+            sqlx::query!(
+                r#"
+                    UPDATE sessions SET last_seen_at = CURRENT_TIMESTAMP
+                    WHERE id = $1 AND last_seen_at < CURRENT_TIMESTAMP - INTERVAL '1 minute'
+                "#,
+                session_id
+            )
+            .execute(pool)
+            .await?;
+            Ok(true)
+        }
+        _ => Ok(false)
+    }
+}
+
+// revoking a session also kills its refresh tokens, so it cannot be extended
+pub async fn revoke_session(pool: &PgPool, session_id: Uuid) -> Result<bool, sqlx::Error> {
+    let revoked = sqlx::query!(
+        "UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP WHERE id = $1 AND revoked_at IS NULL",
+        session_id
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        "UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP WHERE session_id = $1 AND revoked_at IS NULL",
+        session_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(revoked > 0)
+}
+
+// sign out everywhere, optionally keeping the session making the request
+pub async fn revoke_user_sessions(pool: &PgPool, user_id: Uuid, except: Option<Uuid>) -> Result<u64, sqlx::Error> {
+    let revoked = sqlx::query!(
+        r#"
+            UPDATE sessions SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL AND ($2::uuid IS NULL OR id <> $2)
+        "#,
+        user_id, except
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        r#"
+            UPDATE refresh_tokens SET revoked_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND revoked_at IS NULL
+              AND ($2::uuid IS NULL OR session_id IS DISTINCT FROM $2)
+        "#,
+        user_id, except
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(revoked)
+}
@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+use log::{error, info};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// `exp` is the token's own expiry, the row can be pruned after it
+pub async fn revoke_token(pool: &PgPool, jti: Uuid, exp: usize) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO revoked_tokens (jti, expires_at)
+            VALUES ($1, to_timestamp($2)::timestamp)
+            ON CONFLICT (jti) DO NOTHING
+        "#,
+        jti, exp as f64
+    )
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+pub async fn is_revoked(pool: &PgPool, jti: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query!("SELECT jti FROM revoked_tokens WHERE jti = $1", jti)
+        .fetch_optional(pool)
+        .await
+        .map(|row| row.is_some())
+}
+
+// a revoked token only has to be remembered until it would have expired anyway
+pub async fn prune_expired(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let revoked = sqlx::query!("DELETE FROM revoked_tokens WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    let refresh = sqlx::query!("DELETE FROM refresh_tokens WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(pool)
+        .await?
+        .rows_affected();
+
+    Ok(revoked + refresh)
+}
+
+// TOKEN_PRUNE_INTERVAL_MINUTES (default 60)
+pub fn spawn_pruner(pool: PgPool) {
+    let minutes = std::env::var("TOKEN_PRUNE_INTERVAL_MINUTES")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(60);
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(minutes * 60));
+        loop {
+            interval.tick().await;
+            match prune_expired(&pool).await {
+                Ok(count) => info!("Pruned {} expired tokens", count),
+                Err(e) => error!("Failed to prune expired tokens: {:?}", e),
+            }
+        }
+    });
+}
@@ -0,0 +1,216 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use sqlx::PgPool;
+use totp_rs::{Algorithm, Secret, TOTP};
+use uuid::Uuid;
+
+use crate::tools::refresh_token::hash_token;
+
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+
+pub struct TotpConfig {
+    // shown by the authenticator app next to the account name
+    pub issuer: String,
+    // steps accepted either side of now, to tolerate clock drift
+    pub skew: u8,
+    pub recovery_codes: usize,
+}
+
+impl TotpConfig {
+    // TOTP_ISSUER (rocket_work), TOTP_SKEW_STEPS (1), TOTP_RECOVERY_CODES (10)
+    pub fn from_env() -> Self {
+        fn read<T: std::str::FromStr>(name: &str, default: T) -> T {
+            std::env::var(name).ok().and_then(|v| v.parse::<T>().ok()).unwrap_or(default)
+        }
+
+        TotpConfig {
+            issuer: read("TOTP_ISSUER", "rocket_work".to_string()),
+            skew: read("TOTP_SKEW_STEPS", 1),
+            recovery_codes: read("TOTP_RECOVERY_CODES", 10),
+        }
+    }
+}
+
+pub struct TotpSecret {
+    pub secret: String,
+    pub enabled: bool,
+}
+
+// 160 bit secret, base32 encoded as authenticator apps expect
+pub fn new_secret() -> String {
+    let bytes: [u8; 20] = rand::thread_rng().gen();
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+pub fn build_totp(config: &TotpConfig, secret: &str, username: &str) -> Result<TOTP, String> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().map_err(|e| e.to_string())?;
+    TOTP::new(Algorithm::SHA1, DIGITS, config.skew, STEP_SECONDS, bytes, Some(config.issuer.clone()), username.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn now_seconds() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before 1970").as_secs()
+}
+
+// time step the code belongs to, None when it matches none inside the skew window
+pub fn matching_step(totp: &TOTP, code: &str, time: u64) -> Option<u64> {
+    let current = time / STEP_SECONDS;
+    let skew = totp.skew as u64;
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| totp.generate(step * STEP_SECONDS) == code.trim())
+}
+
+// xxxxx-xxxxx, lowercase letters and digits without the easily confused ones
+pub fn generate_recovery_codes(count: usize) -> Vec<String> {
+    const CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+    let mut rng = rand::thread_rng();
+    (0..count)
+        .map(|_| {
+            let chars: String = (0..10).map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char).collect();
+            format!("{}-{}", &chars[..5], &chars[5..])
+        })
+        .collect()
+}
+
+// users type recovery codes by hand, so case, spaces and the dash do not matter
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+pub async fn find_secret(pool: &PgPool, user_id: Uuid) -> Result<Option<TotpSecret>, sqlx::Error> {
+    sqlx::query_as!(
+        TotpSecret,
+        "SELECT secret, enabled FROM user_totp WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+// a new, not yet confirmed secret replaces any earlier pending one
+pub async fn store_pending_secret(pool: &PgPool, user_id: Uuid, secret: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO user_totp (user_id, secret) VALUES ($1, $2)
+            ON CONFLICT (user_id) DO UPDATE SET
+                secret = EXCLUDED.secret, enabled = false, last_used_step = NULL,
+                created_at = CURRENT_TIMESTAMP, confirmed_at = NULL
+            WHERE user_totp.enabled = false
+        "#,
+        user_id, secret
+    )
+    .execute(pool)
+    .await
+    .map(|_| ())
+}
+
+// checks the code against the stored secret and burns its time step, so a code works once
+pub async fn verify_code(
+    pool: &PgPool,
+    config: &TotpConfig,
+    user_id: Uuid,
+    username: &str,
+    code: &str
+) -> Result<bool, sqlx::Error> {
+    let secret = match find_secret(pool, user_id).await? {
+        Some(secret) => secret,
+        None => return Ok(false)
+    };
+
+    let totp = match build_totp(config, &secret.secret, username) {
+        Ok(totp) => totp,
+        Err(_) => return Ok(false)
+    };
+
+    let step = match matching_step(&totp, code, now_seconds()) {
+        Some(step) => step as i64,
+        None => return Ok(false)
+    };
+
+    sqlx::query!(
+        r#"
+            UPDATE user_totp SET last_used_step = $2
+            WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2)
+        "#,
+        user_id, step
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+// enables the pending secret and replaces the recovery codes, returns them in plain text once
+pub async fn enable(pool: &PgPool, config: &TotpConfig, user_id: Uuid) -> Result<Vec<String>, sqlx::Error> {
+    let codes = generate_recovery_codes(config.recovery_codes);
+    let hashes: Vec<String> = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "UPDATE user_totp SET enabled = true, confirmed_at = CURRENT_TIMESTAMP WHERE user_id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!("DELETE FROM totp_recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO totp_recovery_codes (user_id, code_hash) SELECT $1, UNNEST($2::varchar[])",
+        user_id, &hashes
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+pub async fn disable(pool: &PgPool, user_id: Uuid) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("DELETE FROM totp_recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query!("DELETE FROM user_totp WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await
+}
+
+pub async fn use_recovery_code(pool: &PgPool, user_id: Uuid, code: &str) -> Result<bool, sqlx::Error> {
+    sqlx::query!(
+        r#"
+            UPDATE totp_recovery_codes SET used_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+        "#,
+        user_id, hash_recovery_code(code)
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+}
+
+pub async fn is_enabled(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    find_secret(pool, user_id).await.map(|secret| secret.map(|s| s.enabled).unwrap_or(false))
+}
+
+// second factor made mandatory by any of the user's roles
+pub async fn required_by_role(pool: &PgPool, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+            SELECT EXISTS (
+                SELECT 1 FROM user_roles ur JOIN roles r ON ur.role_id = r.id
+                WHERE ur.user_id = $1 AND r.require_mfa
+            ) AS "required!"
+        "#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await
+}
@@ -0,0 +1,47 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::tools::user_roles::retire_tokens;
+
+// like role changes, every change of a user's grants and denies retires their access tokens
+
+// grants (`granted`) or denies the permission to the user, replacing an earlier override of it
+pub async fn set_override(
+    conn: &mut PgConnection,
+    user_id: Uuid,
+    permission_id: i32,
+    granted: bool,
+    expires_in_days: Option<i32>,
+    created_by: &str
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO user_permissions (user_id, permissions_id, granted, expires_at, created_by)
+            VALUES ($1, $2, $3, CURRENT_TIMESTAMP + make_interval(days => $4), $5)
+            ON CONFLICT (user_id, permissions_id) DO UPDATE
+            SET granted = EXCLUDED.granted, expires_at = EXCLUDED.expires_at,
+                created_by = EXCLUDED.created_by, created_at = CURRENT_TIMESTAMP
+        "#,
+        user_id, permission_id, granted, expires_in_days, created_by
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    retire_tokens(conn, user_id).await
+}
+
+// false when there was no override of the permission, expired ones included
+pub async fn remove_override(conn: &mut PgConnection, user_id: Uuid, permission_id: i32) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query!(
+        "DELETE FROM user_permissions WHERE user_id = $1 AND permissions_id = $2",
+        user_id, permission_id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected() > 0;
+
+    if removed {
+        retire_tokens(conn, user_id).await?;
+    }
+    Ok(removed)
+}
@@ -0,0 +1,70 @@
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+// every change of a user's roles retires their access tokens, the next refresh names the new roles
+
+// makes `role_ids` the user's only roles, returns whether anything changed
+pub async fn replace_roles(conn: &mut PgConnection, user_id: Uuid, role_ids: &[i32]) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role_id <> ALL($2)",
+        user_id, role_ids
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    let added = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id) SELECT $1, unnest($2::int[]) ON CONFLICT DO NOTHING",
+        user_id, role_ids
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    let changed = removed + added > 0;
+    if changed {
+        retire_tokens(conn, user_id).await?;
+    }
+    Ok(changed)
+}
+
+// false when the user already holds the role
+pub async fn assign_role(conn: &mut PgConnection, user_id: Uuid, role_id: i32) -> Result<bool, sqlx::Error> {
+    let added = sqlx::query!(
+        "INSERT INTO user_roles (user_id, role_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+        user_id, role_id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected() > 0;
+
+    if added {
+        retire_tokens(conn, user_id).await?;
+    }
+    Ok(added)
+}
+
+// false when the user does not hold the role
+pub async fn remove_role(conn: &mut PgConnection, user_id: Uuid, role_id: i32) -> Result<bool, sqlx::Error> {
+    let removed = sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role_id = $2",
+        user_id, role_id
+    )
+    .execute(&mut *conn)
+    .await?
+    .rows_affected() > 0;
+
+    if removed {
+        retire_tokens(conn, user_id).await?;
+    }
+    Ok(removed)
+}
+
+pub async fn retire_tokens(conn: &mut PgConnection, user_id: Uuid) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE users SET token_version = token_version + 1, updated_at = CURRENT_TIMESTAMP WHERE id = $1",
+        user_id
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}